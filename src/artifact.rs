@@ -0,0 +1,284 @@
+use std::{
+    fs,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::anyhow;
+
+use crate::{backup::hash_file, manifest::ManifestContentArtifact, service::ServiceInformation};
+
+/// Files downloaded once and shared by every service, stored by their sha256.
+#[derive(Debug, Clone)]
+pub struct ArtifactCache {
+    path: PathBuf,
+}
+
+impl ArtifactCache {
+    pub fn new<T>(path: T) -> ArtifactCache
+    where
+        T: Into<PathBuf>,
+    {
+        ArtifactCache { path: path.into() }
+    }
+
+    /// Path of the artifact in the cache, fetching it first if needed. Relative local paths are
+    /// looked up in `base`.
+    pub fn fetch(
+        &self,
+        artifact: &ManifestContentArtifact,
+        base: &Path,
+    ) -> anyhow::Result<PathBuf> {
+        let sha256 = artifact.sha256.to_lowercase();
+        if sha256.len() != 64 || !sha256.chars().all(|ele| ele.is_ascii_hexdigit()) {
+            return Err(anyhow!(
+                "Artifact {} has an invalid sha256 `{}`",
+                artifact.destination,
+                artifact.sha256
+            ));
+        }
+
+        let cached = self.path.join(&sha256);
+        if cached.exists() {
+            return Ok(cached);
+        }
+
+        fs::create_dir_all(&self.path)?;
+        let part = self.path.join(format!(".{}.part", sha256));
+
+        let fetched = match (&artifact.url, &artifact.path) {
+            (Some(url), None) => match url.strip_prefix("file://") {
+                Some(path) => fs::copy(path, &part).map(|_| ()).map_err(Into::into),
+                None if url.starts_with("http://") || url.starts_with("https://") => {
+                    download(url, &part)
+                }
+                None => Err(anyhow!("Unsupported artifact URL {}", url)),
+            },
+            (None, Some(path)) => fs::copy(base.join(path), &part)
+                .map(|_| ())
+                .map_err(Into::into),
+            _ => Err(anyhow!(
+                "Artifact {} needs exactly one of `url` and `path`",
+                artifact.destination
+            )),
+        };
+        if let Err(err) = fetched {
+            let _ = fs::remove_file(&part);
+            return Err(err.context(format!("Cannot fetch artifact {}", artifact.destination)));
+        }
+
+        let hash = hash_file(&part)?;
+        if hash != sha256 {
+            fs::remove_file(&part)?;
+            return Err(anyhow!(
+                "Artifact {} has sha256 {}, expected {}",
+                artifact.destination,
+                hash,
+                sha256
+            ));
+        }
+
+        // Services share the cached file through hard links, it must not be changed in place.
+        fs::set_permissions(&part, fs::Permissions::from_mode(0o444))?;
+        fs::rename(&part, &cached)?;
+
+        Ok(cached)
+    }
+
+    /// Fetch the artifacts of a service and link them into its directory, returning the
+    /// destinations that were linked.
+    pub fn install(&self, service: &ServiceInformation) -> anyhow::Result<Vec<String>> {
+        let mut linked = vec![];
+
+        for artifact in &service.manifest.artifacts {
+            let destination = inside(&service.path, &artifact.destination)?;
+
+            let cached = self.fetch(artifact, &service.path)?;
+            if link(&cached, &destination)? {
+                linked.push(artifact.destination.clone());
+            }
+        }
+
+        Ok(linked)
+    }
+}
+
+/// `destination` in the service directory `root`, an error when it would leave it.
+pub fn inside(root: &Path, destination: &str) -> anyhow::Result<PathBuf> {
+    let path = Path::new(destination);
+    if destination.is_empty()
+        || !path
+            .components()
+            .all(|ele| matches!(ele, Component::Normal(_)))
+    {
+        return Err(anyhow!(
+            "{} is not inside the service directory",
+            destination
+        ));
+    }
+    Ok(root.join(path))
+}
+
+/// Hard-link a cached file to `destination`, replacing what is there. Returns `false` when it
+/// was already linked, or copied when the cache is on another file system.
+pub fn link(cached: &Path, destination: &Path) -> anyhow::Result<bool> {
+    if let Ok(metadata) = fs::symlink_metadata(destination) {
+        let source = fs::metadata(cached)?;
+        if metadata.dev() == source.dev() && metadata.ino() == source.ino() {
+            return Ok(false);
+        }
+        // Cached files are named after their sha256.
+        if metadata.is_file()
+            && metadata.len() == source.len()
+            && cached
+                .file_name()
+                .is_some_and(|name| hash_file(destination).is_ok_and(|hash| *name == *hash))
+        {
+            return Ok(false);
+        }
+        fs::remove_file(destination)?;
+    }
+
+    fs::create_dir_all(destination.parent().expect("Destination has a parent"))?;
+    // The cache may be on another file system.
+    if fs::hard_link(cached, destination).is_err() {
+        fs::copy(cached, destination)?;
+    }
+    Ok(true)
+}
+
+fn download(url: &str, target: &Path) -> anyhow::Result<()> {
+    let output = Command::new("curl")
+        .args([
+            "--fail",
+            "--silent",
+            "--show-error",
+            "--location",
+            "--output",
+        ])
+        .arg(target)
+        .arg(url)
+        .output()?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "curl failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn artifact_test() {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+    };
+
+    use sha2::{Digest, Sha256};
+
+    let dir = tempfile::tempdir().unwrap();
+    let cache = ArtifactCache::new(dir.path().join("cache"));
+    let service_path = dir.path().join("lobby");
+    fs::create_dir_all(&service_path).unwrap();
+
+    let content = b"jar content";
+    let sha256 = hex::encode(Sha256::digest(content));
+    fs::write(dir.path().join("server.jar"), content).unwrap();
+
+    // A loopback stand-in for the download server, answering a single request.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0; 1024];
+        let _ = stream.read(&mut request).unwrap();
+        let header = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            content.len()
+        );
+        stream.write_all(header.as_bytes()).unwrap();
+        stream.write_all(content).unwrap();
+    });
+
+    let artifact = |url: Option<String>, path: Option<&str>, sha256: &str, destination: &str| {
+        ManifestContentArtifact {
+            url,
+            path: path.map(str::to_string),
+            sha256: sha256.to_string(),
+            destination: destination.to_string(),
+        }
+    };
+    let mut service = ServiceInformation {
+        name: "lobby".to_string(),
+        path: service_path.clone(),
+        ..Default::default()
+    };
+    service.manifest.artifacts = vec![
+        artifact(
+            Some(format!("http://{}/server.jar", addr)),
+            None,
+            &sha256,
+            "server.jar",
+        ),
+        artifact(
+            Some(format!(
+                "file://{}",
+                dir.path().join("server.jar").display()
+            )),
+            None,
+            &sha256,
+            "plugins/copy.jar",
+        ),
+    ];
+
+    assert_eq!(
+        cache.install(&service).unwrap(),
+        vec!["server.jar", "plugins/copy.jar"]
+    );
+    assert_eq!(
+        fs::read(service_path.join("plugins/copy.jar")).unwrap(),
+        content
+    );
+    assert_eq!(
+        fs::metadata(service_path.join("server.jar")).unwrap().ino(),
+        fs::metadata(dir.path().join("cache").join(&sha256))
+            .unwrap()
+            .ino()
+    );
+    // Linked once, nothing to do the next time.
+    assert!(cache.install(&service).unwrap().is_empty());
+
+    fs::write(service_path.join("other.jar"), b"other").unwrap();
+    let wrong = artifact(None, Some("other.jar"), &"0".repeat(64), "mods/other.jar");
+    assert!(cache
+        .fetch(&wrong, &service_path)
+        .unwrap_err()
+        .to_string()
+        .contains("expected"));
+    assert!(!dir.path().join("cache").join("0".repeat(64)).exists());
+}
+
+#[cfg(test)]
+#[test]
+fn link_test() {
+    use sha2::{Digest, Sha256};
+
+    let dir = tempfile::tempdir().unwrap();
+    let cached = dir.path().join(hex::encode(Sha256::digest(b"jar")));
+    let destination = dir.path().join("lobby/server.jar");
+    fs::write(&cached, b"jar").unwrap();
+
+    // A copy, as left when the cache is on another file system, is kept as it is.
+    fs::create_dir_all(destination.parent().unwrap()).unwrap();
+    fs::write(&destination, b"jar").unwrap();
+    assert!(!link(&cached, &destination).unwrap());
+
+    fs::write(&destination, b"old").unwrap();
+    assert!(link(&cached, &destination).unwrap());
+    assert_eq!(fs::read(&destination).unwrap(), b"jar");
+}
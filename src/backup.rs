@@ -0,0 +1,722 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashSet},
+    fs::{self, File},
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Datelike, Local};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::{
+    chunk::{self, ChunkStore, CHUNK_SIZE},
+    error::Error,
+    manifest::ManifestContentBackup,
+    service::ServiceInformation,
+};
+
+/// Directory of the chunk store inside the backup directory, next to the per service ones.
+const CHUNK_DIRECTORY: &str = ".chunks";
+/// Shared by incremental backups while they add chunks, exclusive while chunks are collected.
+const CHUNK_LOCK: &str = ".chunks.lock";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Snapshot {
+    pub id: String,
+    pub service: String,
+    pub created: DateTime<Local>,
+    /// Size of the archive, or of the chunks first stored by this snapshot when incremental.
+    pub size: u64,
+    pub files: BTreeMap<String, String>,
+    #[serde(default)]
+    pub incremental: bool,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub chunks: BTreeMap<String, Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, Default)]
+pub struct RestoreReport {
+    pub snapshot: String,
+    pub pre_restore: Option<String>,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+}
+
+/// Snapshots of one service, each described by an `<id>.json` file listing the hash of every
+/// file. Full snapshots are stored as `<id>.tar.gz`, incremental ones as chunks in a
+/// [`ChunkStore`] shared by every service so identical data is only stored once.
+#[derive(Debug, Clone)]
+pub struct BackupStore {
+    pub path: PathBuf,
+    pub service: String,
+    backup_dir: PathBuf,
+    chunks: ChunkStore,
+}
+
+impl BackupStore {
+    pub fn new<T>(backup_dir: T, service: &str) -> BackupStore
+    where
+        T: Into<PathBuf>,
+    {
+        let backup_dir: PathBuf = backup_dir.into();
+
+        let mut path = backup_dir.clone();
+        path.push(service);
+
+        let mut chunks = backup_dir.clone();
+        chunks.push(CHUNK_DIRECTORY);
+
+        BackupStore {
+            path,
+            service: service.to_string(),
+            backup_dir,
+            chunks: ChunkStore::new(chunks),
+        }
+    }
+
+    pub fn create<T>(
+        &self,
+        data_dir: T,
+        incremental: bool,
+        label: Option<&str>,
+    ) -> anyhow::Result<Snapshot>
+    where
+        T: Into<PathBuf>,
+    {
+        let data_dir: PathBuf = data_dir.into();
+        if !data_dir.is_dir() {
+            return Err(anyhow!(
+                "Data directory {} does not exist",
+                data_dir.display()
+            ));
+        }
+
+        fs::create_dir_all(&self.path)?;
+
+        let created = Local::now();
+        let mut id = created.format("%Y%m%d-%H%M%S").to_string();
+        for suffix in 1.. {
+            if !self.index_path(&id).exists() {
+                break;
+            }
+            id = format!("{}-{}", created.format("%Y%m%d-%H%M%S"), suffix);
+        }
+
+        let mut snapshot = Snapshot {
+            id: id.clone(),
+            service: self.service.clone(),
+            created,
+            size: 0,
+            files: BTreeMap::new(),
+            incremental,
+            chunks: BTreeMap::new(),
+            label: label.map(str::to_string),
+        };
+
+        // Held until the index refers to the chunks, a prune would otherwise delete the ones
+        // `put` skipped because they already existed.
+        let _lock = incremental.then(|| self.lock_chunks(false)).transpose()?;
+        if incremental {
+            self.store_chunks(&data_dir, &mut snapshot)?;
+        } else {
+            self.store_archive(&data_dir, &mut snapshot)?;
+        }
+
+        fs::write(
+            self.index_path(&id),
+            serde_json::to_string_pretty(&snapshot)?,
+        )?;
+
+        Ok(snapshot)
+    }
+
+    fn store_archive(&self, data_dir: &Path, snapshot: &mut Snapshot) -> anyhow::Result<()> {
+        let archive = self.archive_path(&snapshot.id);
+
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            File::create(&archive)?,
+            Compression::default(),
+        ));
+        builder.follow_symlinks(false);
+
+        for (path, name) in data_files(data_dir)? {
+            snapshot.files.insert(name.clone(), hash_file(&path)?);
+            builder.append_path_with_name(&path, &name)?;
+        }
+
+        builder.into_inner()?.finish()?;
+        snapshot.size = fs::metadata(&archive)?.len();
+
+        Ok(())
+    }
+
+    fn store_chunks(&self, data_dir: &Path, snapshot: &mut Snapshot) -> anyhow::Result<()> {
+        for (path, name) in data_files(data_dir)? {
+            let mut file = BufReader::new(File::open(&path)?);
+            let mut hasher = Sha256::new();
+            let mut chunks = vec![];
+
+            loop {
+                let mut buffer = Vec::with_capacity(CHUNK_SIZE);
+                (&mut file)
+                    .take(CHUNK_SIZE as u64)
+                    .read_to_end(&mut buffer)?;
+                if buffer.is_empty() {
+                    break;
+                }
+
+                hasher.update(&buffer);
+                let (hash, written) = self.chunks.put(&buffer)?;
+                snapshot.size += written;
+                chunks.push(hash);
+            }
+
+            snapshot
+                .files
+                .insert(name.clone(), hex::encode(hasher.finalize()));
+            snapshot.chunks.insert(name, chunks);
+        }
+
+        Ok(())
+    }
+
+    /// Returns every snapshot of the service, newest first.
+    pub fn list(&self) -> anyhow::Result<Vec<Snapshot>> {
+        if !self.path.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut snapshots = vec![];
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ele| ele.to_str()) != Some("json") {
+                continue;
+            }
+            let snapshot = serde_json::from_str::<Snapshot>(&fs::read_to_string(&path)?)?;
+            if let Some(hash) = snapshot
+                .chunks
+                .values()
+                .flatten()
+                .find(|ele| !chunk::is_hash(ele))
+            {
+                return Err(Error::Invalid(format!(
+                    "Snapshot {} refers to invalid chunk {}",
+                    path.display(),
+                    hash
+                ))
+                .into());
+            }
+            snapshots.push(snapshot);
+        }
+
+        snapshots.sort_by_key(|ele| Reverse(ele.created));
+
+        Ok(snapshots)
+    }
+
+    pub fn find(&self, id: &str) -> anyhow::Result<Snapshot> {
+        self.list()?
+            .into_iter()
+            .find(|ele| ele.id == id)
+            .ok_or_else(|| anyhow!("No snapshot {} of service {}", id, self.service))
+    }
+
+    /// Replace the content of `data_dir` with the snapshot.
+    ///
+    /// The current content is first saved as a `pre-restore` snapshot. The snapshot is unpacked
+    /// next to `data_dir` and swapped in by renaming, so a failed restore leaves the data as it was.
+    pub fn restore<T>(
+        &self,
+        id: &str,
+        data_dir: T,
+        incremental: bool,
+    ) -> anyhow::Result<RestoreReport>
+    where
+        T: Into<PathBuf>,
+    {
+        let data_dir: PathBuf = data_dir.into();
+        let snapshot = self.find(id)?;
+
+        let pre_restore = if data_dir.exists() {
+            Some(self.create(&data_dir, incremental, Some("pre-restore"))?)
+        } else {
+            None
+        };
+
+        let name = data_dir
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid data directory {}", data_dir.display()))?
+            .to_string_lossy()
+            .to_string();
+        let staging = data_dir.with_file_name(format!(".{}.restore", name));
+        let aside = data_dir.with_file_name(format!(".{}.pre-restore", name));
+
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        fs::create_dir_all(&staging)?;
+
+        if let Err(err) = self.unpack(&snapshot, &staging) {
+            fs::remove_dir_all(&staging)?;
+            return Err(err);
+        }
+
+        if pre_restore.is_some() {
+            if aside.exists() {
+                fs::remove_dir_all(&aside)?;
+            }
+            fs::rename(&data_dir, &aside)?;
+            if let Err(err) = fs::rename(&staging, &data_dir) {
+                fs::rename(&aside, &data_dir)?;
+                return Err(err.into());
+            }
+            fs::remove_dir_all(&aside)?;
+        } else {
+            fs::rename(&staging, &data_dir)?;
+        }
+
+        let before = pre_restore
+            .as_ref()
+            .map(|ele| ele.files.clone())
+            .unwrap_or_default();
+
+        Ok(RestoreReport {
+            snapshot: snapshot.id.clone(),
+            pre_restore: pre_restore.map(|ele| ele.id),
+            added: snapshot
+                .files
+                .keys()
+                .filter(|ele| !before.contains_key(*ele))
+                .cloned()
+                .collect(),
+            removed: before
+                .keys()
+                .filter(|ele| !snapshot.files.contains_key(*ele))
+                .cloned()
+                .collect(),
+            modified: snapshot
+                .files
+                .iter()
+                .filter(|(name, hash)| before.get(*name).is_some_and(|ele| ele != *hash))
+                .map(|(name, _)| name.clone())
+                .collect(),
+        })
+    }
+
+    /// Restore the snapshot into a separate, empty directory for inspection.
+    pub fn restore_to<T>(&self, id: &str, target: T) -> anyhow::Result<Snapshot>
+    where
+        T: Into<PathBuf>,
+    {
+        let target: PathBuf = target.into();
+        let snapshot = self.find(id)?;
+
+        if target.exists() && fs::read_dir(&target)?.next().is_some() {
+            return Err(anyhow!("Directory {} is not empty", target.display()));
+        }
+        fs::create_dir_all(&target)?;
+
+        self.unpack(&snapshot, &target)?;
+
+        Ok(snapshot)
+    }
+
+    pub fn unpack(&self, snapshot: &Snapshot, target: &Path) -> anyhow::Result<()> {
+        if !snapshot.incremental {
+            let archive = File::open(self.archive_path(&snapshot.id))?;
+            tar::Archive::new(GzDecoder::new(archive)).unpack(target)?;
+            return Ok(());
+        }
+
+        for (name, chunks) in &snapshot.chunks {
+            let mut path = target.to_path_buf();
+            path.push(name);
+            fs::create_dir_all(path.parent().expect("File path has a parent"))?;
+
+            let mut file = File::create(path)?;
+            for hash in chunks {
+                file.write_all(&self.chunks.get(hash)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check every file of the snapshot against the hashes recorded when it was taken, returning
+    /// the problems found.
+    pub fn verify(&self, snapshot: &Snapshot) -> anyhow::Result<Vec<String>> {
+        let mut problems = vec![];
+
+        if snapshot.incremental {
+            for (name, chunks) in &snapshot.chunks {
+                let mut hasher = Sha256::new();
+                for hash in chunks {
+                    match self.chunks.verify(hash) {
+                        Ok(()) => hasher.update(self.chunks.get(hash)?),
+                        Err(err) => problems.push(format!("{}: {}", name, err)),
+                    }
+                }
+
+                if snapshot.files.get(name) != Some(&hex::encode(hasher.finalize())) {
+                    problems.push(format!("{}: content does not match its hash", name));
+                }
+            }
+            return Ok(problems);
+        }
+
+        let archive = File::open(self.archive_path(&snapshot.id))?;
+        let mut archive = tar::Archive::new(GzDecoder::new(archive));
+
+        let mut seen = HashSet::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().to_string();
+
+            let mut hasher = Sha256::new();
+            io::copy(&mut entry, &mut hasher)?;
+
+            if snapshot.files.get(&name) != Some(&hex::encode(hasher.finalize())) {
+                problems.push(format!("{}: content does not match its hash", name));
+            }
+            seen.insert(name);
+        }
+
+        for name in snapshot.files.keys() {
+            if !seen.contains(name) {
+                problems.push(format!("{}: missing from the archive", name));
+            }
+        }
+
+        Ok(problems)
+    }
+
+    /// Delete the snapshots the retention policy does not keep, returning them.
+    pub fn prune(&self, retention: &ManifestContentBackup) -> anyhow::Result<Vec<Snapshot>> {
+        let snapshots = self.list()?;
+        let keep = retained(&snapshots, retention);
+
+        let mut removed = vec![];
+        for snapshot in snapshots {
+            if keep.contains(&snapshot.id) {
+                continue;
+            }
+            if !snapshot.incremental {
+                fs::remove_file(self.archive_path(&snapshot.id))?;
+            }
+            fs::remove_file(self.index_path(&snapshot.id))?;
+            removed.push(snapshot);
+        }
+
+        if removed.iter().any(|ele| ele.incremental) {
+            self.collect_garbage()?;
+        }
+
+        Ok(removed)
+    }
+
+    /// Delete the chunks no snapshot of any service refers to anymore.
+    fn collect_garbage(&self) -> anyhow::Result<usize> {
+        let _lock = self.lock_chunks(true)?;
+        let mut referenced = HashSet::new();
+        for entry in fs::read_dir(&self.backup_dir)? {
+            let path = entry?.path();
+            if !path.is_dir() || path.ends_with(CHUNK_DIRECTORY) {
+                continue;
+            }
+
+            let service = path.file_name().unwrap().to_string_lossy().to_string();
+            for snapshot in BackupStore::new(&self.backup_dir, &service).list()? {
+                referenced.extend(snapshot.chunks.into_values().flatten());
+            }
+        }
+
+        let mut removed = 0;
+        for hash in self.chunks.all()? {
+            if !referenced.contains(&hash) {
+                self.chunks.remove(&hash)?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn lock_chunks(&self, exclusive: bool) -> anyhow::Result<File> {
+        let file = File::create(self.backup_dir.join(CHUNK_LOCK))?;
+        if exclusive {
+            file.lock()?;
+        } else {
+            file.lock_shared()?;
+        }
+        Ok(file)
+    }
+
+    fn archive_path(&self, id: &str) -> PathBuf {
+        let mut path = self.path.clone();
+        path.push(format!("{}.tar.gz", id));
+        path
+    }
+
+    fn index_path(&self, id: &str) -> PathBuf {
+        let mut path = self.path.clone();
+        path.push(format!("{}.json", id));
+        path
+    }
+}
+
+/// Take a snapshot of the service data directory, pausing world saves around it when asked to.
+pub async fn backup_service<T0, T1>(
+    service: &ServiceInformation,
+    backup_dir: T0,
+    data_dir: T1,
+    pause: bool,
+    incremental: bool,
+) -> anyhow::Result<Snapshot>
+where
+    T0: Into<PathBuf>,
+    T1: Into<PathBuf>,
+{
+    let mut data_dir: PathBuf = data_dir.into();
+    data_dir.push(&service.name);
+
+    let store = BackupStore::new(backup_dir, &service.name);
+
+    if !pause {
+        return store.create(data_dir, incremental, None);
+    }
+
+    // Once saving is off it is turned back on whatever happens, and a snapshot that was taken
+    // is kept even if that fails.
+    service.clone().send_command("save-off").await?;
+    let snapshot = match service.clone().send_command("save-all flush").await {
+        Ok(_) => store.create(data_dir, incremental, None),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = service.clone().send_command("save-on").await {
+        eprintln!("Cannot turn saving back on for {}: {}", service.name, err);
+    }
+
+    snapshot
+}
+
+/// Returns every regular file under `root` with its path relative to `root`, sorted by name.
+pub fn data_files(root: &Path) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    let mut files = vec![];
+
+    for entry in WalkDir::new(root).follow_links(false).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let name = entry
+            .path()
+            .strip_prefix(root)?
+            .to_str()
+            .ok_or_else(|| anyhow!("Non UTF-8 path {}", entry.path().display()))?
+            .to_string();
+
+        files.push((entry.into_path(), name));
+    }
+
+    Ok(files)
+}
+
+pub fn hash_file(path: &Path) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Ids of the snapshots kept by the retention policy. `snapshots` must be sorted newest first.
+///
+/// A policy without any rule keeps everything.
+fn retained(snapshots: &[Snapshot], retention: &ManifestContentBackup) -> HashSet<String> {
+    if retention.keep_last.is_none()
+        && retention.keep_daily.is_none()
+        && retention.keep_weekly.is_none()
+    {
+        return snapshots.iter().map(|ele| ele.id.clone()).collect();
+    }
+
+    let mut keep = snapshots
+        .iter()
+        .take(retention.keep_last.unwrap_or(0))
+        .map(|ele| ele.id.clone())
+        .collect::<HashSet<_>>();
+
+    let mut keep_newest_per = |count: Option<usize>, period: &dyn Fn(&Snapshot) -> (i32, u32)| {
+        let mut periods = vec![];
+        for snapshot in snapshots {
+            let period = period(snapshot);
+            if periods.contains(&period) {
+                continue;
+            }
+            if periods.len() >= count.unwrap_or(0) {
+                break;
+            }
+            periods.push(period);
+            keep.insert(snapshot.id.clone());
+        }
+    };
+
+    keep_newest_per(retention.keep_daily, &|ele| {
+        (ele.created.year(), ele.created.ordinal())
+    });
+    keep_newest_per(retention.keep_weekly, &|ele| {
+        let week = ele.created.iso_week();
+        (week.year(), week.week())
+    });
+
+    keep
+}
+
+#[cfg(test)]
+#[test]
+fn backup_test() {
+    let workdir = tempfile::tempdir().unwrap();
+    let data_dir = workdir.path().join("data");
+    fs::create_dir_all(data_dir.join("world/region")).unwrap();
+    fs::write(data_dir.join("world/level.dat"), "level").unwrap();
+    fs::write(data_dir.join("world/region/r.0.0.mca"), "region").unwrap();
+
+    let store = BackupStore::new(workdir.path().join("backups"), "lobby");
+    let snapshot = store.create(&data_dir, false, None).unwrap();
+    assert_eq!(
+        snapshot.files.keys().collect::<Vec<_>>(),
+        vec!["world/level.dat", "world/region/r.0.0.mca"]
+    );
+
+    fs::write(data_dir.join("world/level.dat"), "broken").unwrap();
+    fs::write(data_dir.join("world/session.lock"), "").unwrap();
+    let report = store.restore(&snapshot.id, &data_dir, false).unwrap();
+    assert_eq!(
+        fs::read_to_string(data_dir.join("world/level.dat")).unwrap(),
+        "level"
+    );
+    assert!(!data_dir.join("world/session.lock").exists());
+    assert!(report.added.is_empty());
+    assert_eq!(report.removed, vec!["world/session.lock"]);
+    assert_eq!(report.modified, vec!["world/level.dat"]);
+
+    let pre_restore = store.find(&report.pre_restore.unwrap()).unwrap();
+    assert_eq!(pre_restore.label.as_deref(), Some("pre-restore"));
+
+    assert!(store.verify(&snapshot).unwrap().is_empty());
+    assert!(store.prune(&Default::default()).unwrap().is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn incremental_backup_test() {
+    let workdir = tempfile::tempdir().unwrap();
+    let data_dir = workdir.path().join("data");
+    fs::create_dir_all(data_dir.join("world/region")).unwrap();
+    fs::write(
+        data_dir.join("world/region/r.0.0.mca"),
+        vec![1; CHUNK_SIZE * 2],
+    )
+    .unwrap();
+
+    let store = BackupStore::new(workdir.path().join("backups"), "lobby");
+    let snapshot = store.create(&data_dir, true, None).unwrap();
+    assert_eq!(snapshot.chunks["world/region/r.0.0.mca"].len(), 2);
+
+    let target = workdir.path().join("inspect");
+    store.restore_to(&snapshot.id, &target).unwrap();
+    assert_eq!(
+        fs::read(target.join("world/region/r.0.0.mca")).unwrap(),
+        vec![1; CHUNK_SIZE * 2]
+    );
+    assert!(store.restore_to(&snapshot.id, &target).is_err());
+
+    // Both halves of the region are identical, so only one chunk was stored.
+    let chunks = ChunkStore::new(workdir.path().join("backups").join(CHUNK_DIRECTORY));
+    assert_eq!(chunks.all().unwrap().len(), 1);
+    assert!(store.verify(&snapshot).unwrap().is_empty());
+
+    chunks
+        .remove(&snapshot.chunks["world/region/r.0.0.mca"][0])
+        .unwrap();
+    assert!(!store.verify(&snapshot).unwrap().is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn retained_test() {
+    use chrono::TimeZone;
+
+    let snapshot = |id: &str, day: u32, hour: u32| Snapshot {
+        id: id.to_string(),
+        service: "lobby".to_string(),
+        created: Local.with_ymd_and_hms(2023, 7, day, hour, 0, 0).unwrap(),
+        size: 0,
+        files: BTreeMap::new(),
+        incremental: false,
+        chunks: BTreeMap::new(),
+        label: None,
+    };
+
+    // Newest first: two snapshots on the 12th, one on the 11th and one a week earlier.
+    let snapshots = vec![
+        snapshot("d", 12, 20),
+        snapshot("c", 12, 8),
+        snapshot("b", 11, 8),
+        snapshot("a", 4, 8),
+    ];
+
+    let keep = |keep_last, keep_daily, keep_weekly| {
+        let mut keep = retained(
+            &snapshots,
+            &ManifestContentBackup {
+                keep_last,
+                keep_daily,
+                keep_weekly,
+                ..Default::default()
+            },
+        )
+        .into_iter()
+        .collect::<Vec<_>>();
+        keep.sort();
+        keep
+    };
+
+    assert_eq!(keep(Some(1), None, None), vec!["d"]);
+    assert_eq!(keep(None, Some(2), None), vec!["b", "d"]);
+    assert_eq!(keep(Some(2), None, Some(2)), vec!["a", "c", "d"]);
+}
+
+#[cfg(test)]
+#[test]
+fn invalid_index_test() {
+    let workdir = tempfile::tempdir().unwrap();
+    let data_dir = workdir.path().join("data");
+    fs::create_dir_all(&data_dir).unwrap();
+    fs::write(data_dir.join("level.dat"), "level").unwrap();
+
+    let store = BackupStore::new(workdir.path().join("backups"), "lobby");
+    let mut snapshot = store.create(&data_dir, true, None).unwrap();
+    snapshot
+        .chunks
+        .insert("level.dat".to_string(), vec!["x".to_string()]);
+    fs::write(
+        store.path.join(format!("{}.json", snapshot.id)),
+        serde_json::to_string(&snapshot).unwrap(),
+    )
+    .unwrap();
+
+    let err = store.list().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::Invalid(_))
+    ));
+}
@@ -0,0 +1,125 @@
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+use anyhow::anyhow;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+/// Size of the pieces files are split into. Region files are rewritten in 4 KiB sectors, so a
+/// multiple of it keeps unchanged parts of a region in unchanged chunks.
+pub const CHUNK_SIZE: usize = 128 * 1024;
+
+/// Content-addressed store of compressed chunks, named after the sha256 of their content.
+#[derive(Debug, Clone)]
+pub struct ChunkStore {
+    pub path: PathBuf,
+}
+
+impl ChunkStore {
+    pub fn new<T>(path: T) -> ChunkStore
+    where
+        T: Into<PathBuf>,
+    {
+        ChunkStore { path: path.into() }
+    }
+
+    /// Store `data` unless an identical chunk is already stored, returning its hash and the
+    /// number of bytes written to disk.
+    pub fn put(&self, data: &[u8]) -> anyhow::Result<(String, u64)> {
+        let hash = hex::encode(Sha256::digest(data));
+
+        let path = self.chunk_path(&hash);
+        if path.exists() {
+            return Ok((hash, 0));
+        }
+
+        fs::create_dir_all(path.parent().expect("Chunk path has a parent"))?;
+
+        // Write next to the chunk and rename so that an interrupted backup never leaves a
+        // truncated chunk behind under a valid name.
+        let temporary = path.with_extension("tmp");
+        let mut encoder = GzEncoder::new(File::create(&temporary)?, Compression::default());
+        encoder.write_all(data)?;
+        encoder.finish()?;
+        fs::rename(&temporary, &path)?;
+
+        Ok((hash, fs::metadata(&path)?.len()))
+    }
+
+    pub fn get(&self, hash: &str) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![];
+        GzDecoder::new(File::open(self.chunk_path(hash))?).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Check that the chunk exists and still hashes to its name.
+    pub fn verify(&self, hash: &str) -> anyhow::Result<()> {
+        if !self.chunk_path(hash).exists() {
+            return Err(anyhow!("Chunk {} is missing", hash));
+        }
+
+        if hex::encode(Sha256::digest(self.get(hash)?)) != hash {
+            return Err(anyhow!("Chunk {} is corrupted", hash));
+        }
+
+        Ok(())
+    }
+
+    /// Hashes of the stored chunks. Chunks still being written are left out.
+    pub fn all(&self) -> anyhow::Result<Vec<String>> {
+        let mut hashes = vec![];
+        for entry in WalkDir::new(&self.path) {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy();
+            if entry.file_type().is_file() && is_hash(&name) {
+                hashes.push(name.to_string());
+            }
+        }
+        Ok(hashes)
+    }
+
+    pub fn remove(&self, hash: &str) -> anyhow::Result<()> {
+        fs::remove_file(self.chunk_path(hash))?;
+        Ok(())
+    }
+
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        let mut path = self.path.clone();
+        path.push(&hash[..2]);
+        path.push(hash);
+        path
+    }
+}
+
+/// Whether `hash` is a sha256 as chunks are named, 64 lowercase hex digits.
+pub fn is_hash(hash: &str) -> bool {
+    hash.len() == 64
+        && hash
+            .bytes()
+            .all(|ele| matches!(ele, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+#[test]
+fn chunk_store_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = ChunkStore::new(dir.path());
+
+    let (hash, written) = store.put(b"region").unwrap();
+    assert!(written > 0);
+    assert_eq!(store.put(b"region").unwrap(), (hash.clone(), 0));
+    assert_eq!(store.get(&hash).unwrap(), b"region");
+    assert!(store.verify(&hash).is_ok());
+    assert!(is_hash(&hash));
+    assert!(!is_hash("a"));
+
+    fs::write(dir.path().join(&hash[..2]).join("partial.tmp"), b"").unwrap();
+    assert_eq!(store.all().unwrap(), vec![hash.clone()]);
+
+    store.remove(&hash).unwrap();
+    assert!(store.verify(&hash).is_err());
+}
@@ -1,46 +1,153 @@
 use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
     fs::{self, File},
-    io::{BufWriter, Write},
-    path::PathBuf,
-    process::Command,
+    io::{BufWriter, IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
+use anyhow::anyhow;
+use chrono::Local;
 use clap::ArgMatches;
-use prettytable::{row, Table};
+use prettytable::{row, Cell, Row, Table};
+use serde::Serialize;
 
 use crate::{
-    manifest::ManifestContent,
-    service::{ServiceInformation, ServiceStatus, StartResult, StopResult},
-    work::WorkDirectory,
+    artifact::ArtifactCache,
+    backup::{self, BackupStore, RestoreReport},
+    config::Config,
+    doctor::{self, Severity},
+    error::{self, Classify, Error},
+    executor::Termination,
+    java,
+    lint::{self, LintIssue},
+    manifest::{ManifestContent, ManifestContentArtifact, ManifestContentPlugin, VariableType},
+    monitor::ServiceMetrics,
+    origin::ServiceOrigin,
+    plugin::{self, PluginStatus},
+    port::{self, PortRegistry},
+    properties::{self, Properties},
+    registry::{self, SourceState, SourcesConfig},
+    response::{
+        BackupCreated, Envelope, ErrorEntry, FileOutcome, JavaCommandEntry, JobEntry, OriginEntry,
+        PropertyChange, PropertyDiff, PropertyEntry, PruneEntry, ReloadEntry, RestartEntry,
+        RestoreEntry, ServiceCreated, ServiceDeleted, ServiceEntry, SnapshotEntry, SourceEntry,
+        SourceUpdated, StartEntry, StatusChange, StopEntry, TemplateCreated, TemplateDeleted,
+        TemplateEntry, UpgradeEntry, VerifyEntry,
+    },
+    scaffold,
+    schedule::{self, ScheduleRecord},
+    service::{
+        dependency_batches, dependency_order, ReloadResult, RestartResult, ServiceInformation,
+        ServiceStatus, StartResult, StopResult,
+    },
+    template::{TemplateInfomation, MANIFEST_FILE},
+    upgrade::{self, MergeOutcome},
+    warning,
+    work::{self, WorkDirectory},
 };
 
+const RESTORE_STOP_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct CMD {
     pub quite: bool,
     pub json: bool,
+    /// Subcommands given on the command line, for the `--json` envelope.
+    pub command: String,
     pub workingdir: String,
     pub matches: ArgMatches,
 }
 
 impl CMD {
-    pub async fn list(self) {
+    pub async fn list(self) -> error::Result<()> {
         let workdir = WorkDirectory::new(&self.workingdir);
 
-        let services = workdir.services().clone();
+        let mut services = workdir.clone().services();
+        services.extend(workdir.invalid_services());
+
+        if !self.matches.get_flag("verbose") {
+            self.print(services);
+            return Ok(());
+        }
+
+        let services = services
+            .into_iter()
+            .map(|service| {
+                let origin = ServiceOrigin::load(&service.path);
+                let modified = origin
+                    .as_ref()
+                    .map(|ele| ele.modified_files(&service.path))
+                    .unwrap_or_default();
+                (service, origin, modified)
+            })
+            .collect::<Vec<_>>();
+
+        if self.quite {
+            return Ok(());
+        }
+
+        let result = if self.json {
+            let services = services
+                .into_iter()
+                .map(|(service, origin, modified)| ServiceEntry {
+                    modified: origin.is_some().then_some(modified),
+                    origin: origin.map(OriginEntry::from),
+                    ..ServiceEntry::from(service)
+                })
+                .collect();
+            self.envelope(services, vec![])
+        } else {
+            let mut table = Table::new();
+            table.set_titles(row![
+                "Service Name",
+                "Status",
+                "Template",
+                "Source",
+                "Commit",
+                "Created",
+                "Variables",
+                "Modified Files"
+            ]);
+            for (service, origin, modified) in services {
+                if let (ServiceStatus::Invalid, Some(error)) = (&service.status, &service.error) {
+                    table.add_row(row![service.name, Fr->service.status, error]);
+                    continue;
+                }
+                let Some(origin) = origin else {
+                    table.add_row(row![service.name, service.status, "unknown"]);
+                    continue;
+                };
+
+                table.add_row(row![
+                    service.name,
+                    service.status,
+                    origin.full_name(),
+                    origin.source.unwrap_or_default(),
+                    origin.commit.unwrap_or_default(),
+                    origin.created.format("%Y-%m-%d %H:%M:%S"),
+                    origin
+                        .vars
+                        .iter()
+                        .map(|(key, value)| format!("{}={}", key, value))
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                    format!("{}/{}", modified.len(), origin.files.len())
+                ]);
+            }
+            table.to_string()
+        };
 
-        self.print(services);
+        println!("{}", result);
+        Ok(())
     }
-    pub async fn start(self) {
-        let arg_services = self
-            .matches
-            .try_get_many::<String>("services")
-            .unwrap_or_default()
-            .unwrap_or_default()
-            .map(|ele| ele.to_string())
-            .collect::<Vec<String>>();
+    pub async fn start(self) -> error::Result<()> {
+        let arg_services = self.arg_services();
 
         let workingdir = WorkDirectory::new(&self.workingdir);
 
-        let services = filt_services(arg_services, workingdir.clone().services());
+        let services = filt_services(arg_services, workingdir.clone().services())?;
 
         let services = update_all_status(services)
             .await
@@ -48,19 +155,37 @@ impl CMD {
             .filter(|ele| ele.status != ServiceStatus::Start)
             .collect();
 
-        let services = start_all_services(services, workingdir.data_directory()).await;
+        let services = start_all_services(
+            services,
+            workingdir.clone().data_directory(),
+            &ArtifactCache::new(workingdir.artifact_directory()),
+        )
+        .await;
+
+        let failed = services
+            .iter()
+            .filter(|ele| !matches!(ele.1, Ok(StartResult::Success)))
+            .count();
+        let outcome = partial_failure(
+            failed,
+            services.len(),
+            "services failed to start",
+            Error::Service,
+        );
 
         if self.quite {
-            return;
+            return outcome;
         }
 
         let result = if self.json {
-            let services: Vec<_> = services
-                .into_iter()
-                .map(|ele| (ele.0, ele.1.ok()))
-                .collect();
-
-            serde_json::to_string_pretty(&services).expect("Cannot serialized into json")
+            let (results, errors) = split_results(
+                services
+                    .into_iter()
+                    .map(|(service, result)| (service.name, result)),
+                |service, result| StartEntry { service, result },
+                Error::Service,
+            );
+            self.envelope(results, errors)
         } else {
             let mut table = Table::new();
 
@@ -83,122 +208,426 @@ impl CMD {
             table.to_string()
         };
 
-        print!("{}", result);
+        println!("{}", result);
+        outcome
     }
-    pub async fn stop(self) {
-        let services = self
-            .matches
-            .try_get_many::<String>("service")
-            .unwrap_or_default()
-            .expect("Cannot get service list")
-            .map(|ele| ele.to_string())
-            .collect::<Vec<String>>();
+    pub async fn stop(self) -> error::Result<()> {
+        let services = self.arg_services();
 
-        let workdir = WorkDirectory::new(self.workingdir);
+        let workdir = WorkDirectory::new(&self.workingdir);
 
-        let mut workdir_services = workdir.clone().services();
+        let workdir_services = filt_services(services, workdir.clone().services())?;
 
-        workdir_services.retain(|ele| services.contains(&ele.name));
+        let (warn, now) = self.warn_args()?;
+        warning::countdown_all(&workdir_services, warn, now, "stop").await;
 
         let mut vec = vec![];
         for ele in workdir_services {
             vec.push((ele.clone(), ele.stop().await));
         }
 
+        let failed = vec
+            .iter()
+            .filter(|ele| !matches!(ele.1, Ok(StopResult::Success)))
+            .count();
+        let outcome = partial_failure(failed, vec.len(), "services failed to stop", Error::Service);
+
         if self.quite {
-            return;
+            return outcome;
         }
 
-        let vec = vec
-            .into_iter()
-            .map(|ele| {
-                (
-                    ele.0.name,
-                    if ele.1.is_err() {
-                        ele.1.unwrap_err().to_string()
-                    } else {
-                        ele.1.unwrap().to_string()
-                    },
+        let vec = vec.into_iter().map(|ele| (ele.0.name, ele.1));
+
+        let result = if self.json {
+            let (results, errors) = split_results(
+                vec,
+                |service, result| StopEntry { service, result },
+                Error::Service,
+            );
+            self.envelope(results, errors)
+        } else {
+            let mut table = Table::new();
+            table.set_titles(row!["Service Name", "Stop Result"]);
+            table.extend(
+                vec.map(|ele| row![ele.0, display_result(ele.1)])
+                    .collect::<Vec<_>>(),
+            );
+            table.to_string()
+        };
+
+        println!("{}", result);
+        outcome
+    }
+
+    pub async fn restart(self) -> error::Result<()> {
+        let timeout = parse_duration("timeout", required::<String>(&self.matches, "timeout")?)?;
+
+        let workingdir = WorkDirectory::new(&self.workingdir);
+
+        let services = filt_services(self.arg_services(), workingdir.clone().services())?;
+        let services = update_all_status(services).await;
+
+        let services = dependency_order(services).classify(Error::Invalid)?;
+
+        let cache = ArtifactCache::new(workingdir.clone().artifact_directory());
+
+        if self.matches.get_flag("rolling") {
+            return self
+                .rolling_restart(services, workingdir.data_directory(), &cache, timeout)
+                .await;
+        }
+
+        let (warn, now) = self.warn_args()?;
+        warning::countdown_all(&services, warn, now, "restart").await;
+
+        let result =
+            restart_all_services(services, workingdir.data_directory(), &cache, timeout).await;
+
+        let failed = result
+            .iter()
+            .filter(|ele| {
+                !matches!(
+                    ele.1,
+                    Ok(RestartResult::Success) | Ok(RestartResult::NotRunning)
                 )
             })
-            .collect::<Vec<_>>();
+            .count();
+        let outcome = partial_failure(
+            failed,
+            result.len(),
+            "services failed to restart",
+            Error::Service,
+        );
+
+        if self.quite {
+            return outcome;
+        }
+
+        let result = result
+            .into_iter()
+            .map(|(service, result)| (service.name, result));
 
         let result = if self.json {
-            serde_json::to_string_pretty(&vec).expect("Cannot serialized into json")
+            let (results, errors) = split_results(
+                result,
+                |service, result| RestartEntry {
+                    service,
+                    batch: None,
+                    result,
+                },
+                Error::Service,
+            );
+            self.envelope(results, errors)
         } else {
             let mut table = Table::new();
-            table.set_titles(row!["Service Name", "Stop Result"]);
+            table.set_titles(row!["Service Name", "Restart Result"]);
+            table.extend(result.map(|ele| row![ele.0, display_result(ele.1)]));
+            table.to_string()
+        };
+
+        println!("{}", result);
+        outcome
+    }
+
+    /// Restart services batch by batch, aborting as soon as one service of a batch fails its health
+    /// check. A service is never restarted in the same batch as a service it depends on.
+    async fn rolling_restart(
+        self,
+        services: Vec<ServiceInformation>,
+        data_dir: PathBuf,
+        cache: &ArtifactCache,
+        timeout: Duration,
+    ) -> error::Result<()> {
+        let batch = *required::<usize>(&self.matches, "batch")?;
+        let wait_healthy = parse_duration(
+            "wait-healthy",
+            required::<String>(&self.matches, "wait-healthy")?,
+        )?;
+
+        let (warn, now) = self.warn_args()?;
+        warning::countdown_all(&services, warn, now, "restart").await;
+
+        let mut aborted = false;
+        let mut result = vec![];
+
+        for (index, chunk) in dependency_batches(services, batch).iter().enumerate() {
+            if aborted {
+                result.extend(
+                    chunk
+                        .iter()
+                        .map(|ele| (index + 1, ele.name.clone(), Ok(RestartResult::Aborted))),
+                );
+                continue;
+            }
+
+            let handles = chunk
+                .iter()
+                .map(|ele| {
+                    let mut data_dir = data_dir.clone();
+                    data_dir.push(&ele.name);
+
+                    let ele = ele.clone();
+                    let cache = cache.clone();
+                    tokio::spawn(async move {
+                        restart_until_healthy(ele, data_dir, &cache, timeout, wait_healthy).await
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for (ele, handle) in chunk.iter().zip(handles) {
+                let restarted = handle
+                    .await
+                    .unwrap_or_else(|err| Err(anyhow!("Restart task failed: {}", err)));
+                if !matches!(
+                    restarted,
+                    Ok(RestartResult::Success) | Ok(RestartResult::NotRunning)
+                ) {
+                    aborted = true;
+                }
+                result.push((index + 1, ele.name.clone(), restarted));
+            }
+        }
+
+        let outcome = if aborted {
+            Err(Error::Service(
+                "Rolling restart aborted, remaining batches were not restarted.".to_string(),
+            ))
+        } else {
+            Ok(())
+        };
+
+        if self.quite {
+            return outcome;
+        }
+
+        let result = if self.json {
+            let (results, errors) = split_results(
+                result
+                    .into_iter()
+                    .map(|(batch, service, result)| (service, result.map(|ele| (batch, ele)))),
+                |service, (batch, result)| RestartEntry {
+                    service,
+                    batch: Some(batch),
+                    result,
+                },
+                Error::Service,
+            );
+            self.envelope(results, errors)
+        } else {
+            let mut table = Table::new();
+            table.set_titles(row!["Batch", "Service Name", "Restart Result"]);
+            table.extend(
+                result
+                    .into_iter()
+                    .map(|ele| row![ele.0, ele.1, display_result(ele.2)]),
+            );
+            table.to_string()
+        };
+
+        println!("{}", result);
+        outcome
+    }
+
+    pub async fn reload(self) -> error::Result<()> {
+        let workingdir = WorkDirectory::new(&self.workingdir);
+
+        let services = filt_services(self.arg_services(), workingdir.services())?;
+
+        let mut vec = vec![];
+        let mut failed = 0;
+        for ele in services {
+            let result = ele.clone().reload().await;
+            if !matches!(
+                result,
+                Ok(ReloadResult::Success) | Ok(ReloadResult::NoScript)
+            ) {
+                failed += 1;
+            }
+            vec.push((ele.name, result));
+        }
+        let outcome = partial_failure(
+            failed,
+            vec.len(),
+            "services failed to reload",
+            Error::Service,
+        );
+
+        if self.quite {
+            return outcome;
+        }
+
+        let result = if self.json {
+            let (results, errors) = split_results(
+                vec,
+                |service, result| ReloadEntry { service, result },
+                Error::Service,
+            );
+            self.envelope(results, errors)
+        } else {
+            let mut table = Table::new();
+            table.set_titles(row!["Service Name", "Reload Result"]);
             table.extend(
                 vec.into_iter()
-                    .map(|ele| row![ele.0, ele.1])
-                    .collect::<Vec<_>>(),
+                    .map(|ele| row![ele.0, display_result(ele.1)]),
             );
             table.to_string()
         };
 
-        print!("{}", result)
+        println!("{}", result);
+        outcome
     }
 
-    pub async fn status(self) {
+    pub async fn status(self) -> error::Result<()> {
         let services = self
             .matches
             .try_get_many::<String>("service")
             .unwrap_or_default();
 
-        let services = if services.is_none() {
-            vec![]
-        } else {
-            services
-                .unwrap()
-                .map(|ele| ele.to_string())
-                .collect::<Vec<String>>()
+        let services = match services {
+            Some(services) => services.map(|ele| ele.to_string()).collect::<Vec<String>>(),
+            None => vec![],
         };
 
-        let workingdir = WorkDirectory::new(self.workingdir);
+        if self.matches.get_flag("watch") {
+            return self.watch(services).await;
+        }
 
-        let workingdir_service = if services.len() == 0 {
-            workingdir.services().clone()
-        } else {
-            let mut v = workingdir.services().clone().clone();
-            v.retain(|ele| services.contains(&ele.name));
-            v
-        };
+        let workingdir = WorkDirectory::new(&self.workingdir);
 
-        let mut vec = vec![];
-        for ele in workingdir_service {
-            vec.push((
-                ele.clone().name,
-                ele.update_status().await.status.to_string(),
-            ))
+        let mut workingdir_service = workingdir.clone().services();
+        workingdir_service.extend(workingdir.invalid_services());
+        if !services.is_empty() {
+            workingdir_service = filt_services(services, workingdir_service)?;
         }
 
+        let workingdir_service = update_all_status(workingdir_service).await;
+
         let result = if self.json {
-            serde_json::to_string_pretty(&vec).expect("Cannot serialized into json")
+            self.envelope(
+                workingdir_service
+                    .into_iter()
+                    .map(ServiceEntry::from)
+                    .collect(),
+                vec![],
+            )
         } else {
+            let vec = workingdir_service
+                .into_iter()
+                .map(|ele| (ele.name, ele.status.to_string(), ele.error))
+                .collect::<Vec<_>>();
             let mut table = Table::new();
-            table.set_titles(row!["Serivice Name", "Service Status"]);
+            if vec.iter().any(|ele| ele.2.is_some()) {
+                table.set_titles(row!["Serivice Name", "Service Status", "Error"]);
+            } else {
+                table.set_titles(row!["Serivice Name", "Service Status"]);
+            }
             let vec = vec
                 .into_iter()
-                .map(|(name, status)| row![name, status])
+                .map(|(name, status, error)| match error {
+                    Some(error) => row![name, Fr->status, error],
+                    None => row![name, status],
+                })
                 .collect::<Vec<_>>();
             table.extend(vec);
             table.to_string()
         };
 
-        print!("{}", result)
+        println!("{}", result);
+        Ok(())
+    }
+
+    async fn watch(self, services: Vec<String>) -> error::Result<()> {
+        let interval = parse_duration("interval", required::<String>(&self.matches, "interval")?)?;
+
+        let mut last_status = HashMap::<String, ServiceStatus>::new();
+
+        loop {
+            let workingdir = WorkDirectory::new(&self.workingdir);
+
+            let mut workingdir_service = workingdir.services();
+            if !services.is_empty() {
+                workingdir_service = filt_services(services.clone(), workingdir_service)?;
+            }
+
+            let workingdir_service = update_all_status(workingdir_service).await;
+
+            if self.json {
+                for ele in &workingdir_service {
+                    let previous = last_status.insert(ele.name.clone(), ele.status.clone());
+                    if previous.as_ref() == Some(&ele.status) {
+                        continue;
+                    }
+
+                    let change = StatusChange {
+                        time: Local::now(),
+                        service: ele.name.clone(),
+                        previous,
+                        status: ele.status.clone(),
+                    };
+                    let envelope = Envelope::new("status --watch", vec![change], vec![]);
+                    println!(
+                        "{}",
+                        serde_json::to_string(&envelope).expect("Cannot serialized into json")
+                    );
+                }
+            } else {
+                let mut table = Table::new();
+                table.set_titles(row![
+                    "Service Name",
+                    "Service Status",
+                    "Players",
+                    "Uptime",
+                    "Resource Usage"
+                ]);
+
+                for ele in &workingdir_service {
+                    let metrics = ServiceMetrics::collect(ele).await;
+                    let style = match ele.status {
+                        ServiceStatus::Start => "Fg",
+                        ServiceStatus::Stop => "Fr",
+                        ServiceStatus::Disable => "Fd",
+                        ServiceStatus::Unknow => "Fy",
+                        ServiceStatus::Invalid => "Fr",
+                    };
+
+                    table.add_row(Row::new(vec![
+                        Cell::new(&ele.name),
+                        Cell::new(&ele.status.to_string()).style_spec(style),
+                        Cell::new(&metrics.players_string()),
+                        Cell::new(&metrics.uptime_string()),
+                        Cell::new(&metrics.resource_string()),
+                    ]));
+                }
+
+                // Clear the screen and move the cursor home so the table redraws in place.
+                print!("\x1B[2J\x1B[H");
+                println!(
+                    "Every {}: {}\n",
+                    humantime::format_duration(interval),
+                    Local::now().format("%Y-%m-%d %H:%M:%S")
+                );
+                table.printstd();
+            }
+
+            tokio::time::sleep(interval).await;
+        }
     }
 
-    pub async fn template(self) {
-        let workingdir = WorkDirectory::new(self.workingdir);
+    pub async fn template(self) -> error::Result<()> {
+        let workingdir = WorkDirectory::new(&self.workingdir);
 
         match self.matches.subcommand() {
             Some(("create", matches)) => {
-                let name = matches
-                    .try_get_many::<String>("name")
-                    .unwrap_or_default()
-                    .expect("Cannot get name")
-                    .map(|ele| ele.to_string())
-                    .collect::<Vec<String>>();
+                let name = required_many(matches, "name")?;
+
+                if let Some(service) = matches.get_one::<String>("from-service") {
+                    let [name] = name.as_slice() else {
+                        return Err(Error::Usage(
+                            "Give exactly one template name with --from-service".to_string(),
+                        ));
+                    };
+                    return self.template_from_service(workingdir, matches, service, name);
+                }
 
                 let templates = name
                     .into_iter()
@@ -207,38 +636,57 @@ impl CMD {
                         template.push(ele);
                         template
                     })
-                    .map(|path| fs::create_dir_all(&path).map(|_| path.clone()).ok())
-                    .filter(|ele| ele.is_some())
-                    .map(|ele| ele.unwrap())
-                    .map(|path| {
-                        let mut path = path.clone();
+                    .map(|dir| {
+                        let mut path = dir.clone();
                         path.push("manifest.toml");
 
-                        let file = File::create(&path).expect("Cannot create file");
-
-                        let mut name = path.clone();
-                        name.pop();
-
                         let default = ManifestContent {
                             ..Default::default()
                         };
                         let default =
                             toml::to_string_pretty(&default).expect("Cannot serialized into toml");
 
-                        let buffer = BufWriter::new(file).write_all(default.as_bytes());
+                        let written = fs::create_dir_all(&dir)
+                            .and_then(|_| File::create(&path))
+                            .and_then(|file| BufWriter::new(file).write_all(default.as_bytes()));
 
-                        (path.clone(), buffer.is_ok())
+                        (path, written)
                     })
                     .collect::<Vec<_>>();
+                let failed = templates
+                    .iter()
+                    .filter(|(_, written)| written.is_err())
+                    .count();
+                let outcome = partial_failure(
+                    failed,
+                    templates.len(),
+                    "templates could not be created",
+                    Error::Io,
+                );
 
                 let result = if self.json {
-                    serde_json::to_string_pretty(&templates).expect("Cannot serialized into json")
+                    let (results, errors) = split_results(
+                        templates.into_iter().map(|(path, written)| {
+                            let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+                            (
+                                path.display().to_string(),
+                                written.map(|_| dir).map_err(Into::into),
+                            )
+                        }),
+                        |_, path| TemplateCreated {
+                            path,
+                            files: 1,
+                            placeholders: vec![],
+                        },
+                        Error::Io,
+                    );
+                    self.envelope(results, errors)
                 } else {
                     let mut table = Table::new();
                     table.set_titles(row!["Manifest Path", "Is Ok"]);
                     let templates = templates
                         .into_iter()
-                        .map(|(path, is_ok)| row![path.to_str().unwrap(), is_ok]);
+                        .map(|(path, written)| row![path.display(), written.is_ok()]);
 
                     table.extend(templates);
 
@@ -246,28 +694,57 @@ impl CMD {
                 };
 
                 if !self.quite {
-                    print!("{}", result);
+                    println!("{}", result);
                 }
+                outcome
             }
             Some(("list", _)) => {
                 let tem = workingdir
                     .templates()
                     .into_iter()
                     .map(|ele| {
-                        let path = ele.clone();
-                        (path.name, path.path.clone().to_owned())
+                        (
+                            ele.name,
+                            ele.version,
+                            ele.source,
+                            ele.template.extends,
+                            ele.path,
+                        )
                     })
                     .collect::<Vec<_>>();
 
                 let result = if self.json {
-                    serde_json::to_string_pretty(&tem).expect("Cannot serialized into json")
+                    let tem = tem
+                        .into_iter()
+                        .map(|(template, version, source, extends, path)| TemplateEntry {
+                            template,
+                            version,
+                            source,
+                            extends,
+                            path,
+                        })
+                        .collect();
+                    self.envelope(tem, vec![])
                 } else {
                     let mut table = Table::new();
-                    table.set_titles(row!["Template Name", "Template Path"]);
-
-                    tem.into_iter().for_each(|(name, path)| {
-                        table.add_row(row![name, path.to_str().unwrap()]);
-                    });
+                    table.set_titles(row![
+                        "Template Name",
+                        "Version",
+                        "Source",
+                        "Extends",
+                        "Template Path"
+                    ]);
+
+                    tem.into_iter()
+                        .for_each(|(name, version, source, extends, path)| {
+                            table.add_row(row![
+                                name,
+                                version.unwrap_or_default(),
+                                source.unwrap_or_default(),
+                                extends.unwrap_or_default(),
+                                path.display()
+                            ]);
+                        });
 
                     table.to_string()
                 };
@@ -275,141 +752,433 @@ impl CMD {
                 if !self.quite {
                     println!("{}", result)
                 }
+                Ok(())
             }
             Some(("delete", matches)) => {
-                let name = matches
-                    .try_get_many::<String>("name")
-                    .unwrap_or_default()
-                    .expect("Cannot get name")
-                    .map(|ele| ele.to_string())
-                    .collect::<Vec<String>>();
+                let name = required_many(matches, "name")?;
                 let mut tem = workingdir.templates().clone();
 
-                tem.retain(|ele| name.contains(&ele.name));
+                tem.retain(|ele| name.contains(&ele.name) || name.contains(&ele.full_name()));
+                if tem.is_empty() {
+                    return Err(Error::NotFound(format!(
+                        "There is no template with the name {}",
+                        name.join(", ")
+                    )));
+                }
                 let action_res = tem
                     .into_iter()
-                    .map(|ele| {
-                        let mut path = ele.path.clone();
-                        path.pop();
-                        (ele.name, path)
-                    })
-                    .map(|ele| {
-                        if fs::remove_dir_all(ele.1).is_err() {
-                            (ele.0, false)
-                        } else {
-                            (ele.0, true)
-                        }
-                    })
+                    .map(|ele| (ele.full_name(), ele.path))
+                    .map(|ele| (ele.0, fs::remove_dir_all(ele.1)))
                     .collect::<Vec<_>>();
+                let failed = action_res.iter().filter(|(_, ok)| ok.is_err()).count();
+                let outcome = partial_failure(
+                    failed,
+                    action_res.len(),
+                    "templates could not be deleted",
+                    Error::Io,
+                );
 
                 if self.quite {
-                    return;
+                    return outcome;
                 }
 
                 let r = if self.json {
-                    serde_json::to_string_pretty(&action_res).expect("Cannot serialized into json")
+                    let (results, errors) = split_results(
+                        action_res
+                            .into_iter()
+                            .map(|(template, removed)| (template, removed.map_err(Into::into))),
+                        |template, _| TemplateDeleted { template },
+                        Error::Io,
+                    );
+                    self.envelope(results, errors)
                 } else {
                     let mut table = Table::new();
                     table.set_titles(row!["Template Name", "Action Result"]);
                     let r = action_res
                         .into_iter()
-                        .map(|ele| row![ele.0, ele.1])
+                        .map(|ele| row![ele.0, ele.1.is_ok()])
                         .collect::<Vec<_>>();
 
                     table.extend(r);
                     table.to_string()
                 };
 
-                println!("{}", r)
+                println!("{}", r);
+                outcome
             }
-            _ => {}
-        }
-    }
-    pub async fn create(self) {
-        let service_name = self.matches.get_one::<String>("name").unwrap();
-        let template_name = self.matches.get_one::<String>("template").unwrap();
-        let workdir = WorkDirectory::new(self.workingdir);
-
-        let templates = workdir
-            .clone()
-            .templates()
-            .into_iter()
-            .find(|ele| ele.name == *template_name);
-
-        let service = workdir
-            .clone()
-            .services()
-            .into_iter()
-            .find(|ele| ele.name == *service_name);
+            Some(("update", matches)) => {
+                let name = matches
+                    .try_get_many::<String>("name")
+                    .unwrap_or_default()
+                    .unwrap_or_default()
+                    .map(|ele| ele.to_string())
+                    .collect::<Vec<String>>();
 
-        let mut action_result = (service_name, template_name, false, "".to_string());
+                let config = SourcesConfig::load(workingdir.clone().sources_file())
+                    .classify(Error::Invalid)?;
+                let template_dir = workingdir.template_directory();
+                fs::create_dir_all(&template_dir).classify(Error::Io)?;
 
-        if templates.is_none() {
-            println!("There is no template with the name {}", template_name);
-            return;
-        } else if service.is_some() {
-            println!("There is already searvice with the name {}", service_name);
-            return;
-        }
+                let action_res = config
+                    .sources
+                    .into_iter()
+                    .filter(|ele| name.is_empty() || name.contains(&ele.name))
+                    .map(|ele| {
+                        let state = ele.update(&template_dir);
+                        (ele.name, state)
+                    })
+                    .collect::<Vec<_>>();
+                let failed = action_res
+                    .iter()
+                    .filter(|(_, state)| state.is_err())
+                    .count();
+                let outcome = partial_failure(
+                    failed,
+                    action_res.len(),
+                    "sources could not be updated",
+                    Error::Io,
+                );
 
-        let template = templates.unwrap();
+                if self.quite {
+                    return outcome;
+                }
 
-        let template_path = template.path.clone();
+                let r = if self.json {
+                    let (results, errors) = split_results(
+                        action_res,
+                        |source, state| SourceUpdated {
+                            source,
+                            commit: state.commit,
+                        },
+                        Error::Io,
+                    );
+                    self.envelope(results, errors)
+                } else {
+                    let mut table = Table::new();
+                    table.set_titles(row!["Source", "Action Result", "Infomation"]);
+                    table.extend(action_res.into_iter().map(|(name, state)| match state {
+                        Ok(state) => row![name, true, state.commit.unwrap_or_default()],
+                        Err(err) => row![name, false, err],
+                    }));
+                    table.to_string()
+                };
 
-        // template_path.pop();
+                println!("{}", r);
+                outcome
+            }
+            Some(("sources", _)) => {
+                let config = SourcesConfig::load(workingdir.clone().sources_file())
+                    .classify(Error::Invalid)?;
+                let template_dir = workingdir.template_directory();
 
-        let mut service_path = workdir.clone().service_directory().clone();
-        service_path.push(service_name);
+                let sources = config
+                    .sources
+                    .into_iter()
+                    .map(|ele| {
+                        let state = SourceState::load(template_dir.join(&ele.name));
+                        (ele, state)
+                    })
+                    .collect::<Vec<_>>();
 
-        fs::create_dir_all(&service_path).expect("Cannot create directory.");
+                if self.quite {
+                    return Ok(());
+                }
 
-        let strip = |template_path: PathBuf| {
-            let mut root = workdir.clone().service_directory();
-            root.pop();
+                let r = if self.json {
+                    let sources = sources
+                        .into_iter()
+                        .map(|(source, state)| SourceEntry {
+                            location: source.location.to_string(),
+                            reference: source.location.reference().map(str::to_string),
+                            commit: state.as_ref().and_then(|ele| ele.commit.clone()),
+                            updated: state.map(|ele| ele.updated),
+                            source: source.name,
+                        })
+                        .collect();
+                    self.envelope(sources, vec![])
+                } else {
+                    let mut table = Table::new();
+                    table.set_titles(row!["Source", "Location", "Ref", "Commit", "Updated"]);
+                    for (source, state) in sources {
+                        table.add_row(row![
+                            source.name,
+                            source.location,
+                            source.location.reference().unwrap_or_default(),
+                            state
+                                .as_ref()
+                                .and_then(|ele| ele.commit.clone())
+                                .unwrap_or_default(),
+                            state
+                                .map(|ele| ele.updated.format("%Y-%m-%d %H:%M:%S").to_string())
+                                .unwrap_or_else(|| "never".to_string())
+                        ]);
+                    }
+                    table.to_string()
+                };
 
-            let p = PathBuf::from(template_path.strip_prefix(root).unwrap());
-            let p = PathBuf::from(p.strip_prefix("templates").unwrap());
+                println!("{}", r);
+                Ok(())
+            }
+            Some(("lint", matches)) => {
+                let name = matches.get_one::<String>("NAME");
+                let templates = workingdir.clone().templates();
 
-            let mut p = p
-                .into_iter()
-                .map(|ele| ele.to_str().unwrap().to_string())
-                .collect::<Vec<_>>();
+                let issues = work::manifest_paths(workingdir.template_directory())
+                    .into_iter()
+                    .filter(|path| {
+                        let directory = path
+                            .parent()
+                            .and_then(|ele| ele.file_name())
+                            .map(|ele| ele.to_string_lossy().to_string())
+                            .unwrap_or_default();
+                        name.is_none_or(|name| {
+                            *name == directory || registry::split_version(&directory).0 == name
+                        })
+                    })
+                    .flat_map(|path| lint::lint_template(&path, &templates))
+                    .collect::<Vec<_>>();
 
-            if p.len() != 0 {
-                p.remove(0);
+                self.print_lint(issues);
+                Ok(())
             }
-            let mut path = PathBuf::new();
-            path.extend(p);
+            _ => Ok(()),
+        }
+    }
 
-            path
-        };
+    fn template_from_service(
+        &self,
+        workingdir: WorkDirectory,
+        matches: &ArgMatches,
+        service_name: &str,
+        template_name: &str,
+    ) -> error::Result<()> {
+        let service = find_service(workingdir.clone(), service_name)?;
+
+        let target = workingdir.template_directory().join(template_name);
+        if target.exists() {
+            return Err(Error::Usage(format!(
+                "There is already a template with the name {}",
+                template_name
+            )));
+        }
 
-        for ele in walkdir::WalkDir::new(template_path) {
-            let ele = ele.expect("Cannot visit");
+        let exclude = matches
+            .try_get_many::<String>("exclude")
+            .unwrap_or_default()
+            .unwrap_or_default()
+            .cloned()
+            .collect::<Vec<_>>();
+        let ignore = scaffold::ignore_rules(&service.path, &exclude).classify(Error::Usage)?;
 
-            let striped = strip(ele.clone().into_path().clone());
+        fs::create_dir_all(&target).classify(Error::Io)?;
+        let files = scaffold::copy_service(&service, &target, &ignore).classify(Error::Io)?;
 
-            let mut target_path = service_path.clone();
-            target_path.push(striped);
+        // Every suggestion is taken with --yes, otherwise the user is asked when there is
+        // someone to ask.
+        let interactive = !self.quite && !self.json && std::io::stdin().is_terminal();
+        let replacements = scaffold::candidates(&service, &target, &files)
+            .into_iter()
+            .filter(|ele| {
+                if matches.get_flag("yes") {
+                    return true;
+                }
+                interactive
+                    && confirm(&format!(
+                        "Replace `{}` ({} occurrences) with {{{}}}?",
+                        ele.value, ele.occurrences, ele.var
+                    ))
+            })
+            .collect::<Vec<_>>();
 
-            if ele.file_type().is_dir() {
-                fs::create_dir_all(target_path).expect("Cannot create directory");
-            } else if ele.file_type().is_file() {
-                fs_extra::file::copy(ele.path(), target_path, &fs_extra::file::CopyOptions::new())
-                    .expect("Cannot copy file");
-            } else {
-                continue;
-            }
+        scaffold::apply(&target, &files, &replacements, &service.name).classify(Error::Io)?;
 
-            action_result.2 = true
+        if self.quite {
+            return Ok(());
         }
 
+        let result = if self.json {
+            let created = TemplateCreated {
+                path: target,
+                files: files.len(),
+                placeholders: replacements,
+            };
+            self.envelope(vec![created], vec![])
+        } else {
+            let mut table = Table::new();
+            table.set_titles(row!["Template Path", "Files", "Placeholders"]);
+            table.add_row(row![
+                target.display(),
+                files.len(),
+                replacements
+                    .iter()
+                    .map(|ele| format!("{} = {}", ele.var, ele.value))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ]);
+            table.to_string()
+        };
+
+        println!("{}", result);
+        Ok(())
+    }
+
+    pub async fn service(self) -> error::Result<()> {
+        let workingdir = WorkDirectory::new(&self.workingdir);
+
+        if let Some(("lint", matches)) = self.matches.subcommand() {
+            let name = matches.get_one::<String>("NAME");
+
+            let mut issues = work::manifest_paths(workingdir.clone().service_directory())
+                .into_iter()
+                .filter(|path| {
+                    name.is_none_or(|name| {
+                        path.parent().and_then(|ele| ele.file_name()) == Some(name.as_ref())
+                    })
+                })
+                .flat_map(|path| lint::lint_service(&path))
+                .collect::<Vec<_>>();
+            issues.extend(
+                port_issues(workingdir.services())
+                    .await
+                    .into_iter()
+                    .filter(|ele| name.is_none_or(|name| ele.name == *name)),
+            );
+
+            self.print_lint(issues);
+        }
+        Ok(())
+    }
+
+    pub async fn create(self) -> error::Result<()> {
+        let service_name = required::<String>(&self.matches, "name")?;
+        let template_name = required::<String>(&self.matches, "template")?;
+        let workdir = WorkDirectory::new(&self.workingdir);
+
+        let template = workdir.clone().resolve_template(template_name)?;
+
+        let service = workdir
+            .clone()
+            .services()
+            .into_iter()
+            .find(|ele| ele.name == *service_name);
+        if service.is_some() {
+            return Err(Error::Usage(format!(
+                "There is already searvice with the name {}",
+                service_name
+            )));
+        }
+
+        let mut action_result = (service_name, template.full_name(), false, "".to_string());
+
+        let mut vars = self
+            .matches
+            .try_get_many::<String>("var")
+            .unwrap_or_default()
+            .unwrap_or_default()
+            .map(|ele| {
+                let (key, value) = ele.split_once('=').ok_or_else(|| {
+                    Error::Usage(format!("Variables are KEY=VALUE, not `{}`", ele))
+                })?;
+                Ok((key.to_string(), value.to_string()))
+            })
+            .collect::<error::Result<BTreeMap<_, _>>>()?;
+        vars.entry("SERVICE_NAME".to_string())
+            .or_insert_with(|| service_name.clone());
+
+        let mut missing = vec![];
+        for (key, variable) in &template.template.variables {
+            if variable.kind == VariableType::Port {
+                continue;
+            }
+            match &variable.default {
+                Some(default) => {
+                    vars.entry(key.clone()).or_insert_with(|| default.clone());
+                }
+                None if !vars.contains_key(key) => missing.push(key.as_str()),
+                None => {}
+            }
+        }
+        if !missing.is_empty() {
+            return Err(Error::Usage(format!(
+                "Template {} needs a value for {}, use --var KEY=VALUE",
+                template.full_name(),
+                missing.join(", ")
+            )));
+        }
+
+        let ports = assign_ports(workdir.clone(), &template, &mut vars).classify(Error::Invalid)?;
+
+        let mut service_path = workdir.clone().service_directory().clone();
+        service_path.push(service_name);
+
+        // The directory is removed again when the service cannot be created, so it must not hold
+        // anything else.
+        if fs::read_dir(&service_path).is_ok_and(|mut ele| ele.next().is_some()) {
+            return Err(Error::Usage(format!(
+                "Directory {} already exists",
+                service_path.display()
+            )));
+        }
+        fs::create_dir_all(&service_path).classify(Error::Io)?;
+
+        let created = template
+            .materialize(&service_path, &vars)
+            .map_err(|err| err.to_string())
+            .and_then(|files| {
+                let cache = ArtifactCache::new(workdir.clone().artifact_directory());
+                ServiceInformation::new(service_path.join(MANIFEST_FILE))
+                    .and_then(|service| service.prepare(&cache))
+                    .map_err(|err| format!("Cannot prepare service: {:#}", err))?;
+
+                let template_dir = workdir.clone().template_directory();
+                let state = template
+                    .source
+                    .as_ref()
+                    .and_then(|source| SourceState::load(template_dir.join(source)));
+                let mut origin = ServiceOrigin::new(&template, &template_dir, state, vars, files);
+                origin.ports = ports;
+                origin
+                    .save(&service_path)
+                    .map_err(|err| format!("Cannot record origin: {}", err))
+            });
+        match created {
+            Ok(()) => action_result.2 = true,
+            Err(err) => {
+                action_result.3 = err;
+                if let Err(err) = fs::remove_dir_all(&service_path) {
+                    action_result.3 +=
+                        &format!(", cannot remove {}: {}", service_path.display(), err);
+                }
+            }
+        }
+        let outcome = if action_result.2 {
+            Ok(())
+        } else {
+            Err(Error::Io(format!(
+                "Cannot create service {}: {}",
+                service_name, action_result.3
+            )))
+        };
+
         if self.quite {
-            return;
+            return outcome;
         }
 
         let result = if self.json {
-            serde_json::to_string_pretty(&action_result).expect("Cannot serialized into json")
+            match &outcome {
+                Ok(()) => {
+                    let created = ServiceCreated {
+                        service: action_result.0.to_string(),
+                        template: action_result.1,
+                        path: service_path,
+                    };
+                    self.envelope(vec![created], vec![])
+                }
+                Err(err) => self.envelope::<ServiceCreated>(
+                    vec![],
+                    vec![ErrorEntry::new(Some(action_result.0), err)],
+                ),
+            }
         } else {
             let mut table = Table::new();
             table.set_titles(row![
@@ -427,80 +1196,989 @@ impl CMD {
             table.to_string()
         };
 
-        println!("{}", result)
+        println!("{}", result);
+        outcome
     }
-    pub async fn delete(self) {
-        let service_name = self.matches.get_one::<String>("name").unwrap().to_owned();
-        let workdir = WorkDirectory::new(self.workingdir);
-        let services = workdir.services().clone();
-        let service = services.into_iter().find(|ele| ele.name == service_name);
-
-        let mut action_result = (service_name, false, "".to_string());
-
-        if service.is_none() {
-            action_result.2 = "No such service found.".to_string()
-        } else if let Some(service) = service {
-            let service = service.update_status().await;
-
-            let stopped = match service.status {
-                ServiceStatus::Start => match service.stop().await {
-                    Ok(result) => match result {
-                        StopResult::StopFailed(ecode) => (false, ecode, "Stop failed".to_string()),
-                        StopResult::PostStopFailed(ecode) => {
-                            (false, ecode, "Post-stop failed".to_string())
-                        }
-                        _ => (true, 0, String::new()),
-                    },
-                    Err(_) => (false, -1, String::new()),
-                },
-                _ => (true, 0, String::new()),
-            };
+    pub async fn delete(self) -> error::Result<()> {
+        let service_name = required::<String>(&self.matches, "name")?.to_owned();
+        let workdir = WorkDirectory::new(&self.workingdir);
+        let service = find_service(workdir, &service_name)?.update_status().await;
 
-            action_result.1 = stopped.0;
-            action_result.2 = if stopped.0 == true {
-                format!("Stop succeed!")
-            } else {
-                format!("{},script exit code is {}", stopped.2, stopped.1)
-            }
-        }
+        let describe = |failed: &str, termination: Termination| match termination.signal {
+            Some(_) => format!("{},script was killed by {}", failed, termination),
+            None => format!("{},script exit code is {}", failed, termination),
+        };
+        let running = service.status == ServiceStatus::Start;
+        let stopped = match service.status {
+            ServiceStatus::Start => match service.stop().await {
+                Ok(StopResult::StopFailed(termination)) => {
+                    Err(describe("Stop failed", termination))
+                }
+                Ok(StopResult::PostStopFailed(termination)) => {
+                    Err(describe("Post-stop failed", termination))
+                }
+                Ok(StopResult::Success) => Ok(()),
+                Err(err) => Err(err.to_string()),
+            },
+            _ => Ok(()),
+        };
+
+        let action_result = (
+            service_name,
+            stopped.is_ok(),
+            stopped.err().unwrap_or_else(|| "Stop succeed!".to_string()),
+        );
+        let outcome = if action_result.1 {
+            Ok(())
+        } else {
+            Err(Error::Service(format!(
+                "Cannot stop service {}: {}",
+                action_result.0, action_result.2
+            )))
+        };
 
         if self.quite {
-            return;
+            return outcome;
         }
 
         let result = if self.json {
-            serde_json::to_string_pretty(&action_result).expect("Cannot serialized into json")
+            match &outcome {
+                Ok(()) => {
+                    let deleted = ServiceDeleted {
+                        service: action_result.0,
+                        stopped: running,
+                    };
+                    self.envelope(vec![deleted], vec![])
+                }
+                Err(err) => self.envelope::<ServiceDeleted>(
+                    vec![],
+                    vec![ErrorEntry::new(Some(&action_result.0), err)],
+                ),
+            }
         } else {
             let mut table = Table::new();
             table.set_titles(row!["Service Name", "Status", "Infomation"]);
             table.add_row(row![action_result.0, action_result.1, action_result.2]);
             table.to_string()
         };
-        print!("{}", result)
+        println!("{}", result);
+        outcome
     }
 
-    pub async fn init(self) {
-        let mut cmd = Command::new("bash");
+    pub async fn upgrade(self) -> error::Result<()> {
+        let workdir = WorkDirectory::new(&self.workingdir);
+        let templates_dir = workdir.clone().template_directory();
+        let templates = workdir.clone().templates();
+        let to = self.matches.get_one::<String>("to");
 
-        let cmd = cmd
-            .arg("-c")
-            .arg("git clone https://github.com/OakMemory/smcmanager-templates templates")
-            .current_dir(self.workingdir);
+        let services = filt_services(self.arg_services(), workdir.clone().services())?;
+        let services = update_all_status(services).await;
 
-        let output = cmd.output().expect("Cannot get output");
+        let names = services
+            .iter()
+            .map(|ele| ele.name.clone())
+            .collect::<Vec<_>>();
+        let results = services.into_iter().map(|service| {
+            if service.status == ServiceStatus::Start {
+                return Err(anyhow!(
+                    "Service {} is running, stop it before upgrading",
+                    service.name
+                ));
+            }
+            let origin = ServiceOrigin::load(&service.path)
+                .ok_or_else(|| anyhow!("{} has no recorded template origin", service.name))?;
+            let spec = match to {
+                Some(version) => format!("{}@{}", origin.template, version),
+                None => origin.template.clone(),
+            };
+            let target = workdir.clone().resolve_template(&spec)?;
 
-        if !output.status.success() {
-            print!(
-                "Initialize templates failed: {}",
-                String::from_utf8(output.stderr).expect("Cannot into string")
-            )
+            upgrade::upgrade(&service, &origin, &target, &templates_dir, &templates)
+        });
+        let results = names.into_iter().zip(results).collect::<Vec<_>>();
+
+        let failed = results.iter().filter(|ele| ele.1.is_err()).count();
+        let outcome = partial_failure(
+            failed,
+            results.len(),
+            "services could not be upgraded",
+            Error::Invalid,
+        );
+
+        if self.quite {
+            return outcome;
+        }
+
+        if self.json {
+            let (results, errors) = split_results(
+                results,
+                |service, report| UpgradeEntry {
+                    service,
+                    from: report.from,
+                    to: report.to,
+                    files: report
+                        .files
+                        .into_iter()
+                        .map(|(file, outcome)| FileOutcome { file, outcome })
+                        .collect(),
+                },
+                Error::Invalid,
+            );
+            println!("{}", self.envelope(results, errors));
+            return outcome;
+        }
+
+        let mut table = Table::new();
+        table.set_titles(row!["Service Name", "Template", "File", "Result"]);
+        for (name, result) in results {
+            match result {
+                Ok(report) => {
+                    let changed = report
+                        .files
+                        .iter()
+                        .filter(|(_, outcome)| *outcome != MergeOutcome::Unchanged)
+                        .collect::<Vec<_>>();
+                    let template = format!("{} -> {}", report.from, report.to);
+
+                    if changed.is_empty() {
+                        table.add_row(row![report.service, template, "", "Up to date"]);
+                    }
+                    for (file, outcome) in changed {
+                        let style = match outcome {
+                            MergeOutcome::Conflict => "Fr",
+                            MergeOutcome::Kept => "Fy",
+                            _ => "Fg",
+                        };
+                        table.add_row(Row::new(vec![
+                            Cell::new(&report.service),
+                            Cell::new(&template),
+                            Cell::new(file),
+                            Cell::new(&outcome.to_string()).style_spec(style),
+                        ]));
+                    }
+                }
+                Err(err) => {
+                    table.add_row(row![name, "", "", Fr->err]);
+                }
+            }
+        }
+        table.printstd();
+        outcome
+    }
+
+    pub async fn plugin(self) -> error::Result<()> {
+        let workdir = WorkDirectory::new(&self.workingdir);
+        let cache = ArtifactCache::new(workdir.clone().artifact_directory());
+
+        let Some((subcommand, matches)) = self.matches.subcommand() else {
+            return Ok(());
+        };
+
+        let entries = match subcommand {
+            "add" | "remove" => plugin::status(&change_plugin(
+                workdir,
+                &cache,
+                matches,
+                subcommand == "add",
+            )?),
+            "list" => {
+                let arg_services = matches
+                    .try_get_many::<String>("service")
+                    .unwrap_or_default()
+                    .unwrap_or_default()
+                    .map(|ele| ele.to_string())
+                    .collect::<Vec<String>>();
+                filt_services(arg_services, workdir.services())?
+                    .iter()
+                    .flat_map(plugin::status)
+                    .collect()
+            }
+            _ => return Ok(()),
+        };
+
+        if self.quite {
+            return Ok(());
+        }
+
+        if self.json {
+            println!("{}", self.envelope(entries, vec![]));
+            return Ok(());
+        }
+
+        let mut table = Table::new();
+        table.set_titles(row![
+            "Service Name",
+            "Plugin",
+            "Version",
+            "File",
+            "SHA256",
+            "Status"
+        ]);
+        for ele in entries {
+            let style = match ele.status {
+                PluginStatus::Installed => "Fg",
+                PluginStatus::Modified => "Fr",
+                _ => "Fy",
+            };
+            table.add_row(Row::new(vec![
+                Cell::new(&ele.service),
+                Cell::new(&ele.name),
+                Cell::new(&ele.version.unwrap_or_default()),
+                Cell::new(&ele.file),
+                Cell::new(
+                    &ele.sha256
+                        .map(|ele| ele.get(..12).unwrap_or(&ele).to_string())
+                        .unwrap_or_default(),
+                ),
+                Cell::new(&ele.status.to_string()).style_spec(style),
+            ]));
+        }
+        table.printstd();
+        Ok(())
+    }
+
+    pub async fn props(self) -> error::Result<()> {
+        let workdir = WorkDirectory::new(&self.workingdir);
+
+        let Some((subcommand, matches)) = self.matches.subcommand() else {
+            return Ok(());
+        };
+        let key = matches.get_one::<String>("KEY");
+
+        let (json, table) = match subcommand {
+            "get" => {
+                let service = find_service(workdir, required::<String>(matches, "service")?)?;
+                let properties = Properties::load(&service.path.join(properties::PROPERTIES_FILE))
+                    .classify(Error::Io)?;
+                let entries = properties
+                    .entries()
+                    .into_iter()
+                    .filter(|(name, _)| key.is_none_or(|key| key == name))
+                    .map(|(name, value)| PropertyEntry {
+                        service: service.name.clone(),
+                        key: name.to_string(),
+                        value: value.to_string(),
+                        managed: service.manifest.properties.contains_key(name),
+                    })
+                    .collect::<Vec<_>>();
+                if let Some(key) = key.filter(|_| entries.is_empty()) {
+                    return Err(Error::NotFound(format!(
+                        "{} has no property {}",
+                        service.name, key
+                    )));
+                }
+
+                let mut table = Table::new();
+                table.set_titles(row!["Service Name", "Key", "Value", "Managed"]);
+                table.extend(
+                    entries
+                        .iter()
+                        .map(|ele| row![ele.service, ele.key, ele.value, ele.managed]),
+                );
+                (self.envelope(entries, vec![]), table)
+            }
+            "set" => {
+                let value = required::<String>(matches, "VALUE")?;
+                let key = required::<String>(matches, "KEY")?;
+                let service = find_service(workdir, required::<String>(matches, "service")?)?;
+                if service.manifest.properties.contains_key(key) {
+                    return Err(Error::Usage(format!(
+                        "{} comes from [properties] in the manifest of {}, change it there",
+                        key, service.name
+                    )));
+                }
+                properties::validate(key, value).classify(Error::Usage)?;
+
+                let path = service.path.join(properties::PROPERTIES_FILE);
+                let mut properties = Properties::load(&path).classify(Error::Io)?;
+                let previous = properties.get(key).map(str::to_string);
+                properties.set(key, value);
+                properties.save(&path).classify(Error::Io)?;
+
+                let mut table = Table::new();
+                table.set_titles(row!["Service Name", "Key", "Previous", "Value"]);
+                table.add_row(row![
+                    service.name,
+                    key,
+                    previous.clone().unwrap_or_default(),
+                    value
+                ]);
+                let change = PropertyChange {
+                    service: service.name,
+                    key: key.to_string(),
+                    previous,
+                    value: value.to_string(),
+                };
+                (self.envelope(vec![change], vec![]), table)
+            }
+            "diff" => {
+                let arg_services = matches
+                    .try_get_many::<String>("service")
+                    .unwrap_or_default()
+                    .unwrap_or_default()
+                    .map(|ele| ele.to_string())
+                    .collect::<Vec<String>>();
+                let services = filt_services(arg_services, workdir.clone().services())?;
+
+                let (columns, compared) = properties_to_compare(workdir, services)?;
+                let mut diff = properties::diff(&compared);
+                diff.retain(|name, _| key.is_none_or(|key| key == name));
+
+                let mut table = Table::new();
+                table.set_titles(Row::new(
+                    std::iter::once("Key")
+                        .chain(columns.iter().map(String::as_str))
+                        .map(Cell::new)
+                        .collect(),
+                ));
+                for (name, values) in &diff {
+                    table.add_row(Row::new(
+                        std::iter::once(name.as_str())
+                            .chain(values.iter().map(|ele| ele.as_deref().unwrap_or("")))
+                            .map(Cell::new)
+                            .collect(),
+                    ));
+                }
+
+                let diff = diff
+                    .into_iter()
+                    .map(|(key, values)| PropertyDiff {
+                        key,
+                        values: columns.iter().cloned().zip(values).collect(),
+                    })
+                    .collect::<Vec<_>>();
+                (self.envelope(diff, vec![]), table)
+            }
+            _ => return Ok(()),
+        };
+
+        if self.quite {
+            return Ok(());
+        }
+
+        if self.json {
+            println!("{}", json);
+        } else {
+            print!("{}", table);
+        }
+        Ok(())
+    }
+
+    pub async fn schedule(self) -> error::Result<()> {
+        let workingdir = WorkDirectory::new(&self.workingdir);
+
+        let mut services = workingdir.clone().services();
+        let arg_services = self.arg_services();
+        if !arg_services.is_empty() {
+            services = filt_services(arg_services, services)?;
+        }
+
+        match self.matches.subcommand() {
+            Some(("list", _)) => {
+                let jobs = schedule::jobs(services)
+                    .into_iter()
+                    .map(|(service, job)| {
+                        let next_run = schedule::next_run(&job.cron);
+                        (service.name, job.cron, job.action.to_string(), next_run)
+                    })
+                    .collect::<Vec<_>>();
+
+                if self.quite {
+                    return Ok(());
+                }
+
+                let result = if self.json {
+                    let jobs = jobs
+                        .into_iter()
+                        .map(|(service, cron, action, next_run)| JobEntry {
+                            service,
+                            cron,
+                            action,
+                            error: next_run.as_ref().err().map(|err| err.to_string()),
+                            next_run: next_run.ok().flatten(),
+                        })
+                        .collect();
+                    self.envelope(jobs, vec![])
+                } else {
+                    let mut table = Table::new();
+                    table.set_titles(row!["Service Name", "Cron", "Action", "Next Run"]);
+                    table.extend(jobs.into_iter().map(|(service, cron, action, next_run)| {
+                        let next_run = match next_run {
+                            Ok(Some(next_run)) => next_run.format("%Y-%m-%d %H:%M:%S").to_string(),
+                            Ok(None) => "Never".to_string(),
+                            Err(err) => format!("Invalid cron: {}", err),
+                        };
+                        row![service, cron, action, next_run]
+                    }));
+                    table.to_string()
+                };
+
+                println!("{}", result);
+                Ok(())
+            }
+            Some(("history", _)) => {
+                let names = services.into_iter().map(|ele| ele.name).collect::<Vec<_>>();
+
+                let mut records =
+                    ScheduleRecord::read_all(workingdir.schedule_history()).classify(Error::Io)?;
+                records.retain(|ele| names.contains(&ele.service));
+
+                if self.quite {
+                    return Ok(());
+                }
+
+                let result = if self.json {
+                    self.envelope(records, vec![])
+                } else {
+                    let mut table = Table::new();
+                    table.set_titles(row!["Time", "Service Name", "Action", "Success", "Result"]);
+                    table.extend(records.into_iter().map(|ele| {
+                        row![
+                            ele.time.format("%Y-%m-%d %H:%M:%S"),
+                            ele.service,
+                            ele.action,
+                            ele.success,
+                            ele.result
+                        ]
+                    }));
+                    table.to_string()
+                };
+
+                println!("{}", result);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn backup(self) -> error::Result<()> {
+        let workingdir = WorkDirectory::new(&self.workingdir);
+
+        let Some((subcommand, matches)) = self.matches.subcommand() else {
+            return Ok(());
+        };
+
+        let arg_services = matches
+            .try_get_many::<String>("service")
+            .unwrap_or_default()
+            .unwrap_or_default()
+            .map(|ele| ele.to_string())
+            .collect::<Vec<String>>();
+        let services = filt_services(arg_services, workingdir.clone().services())?;
+
+        let mut outcome = Ok(());
+        let result = match subcommand {
+            "create" => {
+                let mut vec = vec![];
+                for ele in services {
+                    let pause = matches.get_flag("pause-saves")
+                        || ele
+                            .manifest
+                            .backup
+                            .as_ref()
+                            .is_some_and(|ele| ele.pause_saves);
+                    let incremental = matches.get_flag("incremental")
+                        || ele
+                            .manifest
+                            .backup
+                            .as_ref()
+                            .is_some_and(|ele| ele.incremental);
+
+                    let snapshot = backup::backup_service(
+                        &ele,
+                        workingdir.clone().backup_directory(),
+                        workingdir.clone().data_directory(),
+                        pause,
+                        incremental,
+                    )
+                    .await;
+
+                    vec.push((ele.name, snapshot));
+                }
+                let failed = vec.iter().filter(|ele| ele.1.is_err()).count();
+                outcome = partial_failure(
+                    failed,
+                    vec.len(),
+                    "services could not be backed up",
+                    Error::Io,
+                );
+
+                if self.json {
+                    let (results, errors) = split_results(
+                        vec,
+                        |service, snapshot| BackupCreated {
+                            service,
+                            snapshot: snapshot.id,
+                            files: snapshot.files.len(),
+                            size: snapshot.size,
+                        },
+                        Error::Io,
+                    );
+                    self.envelope(results, errors)
+                } else {
+                    let mut table = Table::new();
+                    table.set_titles(row![
+                        "Service Name",
+                        "Snapshot",
+                        "Files",
+                        "Size",
+                        "Infomation"
+                    ]);
+                    table.extend(vec.into_iter().map(|(name, snapshot)| match snapshot {
+                        Ok(snapshot) => {
+                            row![name, snapshot.id, snapshot.files.len(), snapshot.size, ""]
+                        }
+                        Err(err) => row![name, "", "", "", err],
+                    }));
+                    table.to_string()
+                }
+            }
+            "list" => {
+                let mut snapshots = vec![];
+                for ele in services {
+                    snapshots.extend(
+                        BackupStore::new(workingdir.clone().backup_directory(), &ele.name)
+                            .list()
+                            .classify(Error::Io)?,
+                    );
+                }
+
+                if self.json {
+                    let snapshots = snapshots
+                        .into_iter()
+                        .map(|ele| SnapshotEntry {
+                            service: ele.service,
+                            snapshot: ele.id,
+                            created: ele.created,
+                            incremental: ele.incremental,
+                            label: ele.label,
+                            files: ele.files.len(),
+                            size: ele.size,
+                        })
+                        .collect();
+                    self.envelope(snapshots, vec![])
+                } else {
+                    let mut table = Table::new();
+                    table.set_titles(row![
+                        "Service Name",
+                        "Snapshot",
+                        "Created",
+                        "Kind",
+                        "Files",
+                        "Size"
+                    ]);
+                    table.extend(snapshots.into_iter().map(|ele| {
+                        row![
+                            ele.service,
+                            ele.id,
+                            ele.created.format("%Y-%m-%d %H:%M:%S"),
+                            match (ele.incremental, ele.label) {
+                                (true, Some(label)) => format!("Incremental ({})", label),
+                                (false, Some(label)) => format!("Full ({})", label),
+                                (true, None) => "Incremental".to_string(),
+                                (false, None) => "Full".to_string(),
+                            },
+                            ele.files.len(),
+                            ele.size
+                        ]
+                    }));
+                    table.to_string()
+                }
+            }
+            "restore" => {
+                let service_name = required::<String>(matches, "service")?;
+                let snapshot_id = required::<String>(matches, "snapshot")?;
+
+                let store = BackupStore::new(workingdir.clone().backup_directory(), service_name);
+
+                let report = match matches.get_one::<String>("to") {
+                    Some(target) => store
+                        .restore_to(snapshot_id, target)
+                        .map(|snapshot| RestoreReport {
+                            snapshot: snapshot.id,
+                            added: snapshot.files.into_keys().collect(),
+                            ..Default::default()
+                        })
+                        .classify(Error::Io),
+                    None => {
+                        safe_restore(
+                            &store,
+                            workingdir.clone(),
+                            service_name,
+                            snapshot_id,
+                            matches.get_flag("stop"),
+                        )
+                        .await
+                    }
+                }?;
+
+                if self.json {
+                    let restored = RestoreEntry {
+                        service: service_name.to_string(),
+                        report,
+                    };
+                    self.envelope(vec![restored], vec![])
+                } else {
+                    let mut table = Table::new();
+                    table.set_titles(row![
+                        "Service Name",
+                        "Snapshot",
+                        "Pre-restore Snapshot",
+                        "Added",
+                        "Removed",
+                        "Modified"
+                    ]);
+                    table.add_row(row![
+                        service_name,
+                        report.snapshot,
+                        report.pre_restore.unwrap_or_default(),
+                        report.added.len(),
+                        report.removed.len(),
+                        report.modified.len()
+                    ]);
+
+                    let mut changes = Table::new();
+                    changes.set_titles(row!["Change", "File"]);
+                    for (change, files) in [
+                        ("Added", report.added),
+                        ("Removed", report.removed),
+                        ("Modified", report.modified),
+                    ] {
+                        changes.extend(files.into_iter().map(|file| row![change, file]));
+                    }
+
+                    format!("{}{}", table, changes)
+                }
+            }
+            "verify" => {
+                let snapshot_id = matches.get_one::<String>("snapshot");
+
+                let mut vec = vec![];
+                for ele in services {
+                    let store = BackupStore::new(workingdir.clone().backup_directory(), &ele.name);
+
+                    let mut snapshots = store.list().classify(Error::Io)?;
+                    if let Some(snapshot_id) = snapshot_id {
+                        snapshots.retain(|ele| ele.id == *snapshot_id);
+                    }
+
+                    for snapshot in snapshots {
+                        let problems = store
+                            .verify(&snapshot)
+                            .unwrap_or_else(|err| vec![err.to_string()]);
+                        vec.push(VerifyEntry {
+                            service: ele.name.clone(),
+                            snapshot: snapshot.id,
+                            ok: problems.is_empty(),
+                            problems,
+                        });
+                    }
+                }
+                let failed = vec.iter().filter(|ele| !ele.ok).count();
+                outcome =
+                    partial_failure(failed, vec.len(), "snapshots are damaged", Error::Invalid);
+
+                if self.json {
+                    self.envelope(vec, vec![])
+                } else {
+                    let mut table = Table::new();
+                    table.set_titles(row!["Service Name", "Snapshot", "Is Ok", "Problems"]);
+                    table.extend(vec.into_iter().map(|ele| {
+                        row![ele.service, ele.snapshot, ele.ok, ele.problems.join("\n")]
+                    }));
+                    table.to_string()
+                }
+            }
+            "prune" => {
+                let mut vec = vec![];
+                for ele in services {
+                    let mut retention = ele.manifest.backup.clone().unwrap_or_default();
+                    if let Some(keep_last) = matches.get_one::<usize>("keep-last") {
+                        retention.keep_last = Some(*keep_last);
+                    }
+                    if let Some(keep_daily) = matches.get_one::<usize>("keep-daily") {
+                        retention.keep_daily = Some(*keep_daily);
+                    }
+                    if let Some(keep_weekly) = matches.get_one::<usize>("keep-weekly") {
+                        retention.keep_weekly = Some(*keep_weekly);
+                    }
+
+                    let removed =
+                        BackupStore::new(workingdir.clone().backup_directory(), &ele.name)
+                            .prune(&retention);
+
+                    let removed = removed
+                        .map(|removed| removed.into_iter().map(|ele| ele.id).collect::<Vec<_>>());
+                    vec.push((ele.name, removed));
+                }
+                let failed = vec.iter().filter(|ele| ele.1.is_err()).count();
+                outcome =
+                    partial_failure(failed, vec.len(), "services could not be pruned", Error::Io);
+
+                if self.json {
+                    let (results, errors) = split_results(
+                        vec,
+                        |service, pruned| PruneEntry { service, pruned },
+                        Error::Io,
+                    );
+                    self.envelope(results, errors)
+                } else {
+                    let mut table = Table::new();
+                    table.set_titles(row!["Service Name", "Pruned Snapshots", "Infomation"]);
+                    table.extend(vec.into_iter().map(|(name, removed)| match removed {
+                        Ok(removed) => row![name, removed.join("\n"), ""],
+                        Err(err) => row![name, "", err],
+                    }));
+                    table.to_string()
+                }
+            }
+            _ => return Ok(()),
+        };
+
+        if !self.quite {
+            println!("{}", result)
+        }
+        outcome
+    }
+
+    pub async fn java(self) -> error::Result<()> {
+        let workdir = WorkDirectory::new(&self.workingdir);
+
+        let Some((subcommand, matches)) = self.matches.subcommand() else {
+            return Ok(());
+        };
+
+        match subcommand {
+            "list" => {
+                let config = Config::load(&workdir.config_file()).classify(Error::Invalid)?;
+                let installations = java::installations(&config, Path::new(java::JVM_DIRECTORY));
+
+                if self.quite {
+                    return Ok(());
+                }
+
+                if self.json {
+                    println!("{}", self.envelope(installations, vec![]));
+                    return Ok(());
+                }
+
+                let mut table = Table::new();
+                table.set_titles(row!["Version", "Path", "Source"]);
+                for ele in installations {
+                    let source = if ele.discovered {
+                        "discovered"
+                    } else {
+                        "config.toml"
+                    };
+                    table.add_row(row![ele.version, ele.path.display(), source]);
+                }
+                print!("{}", table);
+            }
+            "command" => {
+                let arg_services = matches
+                    .try_get_many::<String>("service")
+                    .unwrap_or_default()
+                    .unwrap_or_default()
+                    .map(|ele| ele.to_string())
+                    .collect::<Vec<String>>();
+                let commands = filt_services(arg_services, workdir.services())?
+                    .into_iter()
+                    .filter_map(|ele| Some((ele.name, ele.java_command?)))
+                    .collect::<Vec<_>>();
+
+                if self.quite {
+                    return Ok(());
+                }
+
+                if self.json {
+                    let (results, errors) = split_results(
+                        commands.into_iter().map(|(service, command)| {
+                            (service, command.map_err(|err| anyhow!(err)))
+                        }),
+                        |service, command| JavaCommandEntry { service, command },
+                        Error::Invalid,
+                    );
+                    println!("{}", self.envelope(results, errors));
+                    return Ok(());
+                }
+
+                let mut table = Table::new();
+                table.set_titles(row!["Name", "Command"]);
+                for (name, command) in commands {
+                    match command {
+                        Ok(command) => table.add_row(row![name, command]),
+                        Err(err) => table.add_row(row![name, Fr->err]),
+                    };
+                }
+                table.printstd();
+            }
+            _ => {}
         }
+        Ok(())
+    }
+
+    pub async fn doctor(self) -> error::Result<()> {
+        let workdir = WorkDirectory::new(&self.workingdir);
+        let root = workdir.path.clone();
+        let services = workdir.clone().services();
+        let templates = workdir.clone().templates();
+
+        let mut findings = doctor::check_layout(&root);
+        findings.extend(doctor::check_manifests(&root, &workdir.errors()));
+        findings.extend(doctor::check_pid_files(&services));
+        findings.extend(doctor::check_orphaned_data(&root, &services));
+        findings.extend(doctor::check_tools());
+        findings.extend(doctor::check_placeholders(&root, &templates));
+        findings.extend(doctor::port_findings(port_issues(services).await));
+        findings.sort_by_key(|ele| ele.severity);
+
+        if self.quite {
+            return Ok(());
+        }
+
+        if self.json {
+            println!("{}", self.envelope(findings, vec![]));
+            return Ok(());
+        }
+
+        if findings.is_empty() {
+            println!("No issues found.");
+            return Ok(());
+        }
+
+        let mut table = Table::new();
+        table.set_titles(row!["Severity", "Check", "Subject", "Message", "Fix"]);
+        for ele in findings {
+            let style = match ele.severity {
+                Severity::Error => "Fr",
+                Severity::Warning => "Fy",
+                Severity::Info => "",
+            };
+            table.add_row(Row::new(vec![
+                Cell::new(&ele.severity.to_string()).style_spec(style),
+                Cell::new(&ele.check),
+                Cell::new(&ele.subject),
+                Cell::new(&ele.message),
+                Cell::new(&ele.fix),
+            ]));
+        }
+        table.printstd();
+        Ok(())
+    }
+
+    pub async fn daemon(self) -> error::Result<()> {
+        schedule::daemon(self.workingdir, self.json).await;
+        Ok(())
+    }
+
+    pub async fn init(self) -> error::Result<()> {
+        let workdir = WorkDirectory::new(&self.workingdir);
+        let sources_file = workdir.clone().sources_file();
+
+        if !sources_file.exists() {
+            SourcesConfig::official()
+                .save(&sources_file)
+                .classify(Error::Io)?;
+        }
+
+        let config = SourcesConfig::load(&sources_file).classify(Error::Invalid)?;
+        let template_dir = workdir.template_directory();
+        fs::create_dir_all(&template_dir).classify(Error::Io)?;
+
+        let updated = config
+            .sources
+            .iter()
+            .map(|source| (source.name.clone(), source.update(&template_dir)))
+            .collect::<Vec<_>>();
+        let failed = updated.iter().filter(|ele| ele.1.is_err()).count();
+        let outcome = partial_failure(
+            failed,
+            updated.len(),
+            "sources could not be fetched",
+            Error::Io,
+        );
+
+        if self.quite {
+            return outcome;
+        }
+
+        if self.json {
+            let (results, errors) = split_results(
+                updated,
+                |source, state| SourceUpdated {
+                    source,
+                    commit: state.commit,
+                },
+                Error::Io,
+            );
+            println!("{}", self.envelope(results, errors));
+        } else {
+            for (source, state) in updated {
+                if let Err(err) = state {
+                    println!("Initialize templates from {} failed: {}", source, err)
+                }
+            }
+        }
+        outcome
+    }
+
+    fn arg_services(&self) -> Vec<String> {
+        self.matches
+            .try_get_many::<String>("service")
+            .unwrap_or_default()
+            .unwrap_or_default()
+            .map(|ele| ele.to_string())
+            .collect()
+    }
+
+    /// Returns the `--warn` countdown and whether `--now` asked to skip it.
+    fn warn_args(&self) -> error::Result<(Option<Duration>, bool)> {
+        let warn = self
+            .matches
+            .get_one::<String>("warn")
+            .map(|ele| parse_duration("warn", ele))
+            .transpose()?;
+
+        Ok((warn, self.matches.get_flag("now")))
+    }
+
+    /// The `--json` output of the command.
+    fn envelope<T: Serialize>(&self, results: Vec<T>, errors: Vec<ErrorEntry>) -> String {
+        serde_json::to_string_pretty(&Envelope::new(&self.command, results, errors))
+            .expect("Cannot serialized into json")
+    }
+
+    fn print_lint(self, issues: Vec<LintIssue>) {
+        if self.quite {
+            return;
+        }
+
+        let result = if self.json {
+            self.envelope(issues, vec![])
+        } else if issues.is_empty() {
+            "No issues found.".to_string()
+        } else {
+            let mut table = Table::new();
+            table.set_titles(row!["Name", "File", "Position", "Kind", "Message"]);
+            for issue in issues {
+                table.add_row(row![
+                    issue.name,
+                    issue.file,
+                    issue.position(),
+                    issue.kind,
+                    issue.message
+                ]);
+            }
+            table.to_string()
+        };
+
+        println!("{}", result)
     }
 
     fn print(self, v: Vec<ServiceInformation>) {
         if !self.quite {
             let result = if self.json {
-                serde_json::to_string_pretty(&v).expect("Cannot serialized into json")
+                self.envelope(v.into_iter().map(ServiceEntry::from).collect(), vec![])
             } else {
                 into_status_table(&v).to_string()
             };
@@ -509,6 +2187,20 @@ impl CMD {
     }
 }
 
+/// Ask a yes/no question on the terminal, yes being the default.
+fn confirm(question: &str) -> bool {
+    print!("{} [Y/n] ", question);
+    if std::io::stdout().flush().is_err() {
+        return false;
+    }
+
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "" | "y" | "yes")
+}
+
 async fn update_all_status(v: Vec<ServiceInformation>) -> Vec<ServiceInformation> {
     let mut vec = vec![];
 
@@ -519,15 +2211,15 @@ async fn update_all_status(v: Vec<ServiceInformation>) -> Vec<ServiceInformation
     vec
 }
 
+/// Prepare every service, then start it.
 async fn start_all_services<T>(
     v: Vec<ServiceInformation>,
     data_dir: T,
+    cache: &ArtifactCache,
 ) -> Vec<(ServiceInformation, Result<StartResult, anyhow::Error>)>
 where
     T: Into<PathBuf> + Clone,
 {
-    let before = v.clone();
-
     let mut after = vec![];
 
     for ele in v {
@@ -538,31 +2230,498 @@ where
 
     let data_dir: PathBuf = data_dir.into();
 
-    for index in 0..before.len() {
+    for ele in after {
         let mut data_dir = data_dir.clone();
-        data_dir.push(after.get(index).unwrap().clone().name);
+        data_dir.push(&ele.name);
+
+        if let Err(err) = ele.prepare(cache) {
+            vec.push((ele, Err(err)));
+            continue;
+        }
 
-        vec.push((
-            after.get(index).unwrap().clone(),
-            after.get(index).unwrap().clone().start(data_dir).await,
-        ))
+        let result = ele.clone().start(data_dir).await;
+        vec.push((ele, result))
     }
 
     vec
 }
 
-fn into_status_table(v: &Vec<ServiceInformation>) -> Table {
+/// Give every `port` variable of a template a value: the one passed to `create` if no other
+/// service declares it, else its default if free, else the first free port of the configured
+/// range.
+fn assign_ports(
+    workdir: WorkDirectory,
+    template: &TemplateInfomation,
+    vars: &mut BTreeMap<String, String>,
+) -> anyhow::Result<BTreeMap<String, u16>> {
+    let variables = template
+        .template
+        .variables
+        .iter()
+        .filter(|(_, variable)| variable.kind == VariableType::Port)
+        .collect::<Vec<_>>();
+    if variables.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    let config = Config::load(&workdir.clone().config_file())?;
+    let registry = PortRegistry::new(&workdir.services());
+
+    let mut ports = BTreeMap::new();
+    for (key, variable) in variables {
+        let taken = ports.values().copied().collect::<Vec<_>>();
+        let parse = |value: &str| {
+            value
+                .parse::<u16>()
+                .map_err(|_| anyhow!("{} must be a port, not `{}`", key, value))
+        };
+
+        let port = match vars.get(key) {
+            Some(value) => {
+                let port = parse(value)?;
+                let services = registry.services(port);
+                if !services.is_empty() {
+                    return Err(anyhow!(
+                        "Port {} is already used by {}",
+                        port,
+                        services.join(", ")
+                    ));
+                }
+                if taken.contains(&port) {
+                    return Err(anyhow!("Port {} is given to more than one variable", port));
+                }
+                if port::is_bound(port) {
+                    return Err(anyhow!("Port {} is already bound on the host", port));
+                }
+                port
+            }
+            None => {
+                let default = variable.default.as_deref().map(parse).transpose()?;
+                match default.filter(|port| {
+                    registry.services(*port).is_empty()
+                        && !taken.contains(port)
+                        && !port::is_bound(*port)
+                }) {
+                    Some(port) => port,
+                    None => registry
+                        .next_free(config.ports.range(), &taken)
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "No free port left between {} and {} for {}",
+                                config.ports.start,
+                                config.ports.end,
+                                key
+                            )
+                        })?,
+                }
+            }
+        };
+
+        vars.insert(key.clone(), port.to_string());
+        ports.insert(key.clone(), port);
+    }
+
+    Ok(ports)
+}
+
+/// Port issues of `services`. Only services whose ports are bound have their status checked,
+/// since a running service is expected to hold its ports.
+async fn port_issues(services: Vec<ServiceInformation>) -> Vec<LintIssue> {
+    let registry = PortRegistry::new(&services);
+    let bound = registry
+        .uses()
+        .iter()
+        .filter(|ele| port::is_bound(ele.port))
+        .map(|ele| ele.service.clone())
+        .collect::<Vec<_>>();
+
+    let mut running = vec![];
+    for ele in services.into_iter().filter(|ele| bound.contains(&ele.name)) {
+        let ele = ele.update_status().await;
+        if ele.status == ServiceStatus::Start {
+            running.push(ele.name);
+        }
+    }
+
+    lint::lint_ports(&registry, &running)
+}
+
+fn find_service(workdir: WorkDirectory, name: &str) -> error::Result<ServiceInformation> {
+    workdir
+        .services()
+        .into_iter()
+        .find(|ele| ele.name == name)
+        .ok_or_else(|| Error::NotFound(format!("No such service found: {}", name)))
+}
+
+/// Value of an argument, a usage error when it is missing.
+fn required<'a, T>(matches: &'a ArgMatches, name: &str) -> error::Result<&'a T>
+where
+    T: Any + Clone + Send + Sync + 'static,
+{
+    matches
+        .get_one::<T>(name)
+        .ok_or_else(|| Error::Usage(format!("--{} is required", name)))
+}
+
+/// Values of an argument given several times, a usage error when it is not given at all.
+fn required_many(matches: &ArgMatches, name: &str) -> error::Result<Vec<String>> {
+    let values = matches
+        .try_get_many::<String>(name)
+        .unwrap_or_default()
+        .unwrap_or_default()
+        .cloned()
+        .collect::<Vec<_>>();
+    if values.is_empty() {
+        return Err(Error::Usage(format!("--{} is required", name)));
+    }
+    Ok(values)
+}
+
+fn parse_duration(name: &str, value: &str) -> error::Result<Duration> {
+    humantime::parse_duration(value)
+        .map_err(|err| Error::Usage(format!("Invalid --{} `{}`: {}", name, value, err)))
+}
+
+/// Split the results of acting on items into `--json` results and errors, the errors being of
+/// the given kind.
+fn split_results<T, R>(
+    results: impl IntoIterator<Item = (String, anyhow::Result<T>)>,
+    entry: impl Fn(String, T) -> R,
+    kind: fn(String) -> Error,
+) -> (Vec<R>, Vec<ErrorEntry>) {
+    let mut entries = vec![];
+    let mut errors = vec![];
+    for (target, result) in results {
+        match result {
+            Ok(result) => entries.push(entry(target, result)),
+            Err(err) => errors.push(ErrorEntry::new(Some(&target), &kind(format!("{:#}", err)))),
+        }
+    }
+    (entries, errors)
+}
+
+/// Table cell for the result of a service script, or the error that kept it from running.
+fn display_result<T: std::fmt::Display>(result: anyhow::Result<T>) -> String {
+    result.map_or_else(|err| err.to_string(), |value| value.to_string())
+}
+
+/// The outcome of a command acting on `total` items, `failed` of which did not succeed.
+fn partial_failure(
+    failed: usize,
+    total: usize,
+    what: &str,
+    kind: fn(String) -> Error,
+) -> error::Result<()> {
+    if failed == 0 {
+        return Ok(());
+    }
+    Err(Error::Partial(Box::new(kind(format!(
+        "{} of {} {}",
+        failed, total, what
+    )))))
+}
+
+/// The properties `props diff` compares and the name of each column. A single service is
+/// compared with the template it was created from.
+fn properties_to_compare(
+    workdir: WorkDirectory,
+    services: Vec<ServiceInformation>,
+) -> error::Result<(Vec<String>, Vec<Properties>)> {
+    let mut columns = vec![];
+    let mut compared = vec![];
+
+    if let [service] = services.as_slice() {
+        let origin = ServiceOrigin::load(&service.path).ok_or_else(|| {
+            Error::Invalid(format!("{} has no recorded template origin", service.name))
+        })?;
+        let templates = workdir.clone().templates();
+        let path = workdir
+            .template_directory()
+            .join(origin.path.clone().unwrap_or_default());
+        let template = templates
+            .iter()
+            .find(|ele| ele.path == path)
+            .ok_or_else(|| {
+                Error::NotFound(format!("Template {} no longer exists", origin.full_name()))
+            })?
+            .resolve(&templates)
+            .classify(Error::Invalid)?;
+
+        let files = template
+            .render_files(&origin.vars)
+            .classify(Error::Invalid)?;
+        let mut properties = files
+            .get(properties::PROPERTIES_FILE)
+            .map(|ele| Properties::parse(&String::from_utf8_lossy(ele)))
+            .unwrap_or_default();
+        for (key, value) in &service.manifest.properties {
+            properties.set(key, &value.to_string());
+        }
+
+        columns.push(origin.full_name());
+        compared.push(properties);
+    }
+
+    for ele in services {
+        compared.push(
+            Properties::load(&ele.path.join(properties::PROPERTIES_FILE)).classify(Error::Io)?,
+        );
+        columns.push(ele.name);
+    }
+
+    Ok((columns, compared))
+}
+
+/// Declare or remove the plugin named in `matches` and install the plugins of the service.
+fn change_plugin(
+    workdir: WorkDirectory,
+    cache: &ArtifactCache,
+    matches: &ArgMatches,
+    add: bool,
+) -> error::Result<ServiceInformation> {
+    let service_name = required::<String>(matches, "service")?;
+    let name = required::<String>(matches, "NAME")?;
+
+    let service = find_service(workdir, service_name)?;
+    let manifest_path = service.path.join(MANIFEST_FILE);
+
+    if add {
+        plugin::validate_name(name).classify(Error::Usage)?;
+        let path = matches
+            .get_one::<String>("path")
+            .map(|path| {
+                fs::canonicalize(path)
+                    .map_err(|err| Error::NotFound(format!("Cannot find {}: {}", path, err)))
+            })
+            .transpose()?;
+        let sha256 = match (matches.get_one::<String>("sha256"), &path) {
+            (Some(sha256), _) => sha256.clone(),
+            (None, Some(path)) => backup::hash_file(path).classify(Error::Io)?,
+            (None, None) => {
+                return Err(Error::Usage("--sha256 is required with --url".to_string()))
+            }
+        };
+        let declared = ManifestContentPlugin {
+            version: matches.get_one::<String>("version").cloned(),
+            url: matches.get_one::<String>("url").cloned(),
+            path: path.map(|ele| ele.to_string_lossy().to_string()),
+            sha256,
+        };
+
+        // Fetched before touching the manifest, so that a bad URL or hash changes nothing.
+        let artifact = ManifestContentArtifact {
+            url: declared.url.clone(),
+            path: declared.path.clone(),
+            sha256: declared.sha256.clone(),
+            destination: plugin::file_name(name, &declared),
+        };
+        cache.fetch(&artifact, &service.path).classify(Error::Io)?;
+
+        plugin::set_plugin(&manifest_path, name, Some(&declared)).classify(Error::Io)?;
+    } else {
+        let declared = service
+            .manifest
+            .plugins
+            .as_ref()
+            .is_some_and(|ele| ele.jars.contains_key(name));
+        if !declared {
+            return Err(Error::NotFound(format!(
+                "Plugin {} is not declared by {}",
+                name, service.name
+            )));
+        }
+        plugin::set_plugin(&manifest_path, name, None).classify(Error::Io)?;
+    }
+
+    let service = ServiceInformation::new(&manifest_path).classify(Error::Invalid)?;
+    plugin::install(&service, cache).classify(Error::Io)?;
+    Ok(service)
+}
+
+/// Restore a snapshot over the data of a service, refusing while it runs unless `stop` allows
+/// stopping it first.
+async fn safe_restore(
+    store: &BackupStore,
+    workingdir: WorkDirectory,
+    service_name: &str,
+    snapshot_id: &str,
+    stop: bool,
+) -> error::Result<RestoreReport> {
+    let service = find_service(workingdir.clone(), service_name)?
+        .update_status()
+        .await;
+
+    if service.status == ServiceStatus::Start {
+        if !stop {
+            return Err(Error::Usage(format!(
+                "Service {} is running, stop it first or pass --stop",
+                service_name
+            )));
+        }
+
+        match service.clone().stop().await.classify(Error::Service)? {
+            StopResult::Success => {}
+            result => return Err(Error::Service(format!("Cannot stop service: {}", result))),
+        }
+
+        if !service
+            .clone()
+            .wait_until(
+                |status| *status != ServiceStatus::Start,
+                RESTORE_STOP_TIMEOUT,
+            )
+            .await
+        {
+            return Err(Error::Service(format!(
+                "Service {} did not stop in time",
+                service_name
+            )));
+        }
+    }
+
+    let incremental = service
+        .manifest
+        .backup
+        .as_ref()
+        .is_some_and(|ele| ele.incremental);
+
+    let mut data_dir = workingdir.data_directory();
+    data_dir.push(service_name);
+
+    store
+        .restore(snapshot_id, data_dir, incremental)
+        .classify(Error::Io)
+}
+
+/// Stop every running service in reverse dependency order, then start them again in dependency
+/// order. Services that are not running are left as they are.
+async fn restart_all_services<T>(
+    v: Vec<ServiceInformation>,
+    data_dir: T,
+    cache: &ArtifactCache,
+    timeout: Duration,
+) -> Vec<(ServiceInformation, Result<RestartResult, anyhow::Error>)>
+where
+    T: Into<PathBuf> + Clone,
+{
+    let mut stopped = vec![];
+
+    for ele in v.iter().rev() {
+        if ele.status != ServiceStatus::Start {
+            stopped.push(Some(Ok(RestartResult::NotRunning)));
+            continue;
+        }
+
+        let result = match ele.clone().stop().await {
+            Ok(StopResult::Success) => {
+                if ele
+                    .clone()
+                    .wait_until(|status| *status != ServiceStatus::Start, timeout)
+                    .await
+                {
+                    None
+                } else {
+                    Some(Ok(RestartResult::StopTimeout))
+                }
+            }
+            Ok(result) => Some(Ok(RestartResult::StopFailed(result))),
+            Err(err) => Some(Err(err)),
+        };
+        stopped.push(result);
+    }
+    stopped.reverse();
+
+    let data_dir: PathBuf = data_dir.into();
+
+    let mut vec = vec![];
+    for (ele, stopped) in v.into_iter().zip(stopped) {
+        if let Some(result) = stopped {
+            vec.push((ele, result));
+            continue;
+        }
+
+        if let Err(err) = ele.prepare(cache) {
+            vec.push((ele, Err(err)));
+            continue;
+        }
+
+        let mut data_dir = data_dir.clone();
+        data_dir.push(&ele.name);
+
+        let result = ele
+            .clone()
+            .start(data_dir)
+            .await
+            .map(|result| match result {
+                StartResult::Success => RestartResult::Success,
+                result => RestartResult::StartFailed(result),
+            });
+        vec.push((ele.update_status().await, result));
+    }
+
+    vec
+}
+
+async fn restart_until_healthy(
+    service: ServiceInformation,
+    data_dir: PathBuf,
+    cache: &ArtifactCache,
+    timeout: Duration,
+    wait_healthy: Duration,
+) -> anyhow::Result<RestartResult> {
+    if service.status != ServiceStatus::Start {
+        return Ok(RestartResult::NotRunning);
+    }
+
+    match service.clone().restart(data_dir, cache, timeout).await? {
+        RestartResult::Success => {
+            if service
+                .wait_until(|status| *status == ServiceStatus::Start, wait_healthy)
+                .await
+            {
+                Ok(RestartResult::Success)
+            } else {
+                Ok(RestartResult::Unhealthy)
+            }
+        }
+        result => Ok(result),
+    }
+}
+
+fn into_status_table(v: &[ServiceInformation]) -> Table {
     let mut table = Table::new();
-    table.set_titles(row!["Service Name", "Status"]);
-    table.extend(v.iter().map(|ele| row![ele.name, ele.status.to_string()]));
+    if v.iter().any(|ele| ele.error.is_some()) {
+        table.set_titles(row!["Service Name", "Status", "Error"]);
+    } else {
+        table.set_titles(row!["Service Name", "Status"]);
+    }
+    table.extend(v.iter().map(|ele| match &ele.error {
+        Some(error) => row![ele.name, Fr->ele.status, error],
+        None => row![ele.name, ele.status.to_string()],
+    }));
     table
 }
 
+/// The services named on the command line. Names that match no service are an error rather
+/// than being left out.
 fn filt_services(
     arg_services: Vec<String>,
     mut services: Vec<ServiceInformation>,
-) -> Vec<ServiceInformation> {
+) -> error::Result<Vec<ServiceInformation>> {
+    let missing = arg_services
+        .iter()
+        .filter(|name| !services.iter().any(|ele| &ele.name == *name))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(Error::NotFound(format!(
+            "There is no service with the name {}",
+            missing.join(", ")
+        )));
+    }
+
     services.retain(|ele| arg_services.contains(&ele.name));
 
-    services
+    Ok(services)
 }
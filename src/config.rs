@@ -0,0 +1,48 @@
+use std::{fs, ops::RangeInclusive, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::java::JavaInstallation;
+
+/// Settings of the working directory, read from `config.toml`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub ports: PortsConfig,
+    /// Java installations besides the ones discovered under `/usr/lib/jvm`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub java: Vec<JavaInstallation>,
+}
+
+/// Ports `create` picks from for `port` variables.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PortsConfig {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl Default for PortsConfig {
+    fn default() -> Self {
+        Self {
+            start: 25565,
+            end: 25664,
+        }
+    }
+}
+
+impl PortsConfig {
+    pub fn range(&self) -> RangeInclusive<u16> {
+        self.start..=self.end
+    }
+}
+
+impl Config {
+    /// Read the configuration, the defaults when the file does not exist.
+    pub fn load(path: &Path) -> anyhow::Result<Config> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(toml::from_str(&content)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
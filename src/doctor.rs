@@ -0,0 +1,330 @@
+use std::{
+    env,
+    fmt::{self, Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::{
+    lint::{self, LintIssue, LintKind},
+    service::ServiceInformation,
+    template::{TemplateInfomation, MANIFEST_FILE},
+    work::{self, LoadError},
+};
+
+/// Directories of the working directory, and whether `doctor` treats a missing one as an error.
+const LAYOUT: [(&str, bool); 4] = [
+    ("templates", true),
+    ("services", true),
+    ("data", false),
+    ("backups", false),
+];
+
+/// Programs the services and templates rely on, and the severity when one is missing.
+const TOOLS: [(&str, Severity, &str); 3] = [
+    ("sh", Severity::Error, "every script runs with `sh -c`"),
+    (
+        "git",
+        Severity::Warning,
+        "git template sources and upgrades need it",
+    ),
+    ("java", Severity::Warning, "Minecraft servers need it"),
+];
+
+#[derive(Debug, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone)]
+pub struct Finding {
+    pub severity: Severity,
+    pub check: String,
+    /// Path, service, template or program the finding is about.
+    pub subject: String,
+    pub message: String,
+    pub fix: String,
+}
+
+impl Finding {
+    fn new(
+        severity: Severity,
+        check: &str,
+        subject: impl Display,
+        message: String,
+        fix: String,
+    ) -> Self {
+        Finding {
+            severity,
+            check: check.to_string(),
+            subject: subject.to_string(),
+            message,
+            fix,
+        }
+    }
+}
+
+/// Directories missing from the working directory at `root`, or that cannot be written.
+pub fn check_layout(root: &Path) -> Vec<Finding> {
+    let mut findings = vec![];
+
+    for (dir, required) in LAYOUT {
+        let path = root.join(dir);
+        if !path.is_dir() {
+            let severity = if required {
+                Severity::Error
+            } else {
+                Severity::Info
+            };
+            findings.push(Finding::new(
+                severity,
+                "layout",
+                path.display(),
+                format!("Directory {} does not exist", dir),
+                format!("mkdir -p {}", path.display()),
+            ));
+            continue;
+        }
+
+        let probe = path.join(".smc-doctor");
+        match fs::write(&probe, b"") {
+            Ok(()) => {
+                let _ = fs::remove_file(probe);
+            }
+            Err(err) => findings.push(Finding::new(
+                Severity::Error,
+                "permissions",
+                path.display(),
+                format!("Cannot write into {}: {}", dir, err),
+                format!("chown -R $(id -u) {}", path.display()),
+            )),
+        }
+    }
+
+    findings
+}
+
+/// Service and template manifests that cannot be loaded.
+pub fn check_manifests(root: &Path, errors: &[LoadError]) -> Vec<Finding> {
+    let mut findings = vec![];
+
+    for ele in errors {
+        if !ele.path.ends_with(MANIFEST_FILE) {
+            findings.push(Finding::new(
+                Severity::Error,
+                "config",
+                ele.path.display(),
+                format!("Configuration cannot be parsed: {}", ele.message),
+                "Fix the configuration, Java installations are looked up without it".to_string(),
+            ));
+            continue;
+        }
+        findings.push(Finding::new(
+            Severity::Error,
+            "manifest",
+            ele.path.display(),
+            format!("Service manifest cannot be parsed: {}", ele.message),
+            "Fix the manifest, `smcmanager service lint` shows where".to_string(),
+        ));
+    }
+    for path in work::manifest_paths(root.join("templates")) {
+        if let Err(err) = TemplateInfomation::new(&path) {
+            findings.push(Finding::new(
+                Severity::Error,
+                "manifest",
+                path.display(),
+                format!("Template manifest cannot be parsed: {}", err),
+                "Fix the manifest, `smcmanager template lint` shows where".to_string(),
+            ));
+        }
+    }
+
+    findings
+}
+
+/// PID files left behind by processes that are gone.
+pub fn check_pid_files(services: &[ServiceInformation]) -> Vec<Finding> {
+    services
+        .iter()
+        .filter_map(|service| {
+            let path = service.path.join(service.manifest.pid_file.as_ref()?);
+            let content = fs::read_to_string(&path).ok()?;
+
+            let alive = content
+                .trim()
+                .parse::<u32>()
+                .is_ok_and(|pid| Path::new(&format!("/proc/{}", pid)).exists());
+            (!alive).then(|| {
+                Finding::new(
+                    Severity::Warning,
+                    "pid file",
+                    &service.name,
+                    format!(
+                        "{} refers to process `{}`, which is not running",
+                        path.display(),
+                        content.trim()
+                    ),
+                    format!("rm {}", path.display()),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Data directories whose service does not exist anymore. A service directory whose manifest
+/// cannot be loaded still owns its data.
+pub fn check_orphaned_data(root: &Path, services: &[ServiceInformation]) -> Vec<Finding> {
+    let mut orphaned = fs::read_dir(root.join("data"))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|ele| ele.file_type().is_ok_and(|ele| ele.is_dir()))
+        .map(|ele| ele.path())
+        .filter(|path| {
+            let name = path
+                .file_name()
+                .map(|ele| ele.to_string_lossy().to_string());
+            !services.iter().any(|ele| Some(&ele.name) == name.as_ref())
+                && !name.is_some_and(|name| root.join("services").join(name).is_dir())
+        })
+        .collect::<Vec<_>>();
+    orphaned.sort();
+
+    orphaned
+        .into_iter()
+        .map(|path| {
+            Finding::new(
+                Severity::Warning,
+                "orphaned data",
+                path.display(),
+                "Data directory without a service".to_string(),
+                format!(
+                    "Back it up and remove it, or create a service with the same name: rm -r {}",
+                    path.display()
+                ),
+            )
+        })
+        .collect()
+}
+
+/// Programs that cannot be found on `PATH`.
+pub fn check_tools() -> Vec<Finding> {
+    TOOLS
+        .iter()
+        .filter(|(tool, _, _)| which(tool).is_none())
+        .map(|(tool, severity, reason)| {
+            Finding::new(
+                *severity,
+                "tool",
+                tool,
+                format!("{} is not on PATH, {}", tool, reason),
+                format!("Install {} or add it to PATH", tool),
+            )
+        })
+        .collect()
+}
+
+/// Templates with unbalanced placeholders.
+pub fn check_placeholders(root: &Path, templates: &[TemplateInfomation]) -> Vec<Finding> {
+    work::manifest_paths(root.join("templates"))
+        .into_iter()
+        .flat_map(|path| lint::lint_template(&path, templates))
+        .filter(|ele| ele.kind == LintKind::Placeholder)
+        .map(|ele| {
+            Finding::new(
+                Severity::Error,
+                "placeholder",
+                &ele.name,
+                format!("{} {}: {}", ele.file, ele.position(), ele.message),
+                "Balance the braces, `smcmanager template lint` shows where".to_string(),
+            )
+        })
+        .collect()
+}
+
+/// Turn the port issues of `lint` into findings.
+pub fn port_findings(issues: Vec<LintIssue>) -> Vec<Finding> {
+    issues
+        .into_iter()
+        .map(|ele| {
+            Finding::new(
+                Severity::Error,
+                "port",
+                &ele.name,
+                ele.message,
+                format!(
+                    "Change the port in {} of {}, or stop what holds it",
+                    ele.file, ele.name
+                ),
+            )
+        })
+        .collect()
+}
+
+fn which(program: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+#[test]
+fn doctor_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    fs::create_dir_all(root.join("services/lobby")).unwrap();
+    fs::create_dir_all(root.join("data/lobby")).unwrap();
+    fs::create_dir_all(root.join("data/old")).unwrap();
+    fs::write(root.join("services/lobby/manifest.toml"), "[scripts\n").unwrap();
+
+    let layout = check_layout(root);
+    assert_eq!(layout.len(), 2);
+    assert_eq!(layout[0].severity, Severity::Error);
+    assert_eq!(layout[0].check, "layout");
+
+    let errors = crate::work::WorkDirectory::new(root).errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(check_manifests(root, &errors).len(), 1);
+
+    let lobby = ServiceInformation {
+        name: "lobby".to_string(),
+        path: root.join("services/lobby"),
+        ..Default::default()
+    };
+    let orphaned = check_orphaned_data(root, &[lobby]);
+    assert_eq!(orphaned.len(), 1);
+    assert!(orphaned[0].subject.ends_with("old"));
+    assert_eq!(check_orphaned_data(root, &[]).len(), 1);
+}
+
+#[cfg(test)]
+#[test]
+fn config_error_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    fs::create_dir_all(root.join("services/lobby")).unwrap();
+    fs::write(
+        root.join("services/lobby/manifest.toml"),
+        "[scripts]\nhealth_check = \"true\"\nstart = \"true\"\n[java]\n",
+    )
+    .unwrap();
+    fs::write(root.join("config.toml"), "[java\n").unwrap();
+
+    let workdir = crate::work::WorkDirectory::new(root);
+    assert!(workdir.clone().invalid_services().is_empty());
+
+    let findings = check_manifests(root, &workdir.errors());
+    assert_eq!(findings.len(), 1);
+    assert_eq!(findings[0].check, "config");
+}
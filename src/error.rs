@@ -0,0 +1,145 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::response::{Envelope, ErrorEntry};
+
+/// Why a command failed. Each kind exits with its own code, so that scripts can tell a service
+/// that failed to start from a typo on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The command line is wrong: a missing or malformed argument.
+    Usage(String),
+    /// A service, template, plugin or snapshot that does not exist.
+    NotFound(String),
+    /// A manifest, template or configuration that cannot be used as it is.
+    Invalid(String),
+    /// A service script failed or could not be run.
+    Service(String),
+    /// Reading or writing files, or fetching from the network, failed.
+    Io(String),
+    /// Some of the items a command acted on failed, its output already lists them.
+    Partial(Box<Error>),
+}
+
+impl Error {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Usage(_) => "usage",
+            Error::NotFound(_) => "not_found",
+            Error::Invalid(_) => "invalid",
+            Error::Service(_) => "service",
+            Error::Io(_) => "io",
+            Error::Partial(err) => err.kind(),
+        }
+    }
+
+    /// Exit code of the process. 1 is left to panics and 2 matches clap's own usage errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Usage(_) => 2,
+            Error::NotFound(_) => 3,
+            Error::Invalid(_) => 4,
+            Error::Service(_) => 5,
+            Error::Io(_) => 6,
+            Error::Partial(err) => err.exit_code(),
+        }
+    }
+
+    /// The variant of the error, to build another error of the same kind.
+    fn constructor(&self) -> fn(String) -> Error {
+        match self {
+            Error::Usage(_) => Error::Usage,
+            Error::NotFound(_) => Error::NotFound,
+            Error::Invalid(_) => Error::Invalid,
+            Error::Service(_) => Error::Service,
+            Error::Io(_) => Error::Io,
+            Error::Partial(err) => err.constructor(),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Error::Usage(message)
+            | Error::NotFound(message)
+            | Error::Invalid(message)
+            | Error::Service(message)
+            | Error::Io(message) => message,
+            Error::Partial(err) => err.message(),
+        }
+    }
+
+    /// Write the error to stderr, as an envelope without results when `json` is set. Partial
+    /// failures are already in the envelope on stdout.
+    pub fn report(&self, json: bool, command: &str) {
+        if json {
+            if let Error::Partial(_) = self {
+                return;
+            }
+            let envelope = Envelope::<()>::new(command, vec![], vec![ErrorEntry::new(None, self)]);
+            eprintln!(
+                "{}",
+                serde_json::to_string(&envelope).expect("Cannot serialized into json")
+            );
+        } else {
+            eprintln!("Error: {}", self);
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Give the error of a `Result` one of the kinds of [`Error`]. An [`Error`] already carried by
+/// the error keeps its own kind.
+pub trait Classify<T> {
+    fn classify(self, kind: fn(String) -> Error) -> Result<T>;
+}
+
+impl<T, E> Classify<T> for std::result::Result<T, E>
+where
+    E: Into<anyhow::Error>,
+{
+    fn classify(self, kind: fn(String) -> Error) -> Result<T> {
+        self.map_err(|err| {
+            let err = err.into();
+            let kind = err.downcast_ref::<Error>().map_or(kind, Error::constructor);
+            kind(format!("{:#}", err))
+        })
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn error_test() {
+    let err = Error::NotFound("No such service found.".to_string());
+    assert_eq!(err.exit_code(), 3);
+    assert_eq!(err.to_string(), "No such service found.");
+
+    let codes = [
+        Error::Usage(String::new()),
+        Error::NotFound(String::new()),
+        Error::Invalid(String::new()),
+        Error::Service(String::new()),
+        Error::Io(String::new()),
+    ]
+    .map(|ele| ele.exit_code());
+    assert!(codes.iter().all(|code| *code > 1));
+    assert!((1..codes.len()).all(|index| !codes[..index].contains(&codes[index])));
+
+    assert_eq!(err.kind(), "not_found");
+
+    let err = Error::Partial(Box::new(Error::Service("1 of 2 failed".to_string())));
+    assert_eq!(err.exit_code(), 5);
+    assert_eq!(err.kind(), "service");
+
+    let err = Err::<(), _>(anyhow::Error::from(Error::Invalid("bad".to_string())).context("load"))
+        .classify(Error::Io)
+        .unwrap_err();
+    assert_eq!(err, Error::Invalid("load: bad".to_string()));
+}
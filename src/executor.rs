@@ -1,16 +1,22 @@
+use std::fmt::{self, Display, Formatter};
 use std::io::BufReader;
-use std::process::{ChildStdout, Stdio};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ChildStdout, ExitStatus, Stdio};
 use std::{
     path::PathBuf,
     process::{Child, Command},
 };
 
+use schemars::JsonSchema;
+use serde::Serialize;
+
 #[allow(unused)]
 #[derive(Debug, Default)]
 pub struct Executor {
     executable: PathBuf,
     args: Vec<String>,
     current_dir: PathBuf,
+    envs: Vec<(String, String)>,
 }
 
 #[allow(unused)]
@@ -31,8 +37,13 @@ impl Executor {
         let mut args = vec![self.executable.to_str().unwrap().to_string()];
         args.append(&mut self.args.clone());
 
-        Ok(Command::new("sh")
-            .current_dir(self.current_dir)
+        let mut command = Command::new("sh");
+        if !self.current_dir.as_os_str().is_empty() {
+            command.current_dir(self.current_dir);
+        }
+
+        Ok(command
+            .envs(self.envs)
             .arg("-c")
             .arg(args.join(" "))
             .stdout(Stdio::piped())
@@ -46,12 +57,102 @@ impl Executor {
         self.current_dir = dir.into()
     }
 
+    pub fn env<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.envs.push((key.into(), value.into()))
+    }
+
     pub async fn output_reader(mut child: Child) -> (BufReader<ChildStdout>, Child) {
         let reader = BufReader::new(child.stdout.take().expect("Cannot open output stream"));
         (reader, child)
     }
 }
 
+/// Names of the standard Linux signals, by number.
+const SIGNALS: [&str; 31] = [
+    "SIGHUP",
+    "SIGINT",
+    "SIGQUIT",
+    "SIGILL",
+    "SIGTRAP",
+    "SIGABRT",
+    "SIGBUS",
+    "SIGFPE",
+    "SIGKILL",
+    "SIGUSR1",
+    "SIGSEGV",
+    "SIGUSR2",
+    "SIGPIPE",
+    "SIGALRM",
+    "SIGTERM",
+    "SIGSTKFLT",
+    "SIGCHLD",
+    "SIGCONT",
+    "SIGSTOP",
+    "SIGTSTP",
+    "SIGTTIN",
+    "SIGTTOU",
+    "SIGURG",
+    "SIGXCPU",
+    "SIGXFSZ",
+    "SIGVTALRM",
+    "SIGPROF",
+    "SIGWINCH",
+    "SIGIO",
+    "SIGPWR",
+    "SIGSYS",
+];
+
+/// How a script that did not succeed ended: with an exit code, or killed by a signal.
+#[derive(Debug, Serialize, JsonSchema, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Termination {
+    /// `None` when a signal killed the script.
+    pub code: Option<i32>,
+    pub signal: Option<Signal>,
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Signal {
+    pub number: i32,
+    /// `SIGKILL` for 9, `SIG<number>` for real-time signals.
+    pub name: String,
+    pub core_dumped: bool,
+}
+
+impl From<ExitStatus> for Termination {
+    fn from(status: ExitStatus) -> Self {
+        let signal = status.signal().map(|number| Signal {
+            number,
+            name: usize::try_from(number - 1)
+                .ok()
+                .and_then(|index| SIGNALS.get(index))
+                .map_or_else(|| format!("SIG{}", number), |name| name.to_string()),
+            core_dumped: status.core_dumped(),
+        });
+
+        Termination {
+            code: status.code(),
+            signal,
+        }
+    }
+}
+
+impl Display for Termination {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match (&self.signal, self.code) {
+            (Some(signal), _) if signal.core_dumped => {
+                write!(f, "{} ({}), core dumped", signal.name, signal.number)
+            }
+            (Some(signal), _) => write!(f, "{} ({})", signal.name, signal.number),
+            (None, Some(code)) => write!(f, "{}", code),
+            (None, None) => write!(f, "unknown"),
+        }
+    }
+}
+
 impl<T> From<T> for Executor
 where
     T: Into<String>,
@@ -73,7 +174,7 @@ fn executor_test() {
 
     let rt = tokio::runtime::Runtime::new().expect("Cannot initializing Tokio runtime");
     rt.block_on(async {
-        for mut command in vec![
+        for mut command in [
             Executor::new("ls", vec!["/"])
                 .exec()
                 .await
@@ -85,12 +186,7 @@ fn executor_test() {
         ] {
             let reader = BufReader::new(command.stdout.take().expect("Cannot open output stream"));
 
-            let result: Vec<String> = reader
-                .lines()
-                .into_iter()
-                .filter(Result::is_ok)
-                .map(Result::unwrap)
-                .collect();
+            let result: Vec<String> = reader.lines().map_while(Result::ok).collect();
 
             command.wait().expect("Cannot get command exit code");
 
@@ -98,3 +194,29 @@ fn executor_test() {
         }
     });
 }
+
+#[cfg(test)]
+#[test]
+fn termination_test() {
+    let rt = tokio::runtime::Runtime::new().expect("Cannot initializing Tokio runtime");
+    rt.block_on(async {
+        let status = Executor::from("exit 3")
+            .exec()
+            .await
+            .expect("Cannot execute command.")
+            .wait()
+            .expect("Cannot get command exit code");
+        assert_eq!(Termination::from(status).to_string(), "3");
+
+        let status = Executor::from("kill -9 $$")
+            .exec()
+            .await
+            .expect("Cannot execute command.")
+            .wait()
+            .expect("Cannot get command exit code");
+        let termination = Termination::from(status);
+        assert_eq!(termination.code, None);
+        assert_eq!(termination.to_string(), "SIGKILL (9)");
+        assert!(termination.signal.is_some_and(|ele| !ele.core_dumped));
+    });
+}
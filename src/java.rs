@@ -0,0 +1,299 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    manifest::{JavaPreset, ManifestContentJava},
+};
+
+/// Where installations are discovered, one per directory holding `bin/java`.
+pub const JVM_DIRECTORY: &str = "/usr/lib/jvm";
+
+/// Value of `scripts.start` that runs the JVM built from `[java]` in the background.
+pub const BUILTIN_START: &str = "builtin:java";
+
+/// Aikar's flags for G1, from https://mcflags.emc.gs.
+const AIKAR_FLAGS: [&str; 20] = [
+    "-XX:+UseG1GC",
+    "-XX:+ParallelRefProcEnabled",
+    "-XX:MaxGCPauseMillis=200",
+    "-XX:+UnlockExperimentalVMOptions",
+    "-XX:+DisableExplicitGC",
+    "-XX:+AlwaysPreTouch",
+    "-XX:G1NewSizePercent=30",
+    "-XX:G1MaxNewSizePercent=40",
+    "-XX:G1HeapRegionSize=8M",
+    "-XX:G1ReservePercent=20",
+    "-XX:G1HeapWastePercent=5",
+    "-XX:G1MixedGCCountTarget=4",
+    "-XX:InitiatingHeapOccupancyPercent=15",
+    "-XX:G1MixedGCLiveThresholdPercent=90",
+    "-XX:G1RSetUpdatingPauseIntervalMillis=5",
+    "-XX:SurvivorRatio=32",
+    "-XX:+PerfDisableSharedMem",
+    "-XX:MaxTenuringThreshold=1",
+    "-Dusing.aikars.flags=https://mcflags.emc.gs",
+    "-Daikars.new.flags=true",
+];
+
+/// Replacements of Aikar's flags for heaps of 12 GiB and more.
+const AIKAR_LARGE_HEAP_FLAGS: [(&str, &str); 5] = [
+    ("-XX:G1NewSizePercent=30", "-XX:G1NewSizePercent=40"),
+    ("-XX:G1MaxNewSizePercent=40", "-XX:G1MaxNewSizePercent=50"),
+    ("-XX:G1HeapRegionSize=8M", "-XX:G1HeapRegionSize=16M"),
+    ("-XX:G1ReservePercent=20", "-XX:G1ReservePercent=15"),
+    (
+        "-XX:InitiatingHeapOccupancyPercent=15",
+        "-XX:InitiatingHeapOccupancyPercent=20",
+    ),
+];
+
+#[derive(Debug, Serialize, JsonSchema, Deserialize, Clone, PartialEq, Eq)]
+pub struct JavaInstallation {
+    /// Major version, 8 for `1.8.0`.
+    pub version: u32,
+    /// The `java` executable.
+    pub path: PathBuf,
+    /// Discovered under `/usr/lib/jvm` rather than declared in `config.toml`.
+    #[serde(default)]
+    pub discovered: bool,
+}
+
+/// Installations declared in the configuration, then the ones discovered under `jvm_dir`.
+pub fn installations(config: &Config, jvm_dir: &Path) -> Vec<JavaInstallation> {
+    let mut installations = config.java.clone();
+    installations.extend(discover(jvm_dir));
+    installations
+}
+
+/// Installations under `jvm_dir`, leaving out symbolic links to others.
+pub fn discover(jvm_dir: &Path) -> Vec<JavaInstallation> {
+    let mut found: Vec<(PathBuf, JavaInstallation)> = vec![];
+
+    let mut entries = fs::read_dir(jvm_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|ele| ele.path())
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    for dir in entries {
+        let java = dir.join("bin").join("java");
+        let Ok(real) = fs::canonicalize(&java) else {
+            continue;
+        };
+        if found.iter().any(|(path, _)| *path == real) {
+            continue;
+        }
+
+        let release = fs::read_to_string(dir.join("release")).unwrap_or_default();
+        let version = release
+            .lines()
+            .find_map(|ele| ele.strip_prefix("JAVA_VERSION="))
+            .and_then(|ele| major_version(ele.trim_matches('"')))
+            .or_else(|| {
+                let name = dir.file_name()?.to_string_lossy().to_string();
+                name.split(['-', '_']).find_map(|ele| {
+                    major_version(ele).filter(|_| ele.starts_with(char::is_numeric))
+                })
+            });
+
+        if let Some(version) = version {
+            found.push((
+                real,
+                JavaInstallation {
+                    version,
+                    path: java,
+                    discovered: true,
+                },
+            ));
+        }
+    }
+
+    found.into_iter().map(|(_, ele)| ele).collect()
+}
+
+/// The installation for `version`, the newest one when no version is asked for.
+pub fn select(
+    installations: &[JavaInstallation],
+    version: Option<u32>,
+) -> Option<&JavaInstallation> {
+    match version {
+        Some(version) => installations.iter().find(|ele| ele.version == version),
+        None => installations.iter().max_by_key(|ele| ele.version),
+    }
+}
+
+/// The JVM command line of a `[java]` section.
+pub fn command_line(
+    java: &ManifestContentJava,
+    installations: &[JavaInstallation],
+) -> anyhow::Result<String> {
+    let installation = select(installations, java.version).ok_or_else(|| match java.version {
+        Some(version) => anyhow!(
+            "No Java {} installation, install one under {} or declare it in config.toml",
+            version,
+            JVM_DIRECTORY
+        ),
+        None => anyhow!("No Java installation found"),
+    })?;
+
+    let mut args = vec![installation.path.to_string_lossy().to_string()];
+    if let Some(min_heap) = &java.min_heap {
+        args.push(format!("-Xms{}", min_heap));
+    }
+    if let Some(max_heap) = &java.max_heap {
+        args.push(format!("-Xmx{}", max_heap));
+    }
+
+    if java.preset == Some(JavaPreset::Aikar) {
+        let large = java
+            .max_heap
+            .as_deref()
+            .and_then(heap_mebibytes)
+            .is_some_and(|ele| ele >= 12 * 1024);
+        args.extend(AIKAR_FLAGS.iter().map(|flag| {
+            AIKAR_LARGE_HEAP_FLAGS
+                .iter()
+                .find(|(from, _)| large && from == flag)
+                .map_or(*flag, |(_, to)| to)
+                .to_string()
+        }));
+    }
+
+    args.extend(java.flags.iter().cloned());
+    args.push("-jar".to_string());
+    args.push(java.jar.clone());
+    args.extend(java.args.iter().cloned());
+
+    Ok(args
+        .iter()
+        .map(|ele| shell_quote(ele))
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+/// Shell script of the built-in start mode: run the JVM in the background, logging to
+/// `logs/console.log`, and record its PID in `pid_file`.
+pub fn start_script(command_line: &str, pid_file: Option<&str>) -> String {
+    let record = pid_file
+        .map(|ele| format!(" echo $! > {}", shell_quote(ele)))
+        .unwrap_or_default();
+    format!(
+        "mkdir -p logs && nohup {} > logs/console.log 2>&1 < /dev/null &{}",
+        command_line, record
+    )
+}
+
+/// `arg` as a single shell word, single-quoted unless it only holds safe characters.
+fn shell_quote(arg: &str) -> String {
+    let safe = |ele: char| ele.is_ascii_alphanumeric() || "-_./=:+,@%".contains(ele);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// `17.0.8` gives 17 and `1.8.0_382` gives 8.
+fn major_version(version: &str) -> Option<u32> {
+    let mut parts = version.split(['.', '_', '+']);
+    match parts.next()?.parse().ok()? {
+        1 => parts.next()?.parse().ok(),
+        major => Some(major),
+    }
+}
+
+/// Size of a heap like `4G` or `512M` in MiB.
+fn heap_mebibytes(heap: &str) -> Option<u64> {
+    let heap = heap.to_uppercase();
+    let (number, unit) = heap.split_at(heap.find(|ele: char| !ele.is_ascii_digit())?);
+    let number = number.parse::<u64>().ok()?;
+    match unit {
+        "G" => Some(number * 1024),
+        "M" => Some(number),
+        "K" => Some(number / 1024),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn java_test() {
+    assert_eq!(major_version("1.8.0_382"), Some(8));
+    assert_eq!(major_version("17.0.8"), Some(17));
+    assert_eq!(major_version("21"), Some(21));
+
+    let dir = tempfile::tempdir().unwrap();
+    for (name, release) in [
+        ("java-8-openjdk-amd64", None),
+        ("temurin-21-jdk", Some("JAVA_VERSION=\"21.0.1\"\n")),
+    ] {
+        let bin = dir.path().join(name).join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        fs::write(bin.join("java"), "").unwrap();
+        if let Some(release) = release {
+            fs::write(dir.path().join(name).join("release"), release).unwrap();
+        }
+    }
+    std::os::unix::fs::symlink(
+        dir.path().join("temurin-21-jdk"),
+        dir.path().join("zulu-21"),
+    )
+    .unwrap();
+
+    let installations = discover(dir.path());
+    assert_eq!(
+        installations
+            .iter()
+            .map(|ele| ele.version)
+            .collect::<Vec<_>>(),
+        vec![8, 21]
+    );
+    assert_eq!(select(&installations, None).unwrap().version, 21);
+
+    let java = ManifestContentJava {
+        version: Some(8),
+        min_heap: Some("16G".to_string()),
+        max_heap: Some("16G".to_string()),
+        flags: vec!["-Dfile.encoding=UTF-8".to_string()],
+        preset: Some(JavaPreset::Aikar),
+        ..Default::default()
+    };
+    let command = command_line(&java, &installations).unwrap();
+    assert!(command.starts_with(&format!(
+        "{} -Xms16G -Xmx16G -XX:+UseG1GC",
+        dir.path().join("java-8-openjdk-amd64/bin/java").display()
+    )));
+    assert!(command.contains("-XX:G1HeapRegionSize=16M"));
+    assert!(command.ends_with("-Dfile.encoding=UTF-8 -jar server.jar --nogui"));
+
+    let java = ManifestContentJava {
+        version: Some(11),
+        ..java
+    };
+    assert!(command_line(&java, &installations).is_err());
+
+    let installations = vec![JavaInstallation {
+        version: 17,
+        path: PathBuf::from("/opt/my java/bin/java"),
+        discovered: false,
+    }];
+    let java = ManifestContentJava {
+        jar: "it's.jar".to_string(),
+        args: vec![],
+        ..Default::default()
+    };
+    assert_eq!(
+        command_line(&java, &installations).unwrap(),
+        "'/opt/my java/bin/java' -jar 'it'\\''s.jar'"
+    );
+    assert!(start_script("java", Some("run/my pid")).ends_with("echo $! > 'run/my pid'"));
+}
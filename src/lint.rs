@@ -0,0 +1,426 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    fs,
+    path::Path,
+};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use toml::Value;
+
+use crate::{
+    manifest::{ManifestContent, SyntaxError},
+    port::{self, PortRegistry, PortUse},
+    template::{FileRules, TemplateInfomation, BUILTIN_VARS, IGNORE_FILE, MANIFEST_FILE},
+};
+
+/// Scripts a manifest cannot work without.
+const REQUIRED_SCRIPTS: [&str; 2] = ["health_check", "start"];
+
+#[derive(Debug, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+pub enum LintKind {
+    Toml,
+    Placeholder,
+    UndeclaredVariable,
+    MissingScript,
+    MissingFile,
+    Extends,
+    Port,
+    Duration,
+}
+
+impl Display for LintKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone)]
+pub struct LintIssue {
+    /// Template or service the issue was found in.
+    pub name: String,
+    /// File relative to the template or service directory.
+    pub file: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub kind: LintKind,
+    pub message: String,
+}
+
+impl LintIssue {
+    /// `line:column` when the issue has a position.
+    pub fn position(&self) -> String {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => format!("{}:{}", line, column),
+            _ => String::new(),
+        }
+    }
+}
+
+struct Linter {
+    name: String,
+    issues: Vec<LintIssue>,
+}
+
+impl Linter {
+    fn new(manifest_path: &Path) -> Linter {
+        let name = manifest_path
+            .parent()
+            .and_then(Path::file_name)
+            .map(|ele| ele.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        Linter {
+            name,
+            issues: vec![],
+        }
+    }
+
+    fn report(
+        &mut self,
+        file: &str,
+        position: Option<(usize, usize)>,
+        kind: LintKind,
+        message: String,
+    ) {
+        self.issues.push(LintIssue {
+            name: self.name.clone(),
+            file: file.to_string(),
+            line: position.map(|ele| ele.0),
+            column: position.map(|ele| ele.1),
+            kind,
+            message,
+        });
+    }
+
+    /// Parse a TOML file, reporting where it is invalid.
+    fn parse(&mut self, path: &Path, file: &str) -> Option<(String, Value)> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) => {
+                self.report(file, None, LintKind::Toml, err.to_string());
+                return None;
+            }
+        };
+
+        match toml::from_str::<Value>(&content) {
+            Ok(value) => Some((content, value)),
+            Err(err) => {
+                let position = err.span().map(|ele| position(&content, ele.start));
+                self.report(file, position, LintKind::Toml, err.message().to_string());
+                None
+            }
+        }
+    }
+
+    /// Placeholders of a text file, reporting unbalanced pairs.
+    fn placeholders(&mut self, content: &str, file: &str) -> Vec<String> {
+        match ManifestContent::extract_vars(content) {
+            Ok(vars) => vars,
+            Err(err) => {
+                let (position, message) = match err.downcast_ref::<SyntaxError>() {
+                    Some(err) => (Some((err.line, err.column)), err.message.clone()),
+                    None => (None, err.to_string()),
+                };
+                self.report(file, position, LintKind::Placeholder, message);
+                vec![]
+            }
+        }
+    }
+
+    /// Check the complete manifest of a template or a service. `content` is given when the
+    /// manifest comes straight from a file, so that errors can be located.
+    fn manifest(&mut self, manifest: &Value, content: Option<&str>, exists: impl Fn(&str) -> bool) {
+        let scripts = manifest.get("scripts");
+
+        let mut missing = false;
+        for script in REQUIRED_SCRIPTS {
+            let value = scripts
+                .and_then(|ele| ele.get(script))
+                .and_then(Value::as_str);
+            if value.is_none_or(|ele| ele.trim().is_empty()) {
+                missing = true;
+                self.report(
+                    MANIFEST_FILE,
+                    None,
+                    LintKind::MissingScript,
+                    format!("Required script `{}` is missing", script),
+                );
+            }
+        }
+
+        if !missing {
+            let error = match content {
+                Some(content) => toml::from_str::<ManifestContent>(content).err().map(|err| {
+                    let position = err.span().map(|ele| position(content, ele.start));
+                    (position, err.message().to_string())
+                }),
+                None => manifest
+                    .clone()
+                    .try_into::<ManifestContent>()
+                    .err()
+                    .map(|err| (None, err.message().to_string())),
+            };
+            if let Some((position, message)) = error {
+                self.report(MANIFEST_FILE, position, LintKind::Toml, message);
+            }
+        }
+
+        let warning = manifest.get("warning");
+        let durations = warning
+            .and_then(|ele| ele.get("default"))
+            .into_iter()
+            .chain(
+                warning
+                    .and_then(|ele| ele.get("countdown"))
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten(),
+            )
+            .filter_map(Value::as_str);
+        for duration in durations {
+            if let Err(err) = humantime::parse_duration(duration) {
+                self.report(
+                    MANIFEST_FILE,
+                    None,
+                    LintKind::Duration,
+                    format!("Warning duration `{}` is invalid: {}", duration, err),
+                );
+            }
+        }
+
+        let scripts = scripts
+            .and_then(Value::as_table)
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, value)| Some((name, value.as_str()?)));
+        for (name, script) in scripts {
+            for file in referenced_files(script) {
+                if !exists(file) {
+                    self.report(
+                        MANIFEST_FILE,
+                        None,
+                        LintKind::MissingFile,
+                        format!("Script `{}` refers to {}, which does not exist", name, file),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Lint the template whose manifest is at `manifest_path`. `templates` are used to resolve
+/// what it extends.
+pub fn lint_template(manifest_path: &Path, templates: &[TemplateInfomation]) -> Vec<LintIssue> {
+    let mut linter = Linter::new(manifest_path);
+    let dir = manifest_path.parent().expect("Manifest has a parent");
+
+    let Some((content, manifest)) = linter.parse(manifest_path, MANIFEST_FILE) else {
+        return linter.issues;
+    };
+
+    // First file using each placeholder, among the files that are rendered.
+    let rules = FileRules::new(
+        dir,
+        &fs::read_to_string(dir.join(IGNORE_FILE)).unwrap_or_default(),
+        toml::from_str::<ManifestContent>(&content)
+            .ok()
+            .and_then(|ele| ele.files)
+            .as_ref(),
+    );
+    let rules = match rules {
+        Ok(rules) => rules,
+        Err(err) => {
+            linter.report(IGNORE_FILE, None, LintKind::Toml, err.to_string());
+            return linter.issues;
+        }
+    };
+
+    let mut used = BTreeMap::new();
+    for (file, path) in rules.files(dir) {
+        if !rules.is_rendered(&file) {
+            continue;
+        }
+        let Ok(text) = fs::read_to_string(path) else {
+            continue;
+        };
+
+        for var in linter.placeholders(&text, &file) {
+            used.entry(var).or_insert_with(|| file.clone());
+        }
+    }
+
+    let (merged, layers) = if manifest.get("extends").is_some() {
+        let resolved = TemplateInfomation::new(manifest_path)
+            .and_then(|ele| ele.resolve(templates))
+            .and_then(|ele| {
+                let merged = toml::from_str::<Value>(ele.merged_manifest.as_deref().unwrap_or(""))?;
+                Ok((merged, ele.layers))
+            });
+
+        match resolved {
+            Ok(resolved) => resolved,
+            Err(err) => {
+                linter.report(MANIFEST_FILE, None, LintKind::Extends, err.to_string());
+                return linter.issues;
+            }
+        }
+    } else {
+        (manifest, vec![dir.to_path_buf()])
+    };
+
+    let declared = merged
+        .get("variables")
+        .and_then(Value::as_table)
+        .map(|ele| ele.keys().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    for (var, file) in used {
+        if !declared.contains(&var) && !BUILTIN_VARS.contains(&var.as_str()) {
+            linter.report(
+                &file,
+                None,
+                LintKind::UndeclaredVariable,
+                format!("Variable {} is used but not declared in [variables]", var),
+            );
+        }
+    }
+
+    let content = (layers.len() == 1).then_some(content.as_str());
+    linter.manifest(&merged, content, |file| {
+        layers.iter().any(|ele| ele.join(file).exists())
+    });
+
+    linter.issues
+}
+
+/// Lint the service whose manifest is at `manifest_path`.
+pub fn lint_service(manifest_path: &Path) -> Vec<LintIssue> {
+    let mut linter = Linter::new(manifest_path);
+    let dir = manifest_path.parent().expect("Manifest has a parent");
+
+    let Some((content, manifest)) = linter.parse(manifest_path, MANIFEST_FILE) else {
+        return linter.issues;
+    };
+
+    for var in linter.placeholders(&content, MANIFEST_FILE) {
+        linter.report(
+            MANIFEST_FILE,
+            None,
+            LintKind::UndeclaredVariable,
+            format!("Placeholder {{{}}} was never replaced", var),
+        );
+    }
+
+    linter.manifest(&manifest, Some(&content), |file| dir.join(file).exists());
+
+    linter.issues
+}
+
+/// Ports declared by several services, and ports of services that are not `running` but are
+/// already bound on the host.
+pub fn lint_ports(registry: &PortRegistry, running: &[String]) -> Vec<LintIssue> {
+    let mut issues = vec![];
+    let mut report = |ele: &PortUse, message: String| {
+        issues.push(LintIssue {
+            name: ele.service.clone(),
+            file: ele.file.clone(),
+            line: None,
+            column: None,
+            kind: LintKind::Port,
+            message,
+        })
+    };
+
+    let conflicts = registry.conflicts();
+    for ele in registry.uses() {
+        if let Some(uses) = conflicts.get(&ele.port) {
+            let others = uses
+                .iter()
+                .filter(|other| other.service != ele.service)
+                .map(|other| other.service.as_str())
+                .collect::<Vec<_>>();
+            report(
+                ele,
+                format!(
+                    "Port {} of `{}` is also declared by {}",
+                    ele.port,
+                    ele.key,
+                    others.join(", ")
+                ),
+            );
+        } else if !running.contains(&ele.service) && port::is_bound(ele.port) {
+            report(
+                ele,
+                format!(
+                    "Port {} of `{}` is already bound on the host",
+                    ele.port, ele.key
+                ),
+            );
+        }
+    }
+
+    issues
+}
+
+/// Relative paths a script runs or reads, like `./start.sh` or `server.jar`.
+fn referenced_files(script: &str) -> Vec<&str> {
+    script
+        .split_whitespace()
+        .filter(|ele| !ele.starts_with('/') && !ele.starts_with('-'))
+        .filter(|ele| !ele.contains(['$', '{', '=', '*']))
+        .filter(|ele| {
+            ele.starts_with("./")
+                || [".sh", ".jar", ".py"]
+                    .iter()
+                    .any(|extension| ele.ends_with(extension))
+        })
+        .collect()
+}
+
+/// 1-based line and column of a byte offset.
+fn position(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+#[test]
+fn lint_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let template = dir.path().join("paper");
+    fs::create_dir_all(&template).unwrap();
+    fs::write(
+        template.join(MANIFEST_FILE),
+        "[scripts]\nhealth_check = \"true\"\nstart = \"./start.sh {PORT}\"\n",
+    )
+    .unwrap();
+    fs::write(template.join("server.properties"), "motd={MOTD\n").unwrap();
+
+    let issues = lint_template(&template.join(MANIFEST_FILE), &[]);
+    let kinds = issues.iter().map(|ele| ele.kind).collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        vec![
+            LintKind::Placeholder,
+            LintKind::UndeclaredVariable,
+            LintKind::MissingFile
+        ]
+    );
+    assert_eq!(issues[0].position(), "1:6");
+
+    fs::write(
+        template.join(MANIFEST_FILE),
+        "[scripts]\nhealth_check = \"true\"\nstart = \"true\"\n[warning]\ncountdown = [\"1m\", \"soon\"]\n",
+    )
+    .unwrap();
+    let issues = lint_template(&template.join(MANIFEST_FILE), &[]);
+    assert_eq!(issues.last().unwrap().kind, LintKind::Duration);
+
+    fs::write(template.join(MANIFEST_FILE), "[scripts]\nstart = \n").unwrap();
+    let issues = lint_template(&template.join(MANIFEST_FILE), &[]);
+    assert_eq!(issues[0].kind, LintKind::Toml);
+    assert_eq!(issues[0].line, Some(2));
+}
@@ -1,25 +1,61 @@
-use std::{env, fs, path::PathBuf};
+use std::{env, fs, path::Path, process};
 
-use clap::{arg, builder::ValueParser, ArgAction, Command};
+use clap::{
+    arg,
+    builder::{RangedU64ValueParser, ValueParser},
+    value_parser, ArgAction, ArgGroup, ArgMatches, Command,
+};
 use cmd::CMD;
+use error::Error;
 use human_panic::setup_panic;
 
+mod artifact;
+mod backup;
+mod chunk;
 mod cmd;
+mod config;
+mod doctor;
+mod error;
 mod executor;
+mod java;
+mod lint;
 mod manifest;
+mod monitor;
+mod origin;
+mod plugin;
+mod port;
+mod properties;
+mod rcon;
+mod registry;
+mod response;
+mod scaffold;
+mod schedule;
 mod service;
 mod template;
+mod upgrade;
+mod warning;
 mod work;
 
+/// Default working directory, created on first use.
+const WORKING_DIR: &str = "/var/smc";
+
 #[tokio::main]
 async fn main() {
     setup_panic!();
-    run().await;
+    if let Err(err) = run().await {
+        process::exit(err.exit_code());
+    }
 }
 
 fn cmd() -> Command {
     let workingdir = workingdir();
     let arg_service = arg!(-s --service ... "Services list").action(ArgAction::Append);
+    let arg_warn = [
+        arg!(--warn <DURATION> "Warn players and count down before stopping."),
+        arg!(--now "Skip the countdown warning players.")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("warn"),
+    ];
     Command::new(env!("CARGO_PKG_NAME"))
         .help_expected(true)
         .version(env!("CARGO_PKG_VERSION"))
@@ -36,7 +72,11 @@ fn cmd() -> Command {
         .subcommand(
             Command::new("list")
                 .about("List service.")
-                .args([arg!(-s --services "Input services.").action(ArgAction::Append)]),
+                .args([
+                    arg!(-s --services "Input services.").action(ArgAction::Append),
+                    arg!(-v --verbose "Show which template each service was created from.")
+                        .action(ArgAction::SetTrue),
+                ]),
         )
         .subcommand(
             Command::new("start")
@@ -46,12 +86,41 @@ fn cmd() -> Command {
         .subcommand(
             Command::new("stop")
                 .about("Stop one or more service if it's running.")
+                .arg(arg_service.clone())
+                .args(arg_warn.clone()),
+        )
+        .subcommand(
+            Command::new("restart")
+                .about("Gracefully restart one or more services in dependency order.")
+                .args([
+                    arg_service.clone(),
+                    arg!(--timeout <DURATION> "How long to wait for a service to go down.")
+                        .default_value("60s"),
+                    arg!(--rolling "Restart services in batches, one batch at a time.")
+                        .action(ArgAction::SetTrue),
+                    arg!(--batch <N> "Number of services restarted at once with `--rolling`.")
+                        .value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+                        .default_value("1"),
+                    arg!(--"wait-healthy" <DURATION> "How long a batch may take to pass its health check.")
+                        .default_value("120s"),
+                ])
+                .args(arg_warn.clone()),
+        )
+        .subcommand(
+            Command::new("reload")
+                .about("Run the reload script of one or more services without restarting.")
                 .arg(arg_service.clone()),
         )
         .subcommand(
             Command::new("status")
                 .about("Query one or more service running status")
-                .arg(arg_service.clone()),
+                .args([
+                    arg_service.clone(),
+                    arg!(--watch "Keep redrawing the status table until interrupted.")
+                        .action(ArgAction::SetTrue),
+                    arg!(--interval <DURATION> "Refresh interval of `--watch`.")
+                        .default_value("5s"),
+                ]),
         )
         .subcommand(
             Command::new("template")
@@ -61,7 +130,16 @@ fn cmd() -> Command {
                 .subcommand(
                     Command::new("create")
                         .about("Create a template(s).")
-                        .args([arg!(-n --name <NAME> "Template name.").action(ArgAction::Append)])
+                        .args([
+                            arg!(-n --name <NAME> "Template name.").action(ArgAction::Append),
+                            arg!(--"from-service" <SERVICE> "Copy an existing service."),
+                            arg!(--exclude <PATTERN> "Also leave out files matching a gitignore pattern.")
+                                .action(ArgAction::Append)
+                                .requires("from-service"),
+                            arg!(-y --yes "Replace every suggested value with a placeholder.")
+                                .action(ArgAction::SetTrue)
+                                .requires("from-service"),
+                        ])
                         .arg_required_else_help(true),
                 )
                 .subcommand(
@@ -69,14 +147,37 @@ fn cmd() -> Command {
                         .about("Delete template(s).")
                         .args([arg!(-n --name <NAME> "Template name.").action(ArgAction::Append)])
                         .arg_required_else_help(true),
+                )
+                .subcommand(
+                    Command::new("update")
+                        .about("Fetch the configured template sources.")
+                        .args([arg!(-n --name <NAME> "Source name, all sources by default.")
+                            .action(ArgAction::Append)]),
+                )
+                .subcommand(Command::new("sources").about("List template sources."))
+                .subcommand(
+                    Command::new("lint")
+                        .about("Check templates for mistakes.")
+                        .args([arg!([NAME] "Template name, all templates by default.")]),
+                ),
+        )
+        .subcommand(
+            Command::new("service")
+                .about("Services management.")
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("lint")
+                        .about("Check services for mistakes.")
+                        .args([arg!([NAME] "Service name, all services by default.")]),
                 ),
         )
         .subcommand(
             Command::new("create")
                 .about("Create a service from template")
                 .args([
-                    arg!(-t --template <NAME> "Template name"),
+                    arg!(-t --template <NAME> "Template name, optionally with a version like paper@1.20."),
                     arg!(-n --name <NAME> "Service name."),
+                    arg!(--var <VAR> "Template variable as KEY=VALUE.").action(ArgAction::Append),
                 ]),
         )
         .subcommand(
@@ -85,25 +186,224 @@ fn cmd() -> Command {
                 .args([arg!(-n --name <NAME> "Service name.")]),
         )
         .subcommand(
-            Command::new("init").about("Init programs. It will download templates from github."),
+            Command::new("upgrade")
+                .about("Upgrade services to a newer version of their template.")
+                .arg_required_else_help(true)
+                .args([
+                    arg_service.clone(),
+                    arg!(--to <VERSION> "Template version, the latest by default."),
+                ]),
+        )
+        .subcommand(
+            Command::new("plugin")
+                .about("Plugins and mods management.")
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("add")
+                        .about("Declare a plugin in the manifest and install it.")
+                        .args([
+                            arg!(-s --service <NAME> "Service name.").required(true),
+                            arg!(<NAME> "Plugin name."),
+                            arg!(--version <VERSION> "Plugin version."),
+                            arg!(--url <URL> "Download URL, needs `--sha256`.")
+                                .requires("sha256")
+                                .conflicts_with("path"),
+                            arg!(--path <PATH> "Local jar file."),
+                            arg!(--sha256 <HASH> "Expected sha256 of the jar."),
+                        ])
+                        .group(
+                            ArgGroup::new("from")
+                                .args(["url", "path"])
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    Command::new("remove")
+                        .about("Remove a plugin from the manifest and the service.")
+                        .args([
+                            arg!(-s --service <NAME> "Service name.").required(true),
+                            arg!(<NAME> "Plugin name."),
+                        ]),
+                )
+                .subcommand(
+                    Command::new("list")
+                        .about("List managed and unmanaged plugins of one or more services.")
+                        .arg(arg_service.clone()),
+                ),
+        )
+        .subcommand(
+            Command::new("props")
+                .about("Read and change server.properties of services.")
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("get")
+                        .about("Show one or every property of a service.")
+                        .args([
+                            arg!(-s --service <NAME> "Service name.").required(true),
+                            arg!([KEY] "Property key, all properties by default."),
+                        ]),
+                )
+                .subcommand(
+                    Command::new("set")
+                        .about("Change a property, keeping the rest of the file as it is.")
+                        .args([
+                            arg!(-s --service <NAME> "Service name.").required(true),
+                            arg!(<KEY> "Property key."),
+                            arg!(<VALUE> "Property value."),
+                        ]),
+                )
+                .subcommand(
+                    Command::new("diff")
+                        .about("Compare properties between services, or with the template of a single service.")
+                        .args([arg_service.clone(), arg!([KEY] "Property key, all properties by default.")]),
+                ),
+        )
+        .subcommand(
+            Command::new("schedule")
+                .about("Scheduled tasks management.")
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("list")
+                        .about("List scheduled tasks and their next run time.")
+                        .arg(arg_service.clone()),
+                )
+                .subcommand(
+                    Command::new("history")
+                        .about("Show what the daemon has run.")
+                        .arg(arg_service.clone()),
+                ),
+        )
+        .subcommand(
+            Command::new("backup")
+                .about("World backups management.")
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("create")
+                        .about("Snapshot the data directory of one or more services.")
+                        .args([
+                            arg_service.clone(),
+                            arg!(--"pause-saves" "Pause world saves while the snapshot is taken.")
+                                .action(ArgAction::SetTrue),
+                            arg!(--incremental "Only store chunks changed since earlier snapshots.")
+                                .action(ArgAction::SetTrue),
+                        ]),
+                )
+                .subcommand(
+                    Command::new("list")
+                        .about("List snapshots of one or more services.")
+                        .arg(arg_service.clone()),
+                )
+                .subcommand(
+                    Command::new("restore")
+                        .about("Restore the data directory of a service from a snapshot.")
+                        .args([
+                            arg!(-s --service <NAME> "Service name.").required(true),
+                            arg!(--snapshot <ID> "Snapshot id.").required(true),
+                            arg!(--to <DIR> "Restore into this empty directory instead."),
+                            arg!(--stop "Stop the service first if it's running.")
+                                .action(ArgAction::SetTrue),
+                        ]),
+                )
+                .subcommand(
+                    Command::new("verify")
+                        .about("Check the integrity of snapshots.")
+                        .args([arg_service.clone(), arg!(--snapshot <ID> "Snapshot id.")]),
+                )
+                .subcommand(
+                    Command::new("prune")
+                        .about("Delete snapshots not kept by the retention policy.")
+                        .args([
+                            arg_service.clone(),
+                            arg!(--"keep-last" <N> "Keep the N newest snapshots.")
+                                .value_parser(value_parser!(usize)),
+                            arg!(--"keep-daily" <N> "Keep the newest snapshot of the N last days.")
+                                .value_parser(value_parser!(usize)),
+                            arg!(--"keep-weekly" <N> "Keep the newest snapshot of the N last weeks.")
+                                .value_parser(value_parser!(usize)),
+                        ]),
+                ),
+        )
+        .subcommand(
+            Command::new("java")
+                .about("Manage Java installations.")
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("list")
+                        .about("List Java installations, declared and discovered under /usr/lib/jvm."),
+                )
+                .subcommand(
+                    Command::new("command")
+                        .about("Show the JVM command line built from the [java] section of services.")
+                        .arg(arg_service.clone()),
+                ),
+        )
+        .subcommand(
+            Command::new("doctor")
+                .about("Check the working directory, services and templates for problems."),
+        )
+        .subcommand(
+            Command::new("daemon").about("Run in foreground and execute scheduled tasks."),
+        )
+        .subcommand(
+            Command::new("init")
+                .about("Init programs. It will configure and fetch the official templates."),
         )
 }
 
-async fn run() {
+async fn run() -> error::Result<()> {
     let matches = cmd().get_matches();
 
     let quite = matches.get_flag("quite");
     let json = matches.get_flag("json");
+    let command = command_name(&matches);
+
+    let result = dispatch(&matches, quite, json, &command).await;
+    if let Err(err) = &result {
+        if !quite {
+            err.report(json, &command);
+        }
+    }
+    result
+}
+
+/// The subcommands given on the command line, separated by spaces.
+fn command_name(matches: &ArgMatches) -> String {
+    let mut names = vec![];
+    let mut matches = matches;
+    while let Some((name, sub_matches)) = matches.subcommand() {
+        names.push(name);
+        matches = sub_matches;
+    }
+    names.join(" ")
+}
+
+async fn dispatch(
+    matches: &ArgMatches,
+    quite: bool,
+    json: bool,
+    command: &str,
+) -> error::Result<()> {
     let workingdir = matches
         .get_one::<String>("workingdir")
-        .expect("Cannot get argument `--workingdir`")
-        .clone();
+        .cloned()
+        .unwrap_or_else(workingdir);
+    if workingdir == WORKING_DIR && !Path::new(WORKING_DIR).exists() {
+        fs::create_dir_all(WORKING_DIR).map_err(|err| {
+            Error::Io(format!(
+                "You don't have permission to access `{}`: {}",
+                WORKING_DIR, err
+            ))
+        })?;
+    }
 
-    let (_, sub_matches) = matches.subcommand().unwrap().clone();
+    let Some((_, sub_matches)) = matches.subcommand() else {
+        return Err(Error::Usage("No subcommand given, see --help".to_string()));
+    };
 
     let cmd = CMD {
         quite,
         json,
+        command: command.to_string(),
         workingdir,
         matches: sub_matches.clone(),
     };
@@ -112,23 +412,27 @@ async fn run() {
         Some(("list", _)) => cmd.list().await,
         Some(("start", _)) => cmd.start().await,
         Some(("stop", _)) => cmd.stop().await,
+        Some(("restart", _)) => cmd.restart().await,
+        Some(("reload", _)) => cmd.reload().await,
         Some(("status", _)) => cmd.status().await,
         Some(("template", _)) => cmd.template().await,
+        Some(("service", _)) => cmd.service().await,
         Some(("create", _)) => cmd.create().await,
         Some(("delete", _)) => cmd.delete().await,
+        Some(("upgrade", _)) => cmd.upgrade().await,
+        Some(("plugin", _)) => cmd.plugin().await,
+        Some(("props", _)) => cmd.props().await,
+        Some(("schedule", _)) => cmd.schedule().await,
+        Some(("backup", _)) => cmd.backup().await,
+        Some(("java", _)) => cmd.java().await,
+        Some(("doctor", _)) => cmd.doctor().await,
+        Some(("daemon", _)) => cmd.daemon().await,
         Some(("init", _)) => cmd.init().await,
 
-        _ => {}
+        _ => Ok(()),
     }
 }
 
 fn workingdir() -> String {
-    env::var("SMC_WORKING_DIR").unwrap_or_else(|_| {
-        let path = String::from("/var/smc");
-        let path = PathBuf::from(&path);
-        if !&path.exists() {
-            fs::create_dir_all(&path).expect(r"You don't have permission to access `/var/smc`.");
-        }
-        String::from(path.to_str().unwrap())
-    })
+    env::var("SMC_WORKING_DIR").unwrap_or_else(|_| WORKING_DIR.to_string())
 }
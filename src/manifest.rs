@@ -1,14 +1,197 @@
-use anyhow::{anyhow, Ok};
+use core::fmt;
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter},
+};
+
+use anyhow::Ok;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ManifestContent {
+    /// Template this one is based on, only meaningful in templates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extends: Option<String>,
     pub enable: Option<bool>,
+    pub pid_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends: Vec<String>,
     pub scripts: ManifestContentScripts,
+    pub rcon: Option<ManifestContentRcon>,
+    pub warning: Option<ManifestContentWarning>,
+    pub backup: Option<ManifestContentBackup>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedule: Vec<ManifestContentSchedule>,
+    pub files: Option<ManifestContentFiles>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub artifacts: Vec<ManifestContentArtifact>,
+    pub plugins: Option<ManifestContentPlugins>,
+    /// Written into `server.properties` by `create` and `start`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, PropertyValue>,
+    pub java: Option<ManifestContentJava>,
+    /// Variables a template expects, filled by `create`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, ManifestContentVariable>,
     #[serde(skip)]
     pub vars: Vec<String>,
 }
 
+/// Which template files get their placeholders replaced, as gitignore patterns.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ManifestContentFiles {
+    /// Only these files are rendered when given, every text file otherwise.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub render: Vec<String>,
+    /// Copied as they are even if they match `render`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub verbatim: Vec<String>,
+}
+
+/// A file fetched into the artifact cache and hard-linked into the service.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ManifestContentArtifact {
+    /// `http://`, `https://` or `file://` URL.
+    pub url: Option<String>,
+    /// Local file, relative to the service directory.
+    pub path: Option<String>,
+    pub sha256: String,
+    /// Where the file goes, relative to the service directory.
+    pub destination: String,
+}
+
+/// Plugin or mod jars managed with `plugin add|remove`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ManifestContentPlugins {
+    #[serde(default)]
+    pub folder: PluginFolder,
+    #[serde(default)]
+    pub jars: BTreeMap<String, ManifestContentPlugin>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum PluginFolder {
+    /// Bukkit, Spigot and Paper.
+    #[default]
+    Plugins,
+    /// Forge and Fabric.
+    Mods,
+}
+
+impl Display for PluginFolder {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            PluginFolder::Plugins => write!(f, "plugins"),
+            PluginFolder::Mods => write!(f, "mods"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ManifestContentPlugin {
+    pub version: Option<String>,
+    /// `http://`, `https://` or `file://` URL.
+    pub url: Option<String>,
+    /// Local jar, relative to the service directory.
+    pub path: Option<String>,
+    pub sha256: String,
+}
+
+/// The JVM of the service, exposed to scripts as `SMC_JAVA` and run by the built-in start.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ManifestContentJava {
+    /// Major Java version, the newest installation when not given.
+    pub version: Option<u32>,
+    pub min_heap: Option<String>,
+    pub max_heap: Option<String>,
+    /// Extra JVM flags, after the preset.
+    #[serde(default)]
+    pub flags: Vec<String>,
+    pub preset: Option<JavaPreset>,
+    #[serde(default = "default_java_jar")]
+    pub jar: String,
+    /// Arguments of the server itself.
+    #[serde(default = "default_java_args")]
+    pub args: Vec<String>,
+}
+
+impl Default for ManifestContentJava {
+    fn default() -> Self {
+        Self {
+            version: None,
+            min_heap: None,
+            max_heap: None,
+            flags: vec![],
+            preset: None,
+            jar: default_java_jar(),
+            args: default_java_args(),
+        }
+    }
+}
+
+fn default_java_jar() -> String {
+    "server.jar".to_string()
+}
+
+fn default_java_args() -> Vec<String> {
+    vec!["--nogui".to_string()]
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum JavaPreset {
+    /// Aikar's G1 flags.
+    Aikar,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(untagged)]
+pub enum PropertyValue {
+    Boolean(bool),
+    Integer(i64),
+    String(String),
+}
+
+impl Display for PropertyValue {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            PropertyValue::Boolean(value) => write!(f, "{}", value),
+            PropertyValue::Integer(value) => write!(f, "{}", value),
+            PropertyValue::String(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ManifestContentVariable {
+    /// Variables without a default must be given to `create`.
+    pub default: Option<String>,
+    pub description: Option<String>,
+    #[serde(
+        default,
+        rename = "type",
+        skip_serializing_if = "VariableType::is_string"
+    )]
+    pub kind: VariableType,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum VariableType {
+    #[default]
+    String,
+    /// Assigned by `create` from the configured range when not given, and checked against the
+    /// ports of the other services.
+    Port,
+}
+
+impl VariableType {
+    fn is_string(&self) -> bool {
+        *self == VariableType::String
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ManifestContentScripts {
     pub health_check: String,
@@ -16,6 +199,96 @@ pub struct ManifestContentScripts {
     pub start: String,
     pub stop: Option<String>,
     pub post_stop: Option<String>,
+    pub players: Option<String>,
+    pub reload: Option<String>,
+    pub console: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ManifestContentRcon {
+    #[serde(default = "default_rcon_host")]
+    pub host: String,
+    pub port: u16,
+    pub password: String,
+}
+
+fn default_rcon_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ManifestContentWarning {
+    pub default: Option<String>,
+    #[serde(default = "default_warning_countdown")]
+    pub countdown: Vec<String>,
+    #[serde(default = "default_warning_message")]
+    pub message: String,
+}
+
+impl Default for ManifestContentWarning {
+    fn default() -> Self {
+        Self {
+            default: None,
+            countdown: default_warning_countdown(),
+            message: default_warning_message(),
+        }
+    }
+}
+
+fn default_warning_countdown() -> Vec<String> {
+    ["5m", "1m", "30s", "10s"].map(str::to_string).to_vec()
+}
+
+fn default_warning_message() -> String {
+    "say Server will $ACTION in $TIME".to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ManifestContentBackup {
+    pub keep_last: Option<usize>,
+    pub keep_daily: Option<usize>,
+    pub keep_weekly: Option<usize>,
+    #[serde(default)]
+    pub pause_saves: bool,
+    #[serde(default)]
+    pub incremental: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ManifestContentSchedule {
+    pub cron: String,
+    #[serde(flatten)]
+    pub action: ScheduleAction,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ScheduleAction {
+    Restart {
+        #[serde(default)]
+        warn: Option<String>,
+        /// How long to wait for the service to go down, 60 seconds when unset.
+        #[serde(default)]
+        timeout: Option<String>,
+    },
+    Script {
+        script: String,
+    },
+    Command {
+        command: String,
+    },
+    Backup,
+}
+
+impl Display for ScheduleAction {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ScheduleAction::Restart { .. } => write!(f, "restart"),
+            ScheduleAction::Script { script } => write!(f, "script `{}`", script),
+            ScheduleAction::Command { command } => write!(f, "command `{}`", command),
+            ScheduleAction::Backup => write!(f, "backup"),
+        }
+    }
 }
 
 #[allow(unused)]
@@ -32,7 +305,9 @@ impl ManifestContent {
         Ok(manifest)
     }
 
-    fn extract_vars<T>(content: T) -> anyhow::Result<Vec<String>>
+    /// Names of the `{VAR}` placeholders in `content`. Braces around anything but a name, like
+    /// TOML inline tables, are only checked for balance.
+    pub fn extract_vars<T>(content: T) -> anyhow::Result<Vec<String>>
     where
         T: Into<String> + Clone,
     {
@@ -40,54 +315,90 @@ impl ManifestContent {
 
         let mut vars = vec![];
 
-        let mut pairing = false;
-        let (mut line, mut offset) = (0, 0);
-        let mut lpair_offset = (0, 0);
+        // Left pairs still waiting for their right pair, with their position and content.
+        let mut open: Vec<(usize, usize, String)> = vec![];
 
-        let mut var = String::new();
-        for ele in content.lines().into_iter() {
-            line += 1;
-            offset = 0;
+        for (line, ele) in content.lines().enumerate() {
             let mut skip = false;
-            for ele in ele.to_string().as_bytes() {
-                offset += 1;
+            for (offset, ele) in ele.chars().enumerate() {
+                let (line, offset) = (line + 1, offset + 1);
 
                 if skip {
                     skip = false;
-                    var.push(*ele as char);
+                    if let Some((_, _, var)) = open.last_mut() {
+                        var.push(ele);
+                    }
                     continue;
                 }
 
-                match *ele as char {
+                match ele {
                     '\\' => skip = true,
-                    '{' => {
-                        pairing = true;
-                        lpair_offset.0 = line;
-                        lpair_offset.1 = offset;
-                    }
-                    '}' => {
-                        pairing = false;
-                        vars.push(var.clone());
-                    }
+                    '{' => open.push((line, offset, String::new())),
+                    '}' => match open.pop() {
+                        Some((_, _, var)) => {
+                            if is_var_name(&var) {
+                                vars.push(var);
+                            }
+                        }
+                        None => {
+                            return Err(SyntaxError {
+                                line,
+                                column: offset,
+                                message: "found right pair but missing left pair".to_string(),
+                            }
+                            .into())
+                        }
+                    },
                     _ => {
-                        if pairing {
-                            var.push(*ele as char);
+                        if let Some((_, _, var)) = open.last_mut() {
+                            var.push(ele);
                         }
                     }
                 }
             }
         }
 
-        if pairing == true {
-            return Err(anyhow!(format!(
-                "Sytax error: found left pair at {}:{} but mission right pair.",
-                line, offset
-            )));
+        if let Some((line, column, _)) = open.first() {
+            return Err(SyntaxError {
+                line: *line,
+                column: *column,
+                message: "found left pair but missing right pair".to_string(),
+            }
+            .into());
         }
         Ok(vars)
     }
 }
 
+fn is_var_name(var: &str) -> bool {
+    var.chars()
+        .next()
+        .is_some_and(|ele| ele.is_ascii_alphabetic() || ele == '_')
+        && var
+            .chars()
+            .all(|ele| ele.is_ascii_alphanumeric() || ele == '_')
+}
+
+/// A syntax error at a 1-based line and column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Syntax error at {}:{}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
 #[cfg(test)]
 #[test]
 fn extract_vars_test() {
@@ -95,11 +406,33 @@ fn extract_vars_test() {
     assert_eq!(
         ManifestContent::extract_vars(content_1)
             .unwrap()
-            .get(0)
+            .first()
             .unwrap(),
         "TEST_VAR"
     );
 
     let content_2 = r"{TEST_VAR{";
-    assert_eq!(ManifestContent::extract_vars(content_2).is_err(), true);
+    assert!(ManifestContent::extract_vars(content_2).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn syntax_error_test() {
+    let content_3 = "rcon = { port = {PORT} }\nmotd = \"{MOTD}\"\n\nname = \"{NAME\"";
+    let err = ManifestContent::extract_vars(content_3).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<SyntaxError>(),
+        Some(&SyntaxError {
+            line: 4,
+            column: 9,
+            message: "found left pair but missing right pair".to_string()
+        })
+    );
+    assert_eq!(
+        ManifestContent::extract_vars("rcon = { port = {PORT} }\nmotd = {MOTD}").unwrap(),
+        vec!["PORT", "MOTD"]
+    );
+
+    let err = ManifestContent::extract_vars("a\n  }").unwrap_err();
+    assert_eq!(err.downcast_ref::<SyntaxError>().unwrap().column, 3);
 }
@@ -0,0 +1,144 @@
+use std::{fs, io::Read, path::PathBuf, time::Duration};
+
+use serde::Serialize;
+
+use crate::{executor::Executor, service::ServiceInformation};
+
+/// Clock ticks per second used by `/proc/<pid>/stat`, which is 100 on every Linux target we run on.
+const CLOCK_TICKS: u64 = 100;
+
+#[derive(Debug, Default, Serialize, Clone, PartialEq, Eq)]
+pub struct ServiceMetrics {
+    pub pid: Option<u32>,
+    pub players: Option<u32>,
+    pub uptime: Option<u64>,
+    pub cpu_time: Option<u64>,
+    pub memory: Option<u64>,
+}
+
+impl ServiceMetrics {
+    /// Collect whatever metrics the service manifest makes available.
+    ///
+    /// Process metrics need `pid_file`, player counts need the `players` script.
+    pub async fn collect(service: &ServiceInformation) -> ServiceMetrics {
+        let mut metrics = ServiceMetrics {
+            pid: read_pid(service),
+            players: read_players(service).await,
+            ..Default::default()
+        };
+
+        if let Some(pid) = metrics.pid {
+            if let Some((uptime, cpu_time)) = read_stat(pid) {
+                metrics.uptime = Some(uptime);
+                metrics.cpu_time = Some(cpu_time);
+            }
+            metrics.memory = read_memory(pid);
+        }
+
+        metrics
+    }
+
+    pub fn uptime_string(&self) -> String {
+        self.uptime
+            .map(|secs| humantime::format_duration(Duration::from_secs(secs)).to_string())
+            .unwrap_or_else(|| "-".to_string())
+    }
+
+    pub fn players_string(&self) -> String {
+        self.players
+            .map(|players| players.to_string())
+            .unwrap_or_else(|| "-".to_string())
+    }
+
+    pub fn resource_string(&self) -> String {
+        match (self.cpu_time, self.memory) {
+            (Some(cpu_time), Some(memory)) => {
+                format!("cpu {}s / mem {} MiB", cpu_time, memory / 1024)
+            }
+            (None, Some(memory)) => format!("mem {} MiB", memory / 1024),
+            (Some(cpu_time), None) => format!("cpu {}s", cpu_time),
+            (None, None) => "-".to_string(),
+        }
+    }
+}
+
+fn read_pid(service: &ServiceInformation) -> Option<u32> {
+    let pid_file = service.manifest.pid_file.as_ref()?;
+
+    let mut path = service.path.clone();
+    path.push(pid_file);
+
+    let pid = fs::read_to_string(path).ok()?.trim().parse::<u32>().ok()?;
+
+    if PathBuf::from(format!("/proc/{}", pid)).exists() {
+        Some(pid)
+    } else {
+        None
+    }
+}
+
+async fn read_players(service: &ServiceInformation) -> Option<u32> {
+    let script = service.manifest.scripts.players.as_ref()?;
+
+    let mut executor = Executor::from(script);
+    executor.current_dir(&service.path);
+
+    let child = executor.exec().await.ok()?;
+    let (mut reader, mut child) = Executor::output_reader(child).await;
+
+    let mut output = String::new();
+    reader.read_to_string(&mut output).ok()?;
+
+    if !child.wait().ok()?.success() {
+        return None;
+    }
+
+    output.trim().parse::<u32>().ok()
+}
+
+/// Returns the process uptime and the cpu time it consumed, both in seconds.
+fn read_stat(pid: u32) -> Option<(u64, u64)> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+
+    // The command name may contain spaces, so the fields are counted from its closing paren.
+    let fields = stat[stat.rfind(')')? + 2..]
+        .split_whitespace()
+        .collect::<Vec<_>>();
+
+    let utime = fields.get(11)?.parse::<u64>().ok()?;
+    let stime = fields.get(12)?.parse::<u64>().ok()?;
+    let start_time = fields.get(19)?.parse::<u64>().ok()?;
+
+    let system_uptime = fs::read_to_string("/proc/uptime").ok()?;
+    let system_uptime = system_uptime
+        .split_whitespace()
+        .next()?
+        .parse::<f64>()
+        .ok()? as u64;
+
+    Some((
+        system_uptime.saturating_sub(start_time / CLOCK_TICKS),
+        (utime + stime) / CLOCK_TICKS,
+    ))
+}
+
+/// Returns the resident set size of the process in KiB.
+fn read_memory(pid: u32) -> Option<u64> {
+    fs::read_to_string(format!("/proc/{}/status", pid))
+        .ok()?
+        .lines()
+        .find(|line| line.starts_with("VmRSS:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse::<u64>()
+        .ok()
+}
+
+#[cfg(test)]
+#[test]
+fn read_stat_test() {
+    let pid = std::process::id();
+
+    assert!(read_stat(pid).is_some());
+    assert!(read_memory(pid).unwrap() > 0);
+}
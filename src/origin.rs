@@ -0,0 +1,121 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::{backup::hash_file, registry::SourceState, template::TemplateInfomation};
+
+/// Written by `create` to `.smc/origin.toml` in the service directory.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServiceOrigin {
+    pub template: String,
+    pub version: Option<String>,
+    pub source: Option<String>,
+    pub commit: Option<String>,
+    /// Directory of the template relative to the templates directory.
+    #[serde(default)]
+    pub path: Option<String>,
+    pub created: DateTime<Local>,
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+    /// sha256 of every file copied from the template, keyed by its path in the service.
+    #[serde(default)]
+    pub files: BTreeMap<String, String>,
+    /// Values of the `port` variables, kept in the port registry.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ports: BTreeMap<String, u16>,
+}
+
+impl ServiceOrigin {
+    pub fn new(
+        template: &TemplateInfomation,
+        templates_dir: &Path,
+        state: Option<SourceState>,
+        vars: BTreeMap<String, String>,
+        files: BTreeMap<String, String>,
+    ) -> ServiceOrigin {
+        ServiceOrigin {
+            template: template.name.clone(),
+            version: template.version.clone(),
+            source: template.source.clone(),
+            commit: state.and_then(|ele| ele.commit),
+            path: template
+                .path
+                .strip_prefix(templates_dir)
+                .ok()
+                .map(|ele| ele.to_string_lossy().to_string()),
+            created: Local::now(),
+            vars,
+            files,
+            ports: BTreeMap::new(),
+        }
+    }
+
+    pub fn load<T>(service_dir: T) -> Option<ServiceOrigin>
+    where
+        T: Into<PathBuf>,
+    {
+        toml::from_str(&fs::read_to_string(origin_path(service_dir)).ok()?).ok()
+    }
+
+    pub fn save<T>(&self, service_dir: T) -> anyhow::Result<()>
+    where
+        T: Into<PathBuf>,
+    {
+        let path = origin_path(service_dir);
+        fs::create_dir_all(path.parent().expect("Origin path has a parent"))?;
+        fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Template files that were changed or removed in the service since it was created.
+    pub fn modified_files<T>(&self, service_dir: T) -> Vec<String>
+    where
+        T: Into<PathBuf>,
+    {
+        let service_dir: PathBuf = service_dir.into();
+
+        self.files
+            .iter()
+            .filter(|(path, hash)| {
+                hash_file(&service_dir.join(path)).map_or(true, |current| current != **hash)
+            })
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    /// `name@version`, or only the name for unversioned templates.
+    pub fn full_name(&self) -> String {
+        match &self.version {
+            Some(version) => format!("{}@{}", self.template, version),
+            None => self.template.clone(),
+        }
+    }
+}
+
+impl Display for ServiceOrigin {
+    /// The template name with its version, or with the short commit it was taken at.
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match (&self.version, &self.commit) {
+            (None, Some(commit)) => {
+                write!(f, "{} ({})", self.template, &commit[..7.min(commit.len())])
+            }
+            _ => write!(f, "{}", self.full_name()),
+        }
+    }
+}
+
+fn origin_path<T>(service_dir: T) -> PathBuf
+where
+    T: Into<PathBuf>,
+{
+    let mut path: PathBuf = service_dir.into();
+    path.push(".smc");
+    path.push("origin.toml");
+    path
+}
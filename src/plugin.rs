@@ -0,0 +1,363 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Local};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use toml_edit::{value, Document, Item, Table};
+
+use crate::{
+    artifact::{self, ArtifactCache},
+    backup::hash_file,
+    manifest::{ManifestContentArtifact, ManifestContentPlugin},
+    service::ServiceInformation,
+};
+
+/// Written to `.smc/plugins.lock` in the service directory whenever plugins are installed.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PluginLock {
+    #[serde(default, rename = "plugin")]
+    pub plugins: Vec<LockedPlugin>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LockedPlugin {
+    pub name: String,
+    pub version: Option<String>,
+    pub sha256: String,
+    /// Path of the jar relative to the service directory.
+    pub file: String,
+    pub installed: DateTime<Local>,
+}
+
+impl PluginLock {
+    pub fn load<T>(service_dir: T) -> PluginLock
+    where
+        T: Into<PathBuf>,
+    {
+        fs::read_to_string(lock_path(service_dir))
+            .ok()
+            .and_then(|ele| toml::from_str(&ele).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save<T>(&self, service_dir: T) -> anyhow::Result<()>
+    where
+        T: Into<PathBuf>,
+    {
+        let path = lock_path(service_dir);
+        fs::create_dir_all(path.parent().expect("Lock path has a parent"))?;
+        fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Option<&LockedPlugin> {
+        self.plugins.iter().find(|ele| ele.name == name)
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+pub enum PluginStatus {
+    /// The jar matches the manifest and the lockfile.
+    Installed,
+    /// Declared in the manifest but not installed yet, it will be on the next start.
+    Pending,
+    /// The installed jar was changed or deleted.
+    Modified,
+    /// In the lockfile but no longer declared, it will be removed on the next start.
+    Undeclared,
+    /// A jar dropped into the folder by hand.
+    Unmanaged,
+}
+
+impl Display for PluginStatus {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone)]
+pub struct PluginEntry {
+    pub service: String,
+    pub name: String,
+    pub version: Option<String>,
+    pub file: String,
+    pub sha256: Option<String>,
+    pub status: PluginStatus,
+}
+
+/// Jar name of a plugin, `name-version.jar` when it has a version.
+pub fn file_name(name: &str, plugin: &ManifestContentPlugin) -> String {
+    match &plugin.version {
+        Some(version) => format!("{}-{}.jar", name, version),
+        None => format!("{}.jar", name),
+    }
+}
+
+/// Link the plugins declared by a service into its plugin folder, remove the ones it no longer
+/// declares and update the lockfile. Returns the names of the plugins that changed.
+pub fn install(service: &ServiceInformation, cache: &ArtifactCache) -> anyhow::Result<Vec<String>> {
+    let lock = PluginLock::load(&service.path);
+    let plugins = service.manifest.plugins.clone().unwrap_or_default();
+    if lock.plugins.is_empty() && plugins.jars.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut changed = vec![];
+    let mut locked = vec![];
+    for (name, plugin) in &plugins.jars {
+        validate_name(name)?;
+        let file = format!("{}/{}", plugins.folder, file_name(name, plugin));
+        let target = artifact::inside(&service.path, &file)?;
+        let artifact = ManifestContentArtifact {
+            url: plugin.url.clone(),
+            path: plugin.path.clone(),
+            sha256: plugin.sha256.clone(),
+            destination: file.clone(),
+        };
+        let cached = cache.fetch(&artifact, &service.path)?;
+
+        let previous = lock.find(name);
+        if let Some(previous) = previous.filter(|ele| ele.file != file) {
+            remove_file(&artifact::inside(&service.path, &previous.file)?)?;
+        }
+        if artifact::link(&cached, &target)? {
+            changed.push(name.clone());
+        }
+
+        let sha256 = plugin.sha256.to_lowercase();
+        let installed = previous
+            .filter(|ele| ele.sha256 == sha256 && ele.file == file)
+            .map_or_else(Local::now, |ele| ele.installed);
+        locked.push(LockedPlugin {
+            name: name.clone(),
+            version: plugin.version.clone(),
+            sha256,
+            file,
+            installed,
+        });
+    }
+
+    for ele in &lock.plugins {
+        if !plugins.jars.contains_key(&ele.name) {
+            remove_file(&artifact::inside(&service.path, &ele.file)?)?;
+            changed.push(ele.name.clone());
+        }
+    }
+
+    PluginLock { plugins: locked }.save(&service.path)?;
+    Ok(changed)
+}
+
+/// Plugin names end up in file names, so they cannot hold a path.
+pub fn validate_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.contains("..") {
+        return Err(anyhow!("Invalid plugin name `{}`", name));
+    }
+    Ok(())
+}
+
+/// Plugins of a service: what the manifest declares, what the lockfile records and the jars
+/// found in its plugin folder.
+pub fn status(service: &ServiceInformation) -> Vec<PluginEntry> {
+    let lock = PluginLock::load(&service.path);
+    let plugins = service.manifest.plugins.clone().unwrap_or_default();
+    let entry = |name: &str, version, file: String, sha256, status| PluginEntry {
+        service: service.name.clone(),
+        name: name.to_string(),
+        version,
+        file,
+        sha256,
+        status,
+    };
+
+    let mut entries = vec![];
+    for (name, plugin) in &plugins.jars {
+        let file = format!("{}/{}", plugins.folder, file_name(name, plugin));
+        let sha256 = plugin.sha256.to_lowercase();
+
+        let status = match lock.find(name) {
+            Some(locked) if locked.sha256 == sha256 && locked.file == file => {
+                match hash_file(&service.path.join(&file)) {
+                    Ok(hash) if hash == sha256 => PluginStatus::Installed,
+                    _ => PluginStatus::Modified,
+                }
+            }
+            _ => PluginStatus::Pending,
+        };
+        entries.push(entry(
+            name,
+            plugin.version.clone(),
+            file,
+            Some(sha256),
+            status,
+        ));
+    }
+
+    for ele in &lock.plugins {
+        if !plugins.jars.contains_key(&ele.name) {
+            entries.push(entry(
+                &ele.name,
+                ele.version.clone(),
+                ele.file.clone(),
+                Some(ele.sha256.clone()),
+                PluginStatus::Undeclared,
+            ));
+        }
+    }
+
+    let folder = service.path.join(plugins.folder.to_string());
+    let mut unmanaged = fs::read_dir(&folder)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|ele| ele.file_type().is_ok_and(|ele| ele.is_file()))
+        .map(|ele| ele.file_name().to_string_lossy().to_string())
+        .filter(|ele| ele.ends_with(".jar"))
+        .map(|ele| format!("{}/{}", plugins.folder, ele))
+        .filter(|file| !entries.iter().any(|ele| ele.file == *file))
+        .collect::<Vec<_>>();
+    unmanaged.sort();
+    for file in unmanaged {
+        let name = file
+            .rsplit('/')
+            .next()
+            .and_then(|ele| ele.strip_suffix(".jar"))
+            .unwrap_or_default()
+            .to_string();
+        let sha256 = hash_file(&service.path.join(&file)).ok();
+        entries.push(entry(&name, None, file, sha256, PluginStatus::Unmanaged));
+    }
+
+    entries
+}
+
+/// Declare `plugin` under `name` in the manifest at `manifest_path`, or remove it when `None`,
+/// keeping the rest of the file as it is.
+pub fn set_plugin(
+    manifest_path: &Path,
+    name: &str,
+    plugin: Option<&ManifestContentPlugin>,
+) -> anyhow::Result<()> {
+    let mut document = fs::read_to_string(manifest_path)?.parse::<Document>()?;
+
+    let plugins = document
+        .entry("plugins")
+        .or_insert(toml_edit::table())
+        .as_table_mut()
+        .ok_or_else(|| anyhow!("[plugins] is not a table"))?;
+    plugins.set_implicit(true);
+    let jars = plugins
+        .entry("jars")
+        .or_insert(toml_edit::table())
+        .as_table_mut()
+        .ok_or_else(|| anyhow!("[plugins.jars] is not a table"))?;
+    jars.set_implicit(true);
+
+    match plugin {
+        Some(plugin) => {
+            let mut table = Table::new();
+            let fields = [
+                ("version", &plugin.version),
+                ("url", &plugin.url),
+                ("path", &plugin.path),
+            ];
+            for (key, field) in fields {
+                if let Some(field) = field {
+                    table[key] = value(field.as_str());
+                }
+            }
+            table["sha256"] = value(plugin.sha256.as_str());
+            jars.insert(name, Item::Table(table));
+        }
+        None => {
+            if jars.remove(name).is_none() {
+                return Err(anyhow!("Plugin {} is not declared", name));
+            }
+        }
+    }
+
+    fs::write(manifest_path, document.to_string())?;
+    Ok(())
+}
+
+fn remove_file(path: &Path) -> anyhow::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+fn lock_path<T>(service_dir: T) -> PathBuf
+where
+    T: Into<PathBuf>,
+{
+    let mut path: PathBuf = service_dir.into();
+    path.push(".smc");
+    path.push("plugins.lock");
+    path
+}
+
+#[cfg(test)]
+#[test]
+fn plugin_test() {
+    use sha2::{Digest, Sha256};
+
+    let dir = tempfile::tempdir().unwrap();
+    let cache = ArtifactCache::new(dir.path().join("cache"));
+    let service_path = dir.path().join("lobby");
+    fs::create_dir_all(service_path.join("plugins")).unwrap();
+    let manifest_path = service_path.join("manifest.toml");
+    fs::write(
+        &manifest_path,
+        "# Lobby\n[scripts]\nhealth_check = \"true\"\nstart = \"true\"\n",
+    )
+    .unwrap();
+
+    fs::write(dir.path().join("perms.jar"), b"perms").unwrap();
+    fs::write(service_path.join("plugins/manual.jar"), b"manual").unwrap();
+
+    let plugin = ManifestContentPlugin {
+        version: Some("5.4".to_string()),
+        url: None,
+        path: Some(dir.path().join("perms.jar").to_string_lossy().to_string()),
+        sha256: hex::encode(Sha256::digest(b"perms")),
+    };
+    set_plugin(&manifest_path, "perms", Some(&plugin)).unwrap();
+    assert!(fs::read_to_string(&manifest_path)
+        .unwrap()
+        .starts_with("# Lobby\n"));
+
+    let service = ServiceInformation::new(&manifest_path).unwrap();
+    assert_eq!(status(&service)[0].status, PluginStatus::Pending);
+    assert_eq!(install(&service, &cache).unwrap(), vec!["perms"]);
+
+    let entries = status(&service);
+    let statuses = entries
+        .iter()
+        .map(|ele| (ele.file.as_str(), ele.status))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        statuses,
+        vec![
+            ("plugins/perms-5.4.jar", PluginStatus::Installed),
+            ("plugins/manual.jar", PluginStatus::Unmanaged)
+        ]
+    );
+
+    set_plugin(&manifest_path, "perms", None).unwrap();
+    let service = ServiceInformation::new(&manifest_path).unwrap();
+    assert_eq!(status(&service)[0].status, PluginStatus::Undeclared);
+    install(&service, &cache).unwrap();
+    assert!(!service_path.join("plugins/perms-5.4.jar").exists());
+    assert!(PluginLock::load(&service_path).plugins.is_empty());
+
+    set_plugin(&manifest_path, "../evil", Some(&plugin)).unwrap();
+    let service = ServiceInformation::new(&manifest_path).unwrap();
+    assert!(install(&service, &cache).is_err());
+    assert!(!dir.path().join("evil-5.4.jar").exists());
+}
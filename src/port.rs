@@ -0,0 +1,171 @@
+use std::{collections::BTreeMap, io::ErrorKind, net::TcpListener, ops::RangeInclusive};
+
+use serde::Serialize;
+
+use crate::{
+    origin::ServiceOrigin,
+    properties::{Properties, PROPERTIES_FILE},
+    service::ServiceInformation,
+    template::MANIFEST_FILE,
+};
+
+/// A port declared by a service.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct PortUse {
+    pub port: u16,
+    pub service: String,
+    /// File the port is declared in, relative to the service directory.
+    pub file: String,
+    pub key: String,
+}
+
+/// Every port declared by the services of the working directory.
+#[derive(Debug, Default, Clone)]
+pub struct PortRegistry {
+    uses: Vec<PortUse>,
+}
+
+impl PortRegistry {
+    pub fn new(services: &[ServiceInformation]) -> PortRegistry {
+        PortRegistry {
+            uses: services.iter().flat_map(declared).collect(),
+        }
+    }
+
+    pub fn uses(&self) -> &[PortUse] {
+        &self.uses
+    }
+
+    /// Services declaring `port`.
+    pub fn services(&self, port: u16) -> Vec<&str> {
+        let mut services = self
+            .uses
+            .iter()
+            .filter(|ele| ele.port == port)
+            .map(|ele| ele.service.as_str())
+            .collect::<Vec<_>>();
+        services.dedup();
+        services
+    }
+
+    /// Ports declared by more than one service.
+    pub fn conflicts(&self) -> BTreeMap<u16, Vec<&PortUse>> {
+        let mut ports = BTreeMap::<u16, Vec<&PortUse>>::new();
+        for ele in &self.uses {
+            ports.entry(ele.port).or_default().push(ele);
+        }
+
+        ports.retain(|port, _| self.services(*port).len() > 1);
+        ports
+    }
+
+    /// First port of `range` no service declares, not bound on the host and not in `taken`.
+    pub fn next_free(&self, range: RangeInclusive<u16>, taken: &[u16]) -> Option<u16> {
+        range.into_iter().find(|port| {
+            self.services(*port).is_empty() && !taken.contains(port) && !is_bound(*port)
+        })
+    }
+}
+
+/// Whether something on the host is listening on `port`.
+pub fn is_bound(port: u16) -> bool {
+    matches!(
+        TcpListener::bind(("0.0.0.0", port)),
+        Err(err) if err.kind() == ErrorKind::AddrInUse
+    )
+}
+
+/// Ports a service declares in its `server.properties`, its manifest and its origin, each port
+/// once.
+fn declared(service: &ServiceInformation) -> Vec<PortUse> {
+    let mut found = vec![];
+
+    let properties = Properties::load(&service.path.join(PROPERTIES_FILE)).unwrap_or_default();
+    let enabled = |key: &str| properties.get(key) == Some("true");
+    for (key, used) in [
+        ("server-port", true),
+        ("query.port", enabled("enable-query")),
+        ("rcon.port", enabled("enable-rcon")),
+    ] {
+        if let Some(port) = properties.get(key).filter(|_| used) {
+            found.push((port.trim().parse().ok(), PROPERTIES_FILE, key.to_string()));
+        }
+    }
+
+    for (key, value) in &service.manifest.properties {
+        if ["server-port", "query.port", "rcon.port"].contains(&key.as_str()) {
+            let key = format!("properties.{}", key);
+            found.push((value.to_string().parse().ok(), MANIFEST_FILE, key));
+        }
+    }
+    if let Some(rcon) = &service.manifest.rcon {
+        found.push((Some(rcon.port), MANIFEST_FILE, "rcon.port".to_string()));
+    }
+
+    for (key, port) in ServiceOrigin::load(&service.path)
+        .map(|ele| ele.ports)
+        .unwrap_or_default()
+    {
+        found.push((Some(port), ".smc/origin.toml", format!("ports.{}", key)));
+    }
+
+    let mut uses: Vec<PortUse> = vec![];
+    for (port, file, key) in found {
+        let Some(port) = port else {
+            continue;
+        };
+        if uses.iter().any(|ele| ele.port == port) {
+            continue;
+        }
+        uses.push(PortUse {
+            port,
+            service: service.name.clone(),
+            file: file.to_string(),
+            key,
+        });
+    }
+
+    uses
+}
+
+#[cfg(test)]
+#[test]
+fn port_registry_test() {
+    use std::fs;
+
+    let dir = tempfile::tempdir().unwrap();
+    let service = |name: &str, properties: &str| {
+        let path = dir.path().join(name);
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join(PROPERTIES_FILE), properties).unwrap();
+        ServiceInformation {
+            name: name.to_string(),
+            path,
+            ..Default::default()
+        }
+    };
+
+    let services = [
+        service(
+            "lobby",
+            "server-port=30000\nquery.port=30000\nrcon.port=30001\n",
+        ),
+        service(
+            "survival",
+            "server-port=30000\nenable-rcon=true\nrcon.port=30002\n",
+        ),
+    ];
+    let registry = PortRegistry::new(&services);
+
+    assert_eq!(registry.uses().len(), 3);
+    let conflicts = registry.conflicts();
+    assert_eq!(conflicts.keys().collect::<Vec<_>>(), vec![&30000]);
+    assert_eq!(registry.services(30000), vec!["lobby", "survival"]);
+
+    // Hold a port so that it is bound on the host.
+    let listener = TcpListener::bind(("0.0.0.0", 0)).unwrap();
+    let bound = listener.local_addr().unwrap().port();
+    assert!(is_bound(bound));
+    assert_eq!(registry.next_free(bound..=bound, &[]), None);
+    assert_eq!(registry.next_free(30000..=30003, &[30001]), Some(30003));
+}
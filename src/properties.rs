@@ -0,0 +1,311 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    fs,
+    path::Path,
+};
+
+use anyhow::anyhow;
+
+use crate::manifest::ManifestContent;
+
+pub const PROPERTIES_FILE: &str = "server.properties";
+
+const PORT_KEYS: [&str; 3] = ["server-port", "query.port", "rcon.port"];
+
+const BOOLEAN_KEYS: [&str; 20] = [
+    "allow-flight",
+    "allow-nether",
+    "broadcast-console-to-ops",
+    "broadcast-rcon-to-ops",
+    "enable-command-block",
+    "enable-jmx-monitoring",
+    "enable-query",
+    "enable-rcon",
+    "enable-status",
+    "enforce-secure-profile",
+    "enforce-whitelist",
+    "force-gamemode",
+    "generate-structures",
+    "hardcore",
+    "hide-online-players",
+    "online-mode",
+    "prevent-proxy-connections",
+    "pvp",
+    "spawn-monsters",
+    "white-list",
+];
+
+const ENUM_KEYS: [(&str, &[&str]); 3] = [
+    (
+        "gamemode",
+        &["survival", "creative", "adventure", "spectator"],
+    ),
+    ("difficulty", &["peaceful", "easy", "normal", "hard"]),
+    (
+        "level-type",
+        &[
+            "minecraft:normal",
+            "minecraft:flat",
+            "minecraft:large_biomes",
+            "minecraft:amplified",
+            "minecraft:single_biome_surface",
+            "normal",
+            "flat",
+            "large_biomes",
+            "amplified",
+            "default",
+        ],
+    ),
+];
+
+const RANGE_KEYS: [(&str, i64, i64); 5] = [
+    ("max-players", 0, i32::MAX as i64),
+    ("view-distance", 2, 32),
+    ("simulation-distance", 2, 32),
+    ("spawn-protection", 0, i32::MAX as i64),
+    ("op-permission-level", 0, 4),
+];
+
+/// A `server.properties` file, keeping comments, blank lines and the order of the keys so that
+/// it is written back the way it was read.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Properties {
+    lines: Vec<Line>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Line {
+    Entry {
+        key: String,
+        value: String,
+        /// The line as it was read, until the value is changed.
+        raw: Option<String>,
+    },
+    Other(String),
+}
+
+impl Properties {
+    pub fn parse(content: &str) -> Properties {
+        let lines = content
+            .lines()
+            .map(|line| {
+                let trimmed = line.trim_start();
+                if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with('!') {
+                    return Line::Other(line.to_string());
+                }
+                match line.split_once('=') {
+                    Some((key, value)) => Line::Entry {
+                        key: key.trim().to_string(),
+                        value: value.trim_start().to_string(),
+                        raw: Some(line.to_string()),
+                    },
+                    None => Line::Other(line.to_string()),
+                }
+            })
+            .collect();
+
+        Properties { lines }
+    }
+
+    /// Read a file, an empty set of properties if it does not exist.
+    pub fn load(path: &Path) -> anyhow::Result<Properties> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Properties::parse(&content)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Properties::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.lines.iter().find_map(|ele| match ele {
+            Line::Entry {
+                key: name, value, ..
+            } if name == key => Some(value.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Every key with its value, in file order.
+    pub fn entries(&self) -> Vec<(&str, &str)> {
+        self.lines
+            .iter()
+            .filter_map(|ele| match ele {
+                Line::Entry { key, value, .. } => Some((key.as_str(), value.as_str())),
+                Line::Other(_) => None,
+            })
+            .collect()
+    }
+
+    /// Change the value of `key` where it is, or append it. Returns whether anything changed.
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        for ele in self.lines.iter_mut() {
+            if let Line::Entry {
+                key: name,
+                value: current,
+                raw,
+            } = ele
+            {
+                if name == key {
+                    if current == value {
+                        return false;
+                    }
+                    *current = value.to_string();
+                    *raw = None;
+                    return true;
+                }
+            }
+        }
+
+        self.lines.push(Line::Entry {
+            key: key.to_string(),
+            value: value.to_string(),
+            raw: None,
+        });
+        true
+    }
+}
+
+impl Display for Properties {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for ele in &self.lines {
+            match ele {
+                Line::Entry { raw: Some(raw), .. } | Line::Other(raw) => writeln!(f, "{}", raw)?,
+                Line::Entry { key, value, .. } => writeln!(f, "{}={}", key, value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check the value of a key the server is known to read. Unknown keys are accepted as they are,
+/// as long as the entry fits on one line.
+pub fn validate(key: &str, value: &str) -> anyhow::Result<()> {
+    if key.contains(['\n', '\r']) || value.contains(['\n', '\r']) {
+        return Err(anyhow!("Property {:?} cannot contain a line break", key));
+    }
+
+    if PORT_KEYS.contains(&key) {
+        return match value.parse::<u16>() {
+            Ok(port) if port > 0 => Ok(()),
+            _ => Err(anyhow!(
+                "{} must be a port between 1 and 65535, not `{}`",
+                key,
+                value
+            )),
+        };
+    }
+
+    if BOOLEAN_KEYS.contains(&key) && value != "true" && value != "false" {
+        return Err(anyhow!("{} must be true or false, not `{}`", key, value));
+    }
+
+    if let Some((_, allowed)) = ENUM_KEYS.iter().find(|(name, _)| *name == key) {
+        if !allowed.contains(&value) {
+            return Err(anyhow!(
+                "{} must be one of {}, not `{}`",
+                key,
+                allowed.join(", "),
+                value
+            ));
+        }
+    }
+
+    if let Some((_, min, max)) = RANGE_KEYS.iter().find(|(name, _, _)| *name == key) {
+        match value.parse::<i64>() {
+            Ok(number) if (*min..=*max).contains(&number) => {}
+            _ => {
+                return Err(anyhow!(
+                    "{} must be a number between {} and {}, not `{}`",
+                    key,
+                    min,
+                    max,
+                    value
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the `[properties]` of a manifest into the `server.properties` of `service_dir`.
+/// Returns the keys that changed.
+pub fn apply(manifest: &ManifestContent, service_dir: &Path) -> anyhow::Result<Vec<String>> {
+    if manifest.properties.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let path = service_dir.join(PROPERTIES_FILE);
+    let mut properties = Properties::load(&path)?;
+
+    let mut changed = vec![];
+    for (key, value) in &manifest.properties {
+        let value = value.to_string();
+        validate(key, &value)?;
+        if properties.set(key, &value) {
+            changed.push(key.clone());
+        }
+    }
+
+    if !changed.is_empty() {
+        properties.save(&path)?;
+    }
+    Ok(changed)
+}
+
+/// Keys whose values are not the same in every set of properties, with the value in each.
+pub fn diff(properties: &[Properties]) -> BTreeMap<String, Vec<Option<String>>> {
+    let mut values = BTreeMap::<String, Vec<Option<String>>>::new();
+    for (index, ele) in properties.iter().enumerate() {
+        for (key, value) in ele.entries() {
+            values
+                .entry(key.to_string())
+                .or_insert_with(|| vec![None; properties.len()])[index] = Some(value.to_string());
+        }
+    }
+
+    values.retain(|_, values| values.iter().any(|ele| *ele != values[0]));
+    values
+}
+
+#[cfg(test)]
+#[test]
+fn properties_test() {
+    let content = "#Minecraft server properties\nmotd = A Server\n\nserver-port=25565\npvp=true\n";
+    let mut properties = Properties::parse(content);
+    assert_eq!(properties.to_string(), content);
+    assert_eq!(properties.get("server-port"), Some("25565"));
+
+    assert!(properties.set("server-port", "25566"));
+    assert!(!properties.set("pvp", "true"));
+    assert!(properties.set("gamemode", "creative"));
+    assert_eq!(
+        properties.to_string(),
+        "#Minecraft server properties\nmotd = A Server\n\nserver-port=25566\npvp=true\ngamemode=creative\n"
+    );
+
+    assert!(validate("server-port", "70000").is_err());
+    assert!(validate("pvp", "yes").is_err());
+    assert!(validate("gamemode", "hardcore").is_err());
+    assert!(validate("view-distance", "10").is_ok());
+    assert!(validate("motd", "anything").is_ok());
+    assert!(validate("motd", "a\nop=true").is_err());
+    assert!(validate("motd\r", "anything").is_err());
+
+    let other = Properties::parse("motd=A Server\nserver-port=25565\n");
+    let diff = diff(&[properties, other]);
+    assert_eq!(
+        diff.keys().collect::<Vec<_>>(),
+        vec!["gamemode", "pvp", "server-port"]
+    );
+    assert_eq!(
+        diff["server-port"],
+        vec![Some("25566".to_string()), Some("25565".to_string())]
+    );
+}
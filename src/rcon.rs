@@ -0,0 +1,131 @@
+use std::{future::Future, time::Duration};
+
+use anyhow::anyhow;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// How long connecting or waiting for a packet may take before giving up on the server.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const PACKET_RESPONSE: i32 = 0;
+const PACKET_COMMAND: i32 = 2;
+const PACKET_AUTH: i32 = 3;
+
+/// Minimal client of the Source RCON protocol spoken by Minecraft servers.
+#[derive(Debug)]
+pub struct Rcon {
+    stream: TcpStream,
+    request_id: i32,
+}
+
+impl Rcon {
+    pub async fn connect<T>(addr: T, password: &str) -> anyhow::Result<Rcon>
+    where
+        T: tokio::net::ToSocketAddrs,
+    {
+        let mut rcon = Rcon {
+            stream: timed(TcpStream::connect(addr)).await??,
+            request_id: 0,
+        };
+
+        let request_id = rcon.send(PACKET_AUTH, password).await?;
+
+        // Some servers send an empty response packet before the auth response.
+        loop {
+            let (id, kind, _) = rcon.receive().await?;
+            if kind != PACKET_COMMAND {
+                continue;
+            }
+            if id != request_id {
+                return Err(anyhow!("RCON authentication failed"));
+            }
+            return Ok(rcon);
+        }
+    }
+
+    pub async fn command(&mut self, command: &str) -> anyhow::Result<String> {
+        let request_id = self.send(PACKET_COMMAND, command).await?;
+
+        loop {
+            let (id, kind, body) = self.receive().await?;
+            if id == request_id && kind == PACKET_RESPONSE {
+                return Ok(body);
+            }
+        }
+    }
+
+    async fn send(&mut self, kind: i32, body: &str) -> anyhow::Result<i32> {
+        self.request_id += 1;
+
+        let mut packet = vec![];
+        packet.extend(((body.len() + 10) as i32).to_le_bytes());
+        packet.extend(self.request_id.to_le_bytes());
+        packet.extend(kind.to_le_bytes());
+        packet.extend(body.as_bytes());
+        packet.extend([0, 0]);
+
+        self.stream.write_all(&packet).await?;
+
+        Ok(self.request_id)
+    }
+
+    async fn receive(&mut self) -> anyhow::Result<(i32, i32, String)> {
+        timed(self.read_packet()).await?
+    }
+
+    async fn read_packet(&mut self) -> anyhow::Result<(i32, i32, String)> {
+        let length = self.stream.read_i32_le().await?;
+        if !(10..=4110).contains(&length) {
+            return Err(anyhow!("Invalid RCON packet length {}", length));
+        }
+
+        let id = self.stream.read_i32_le().await?;
+        let kind = self.stream.read_i32_le().await?;
+
+        let mut body = vec![0; length as usize - 8];
+        self.stream.read_exact(&mut body).await?;
+        body.truncate(body.len() - 2);
+
+        Ok((id, kind, String::from_utf8_lossy(&body).to_string()))
+    }
+}
+
+async fn timed<T>(future: impl Future<Output = T>) -> anyhow::Result<T> {
+    tokio::time::timeout(TIMEOUT, future)
+        .await
+        .map_err(|_| anyhow!("RCON server did not answer within {}s", TIMEOUT.as_secs()))
+}
+
+#[cfg(test)]
+#[test]
+fn rcon_test() {
+    use tokio::net::TcpListener;
+
+    let rt = tokio::runtime::Runtime::new().expect("Cannot initializing Tokio runtime");
+    rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Echo every command back, like `say` would, after accepting the password `secret`.
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server = Rcon {
+                stream,
+                request_id: 0,
+            };
+
+            let (id, _, password) = server.receive().await.unwrap();
+            server.request_id = if password == "secret" { id - 1 } else { -2 };
+            server.send(PACKET_COMMAND, "").await.unwrap();
+
+            let (id, _, command) = server.receive().await.unwrap();
+            server.request_id = id - 1;
+            server.send(PACKET_RESPONSE, &command).await.unwrap();
+        });
+
+        let mut rcon = Rcon::connect(addr, "secret").await.unwrap();
+        assert_eq!(rcon.command("say hello").await.unwrap(), "say hello");
+    });
+}
@@ -0,0 +1,372 @@
+use std::{
+    cmp::Ordering,
+    fmt::{self, Display, Formatter},
+    fs::{self, File},
+    path::{Component, Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Local};
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+
+/// Name of the file recording the state of a source, written at the root of its directory.
+pub const SOURCE_STATE_FILE: &str = ".smc-source.toml";
+
+pub const DEFAULT_SOURCE: &str = "https://github.com/OakMemory/smcmanager-templates";
+
+/// Content of `sources.toml` in the working directory.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SourcesConfig {
+    #[serde(default, rename = "source")]
+    pub sources: Vec<TemplateSource>,
+}
+
+/// Where the templates of a source come from. Every source is fetched into
+/// `templates/<name>/`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TemplateSource {
+    pub name: String,
+    #[serde(flatten)]
+    pub location: SourceLocation,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum SourceLocation {
+    Git {
+        git: String,
+        #[serde(rename = "ref")]
+        reference: Option<String>,
+    },
+    Local {
+        path: PathBuf,
+    },
+    Tarball {
+        tarball: PathBuf,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SourceState {
+    pub name: String,
+    pub location: String,
+    pub reference: Option<String>,
+    pub commit: Option<String>,
+    pub updated: DateTime<Local>,
+}
+
+impl SourcesConfig {
+    pub fn load<T>(path: T) -> anyhow::Result<SourcesConfig>
+    where
+        T: Into<PathBuf>,
+    {
+        let path: PathBuf = path.into();
+        if !path.exists() {
+            return Ok(SourcesConfig::default());
+        }
+
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save<T>(&self, path: T) -> anyhow::Result<()>
+    where
+        T: Into<PathBuf>,
+    {
+        fs::write(path.into(), toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// The configuration written by `init`, pointing at the official templates.
+    pub fn official() -> SourcesConfig {
+        SourcesConfig {
+            sources: vec![TemplateSource {
+                name: "official".to_string(),
+                location: SourceLocation::Git {
+                    git: DEFAULT_SOURCE.to_string(),
+                    reference: None,
+                },
+            }],
+        }
+    }
+}
+
+impl TemplateSource {
+    /// Fetch the source into `templates_dir/<name>` and record its state there.
+    pub fn update<T>(&self, templates_dir: T) -> anyhow::Result<SourceState>
+    where
+        T: Into<PathBuf>,
+    {
+        let mut components = Path::new(&self.name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(anyhow!("{} is not a valid source name", self.name));
+        }
+
+        let templates_dir: PathBuf = templates_dir.into();
+        let target = templates_dir.join(&self.name);
+
+        // Existing checkouts of the same repository are updated in place, a failed fetch leaves
+        // them as they were. Everything else is fetched next to the target and only replaces it
+        // on success.
+        let in_place = match &self.location {
+            SourceLocation::Git { git, .. } => {
+                target.join(".git").exists()
+                    && run_git(&target, &["remote", "get-url", "origin"])
+                        .is_ok_and(|url| url == *git)
+            }
+            _ => false,
+        };
+        let staging = if in_place {
+            target.clone()
+        } else {
+            let staging = templates_dir.join(format!(".{}.staging", self.name));
+            replace_dir(&staging)?;
+            staging
+        };
+
+        let fetched = self.fetch(&staging);
+        if !in_place {
+            match fetched {
+                Ok(_) => {
+                    if target.exists() {
+                        fs::remove_dir_all(&target)?;
+                    }
+                    fs::rename(&staging, &target)?;
+                }
+                Err(_) => fs::remove_dir_all(&staging)?,
+            }
+        }
+        let (location, reference, commit) = fetched?;
+
+        let state = SourceState {
+            name: self.name.clone(),
+            location,
+            reference,
+            commit,
+            updated: Local::now(),
+        };
+
+        fs::write(
+            target.join(SOURCE_STATE_FILE),
+            toml::to_string_pretty(&state)?,
+        )?;
+
+        Ok(state)
+    }
+
+    /// Fetch the source into `target`, returning its location, reference and commit.
+    fn fetch(&self, target: &Path) -> anyhow::Result<(String, Option<String>, Option<String>)> {
+        match &self.location {
+            SourceLocation::Git { git, reference } => {
+                if target.join(".git").exists() {
+                    run_git(target, &["fetch", "--tags", "origin"])?;
+                } else {
+                    run_git(target, &["clone", git, "."])?;
+                }
+
+                // Branches are checked out from the remote so that updates are picked up, tags
+                // and commits as they are.
+                let reference = reference.clone();
+                let remote = format!("origin/{}", reference.as_deref().unwrap_or("HEAD"));
+                run_git(target, &["checkout", "--detach", &remote]).or_else(
+                    |err| match &reference {
+                        Some(reference) => run_git(target, &["checkout", "--detach", reference]),
+                        None => Err(err),
+                    },
+                )?;
+
+                let commit = run_git(target, &["rev-parse", "HEAD"])?;
+                Ok((git.clone(), reference, Some(commit)))
+            }
+            SourceLocation::Local { path } => {
+                fs_extra::dir::copy(
+                    path,
+                    target,
+                    &fs_extra::dir::CopyOptions::new().content_only(true),
+                )?;
+                Ok((path.display().to_string(), None, None))
+            }
+            SourceLocation::Tarball { tarball } => {
+                let file = File::open(tarball)?;
+                if tarball
+                    .extension()
+                    .is_some_and(|ele| ele == "gz" || ele == "tgz")
+                {
+                    tar::Archive::new(GzDecoder::new(file)).unpack(target)?;
+                } else {
+                    tar::Archive::new(file).unpack(target)?;
+                }
+                Ok((tarball.display().to_string(), None, None))
+            }
+        }
+    }
+}
+
+impl Display for SourceLocation {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            SourceLocation::Git { git, .. } => write!(f, "{}", git),
+            SourceLocation::Local { path } => write!(f, "{}", path.display()),
+            SourceLocation::Tarball { tarball } => write!(f, "{}", tarball.display()),
+        }
+    }
+}
+
+impl SourceLocation {
+    pub fn reference(&self) -> Option<&str> {
+        match self {
+            SourceLocation::Git { reference, .. } => reference.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+impl SourceState {
+    pub fn load<T>(source_dir: T) -> Option<SourceState>
+    where
+        T: Into<PathBuf>,
+    {
+        let mut path: PathBuf = source_dir.into();
+        path.push(SOURCE_STATE_FILE);
+
+        toml::from_str(&fs::read_to_string(path).ok()?).ok()
+    }
+}
+
+/// Split a template reference like `paper@1.20` into its name and version.
+pub fn split_version(spec: &str) -> (&str, Option<&str>) {
+    match spec.split_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (spec, None),
+    }
+}
+
+/// Compare versions segment by segment, numerically where both segments are numbers, so that
+/// `1.9` sorts before `1.20`.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut a = a.split(['.', '-']);
+    let mut b = b.split(['.', '-']);
+
+    loop {
+        let ordering = match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a), Some(b)) => match (a.parse::<u64>(), b.parse::<u64>()) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                _ => a.cmp(b),
+            },
+        };
+
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+fn replace_dir(path: &Path) -> anyhow::Result<()> {
+    if path.exists() {
+        fs::remove_dir_all(path)?;
+    }
+    fs::create_dir_all(path)?;
+    Ok(())
+}
+
+pub fn run_git(dir: &Path, args: &[&str]) -> anyhow::Result<String> {
+    let output = git_output(dir, args)?;
+    Ok(String::from_utf8_lossy(&output).trim().to_string())
+}
+
+/// Raw standard output of a git command, for file content.
+pub fn git_output(dir: &Path, args: &[&str]) -> anyhow::Result<Vec<u8>> {
+    let output = Command::new("git").args(args).current_dir(dir).output()?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+#[test]
+fn compare_versions_test() {
+    assert_eq!(compare_versions("1.9", "1.20"), Ordering::Less);
+    assert_eq!(compare_versions("1.20.1", "1.20"), Ordering::Greater);
+    assert_eq!(compare_versions("1.20", "1.20"), Ordering::Equal);
+    assert_eq!(split_version("paper@1.20"), ("paper", Some("1.20")));
+    assert_eq!(split_version("paper"), ("paper", None));
+
+    let dir = tempfile::tempdir().unwrap();
+    let source = |name: &str, path: &str| TemplateSource {
+        name: name.to_string(),
+        location: SourceLocation::Local {
+            path: dir.path().join(path),
+        },
+    };
+    let templates = dir.path().join("templates");
+    fs::create_dir_all(dir.path().join("local")).unwrap();
+    fs::write(dir.path().join("local/manifest.toml"), "").unwrap();
+    source("local", "local").update(&templates).unwrap();
+    assert!(templates.join("local/manifest.toml").exists());
+
+    assert!(source("local", "missing").update(&templates).is_err());
+    assert!(templates.join("local/manifest.toml").exists());
+    assert!(!templates.join(".local.staging").exists());
+    assert!(source("../local", "local").update(&templates).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn git_source_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let repository = |name: &str| {
+        let path = dir.path().join(name);
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("manifest.toml"), name).unwrap();
+        for args in [
+            &["init", "-q"][..],
+            &["add", "-A"],
+            &[
+                "-c",
+                "user.name=smc",
+                "-c",
+                "user.email=smc@localhost",
+                "commit",
+                "-qm",
+                name,
+            ],
+        ] {
+            run_git(&path, args).unwrap();
+        }
+        path.display().to_string()
+    };
+    let source = |git: String| TemplateSource {
+        name: "official".to_string(),
+        location: SourceLocation::Git {
+            git,
+            reference: None,
+        },
+    };
+
+    let templates = dir.path().join("templates");
+    let first = repository("first");
+    source(first.clone()).update(&templates).unwrap();
+    source(first).update(&templates).unwrap();
+
+    let second = repository("second");
+    let state = source(second.clone()).update(&templates).unwrap();
+    assert_eq!(state.location, second);
+    assert_eq!(
+        fs::read_to_string(templates.join("official/manifest.toml")).unwrap(),
+        "second"
+    );
+}
@@ -0,0 +1,418 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use chrono::{DateTime, Local};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::{
+    backup::RestoreReport,
+    error::Error,
+    origin::ServiceOrigin,
+    scaffold::Replacement,
+    service::{
+        ReloadResult, RestartResult, ServiceInformation, ServiceStatus, StartResult, StopResult,
+    },
+    upgrade::MergeOutcome,
+};
+
+/// Version of the `--json` output. Bump it when a field is renamed, removed or changes type,
+/// adding a field does not need a new version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Directory of the JSON Schema files, one per command.
+#[cfg(test)]
+const SCHEMA_DIRECTORY: &str = "schemas";
+
+/// What every command prints with `--json`.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Envelope<T> {
+    pub schema_version: u32,
+    /// Subcommands separated by spaces, such as `backup create`.
+    pub command: String,
+    pub results: Vec<T>,
+    /// Items the command failed on. A command that failed as a whole reports its error on
+    /// stderr, in an envelope without results.
+    pub errors: Vec<ErrorEntry>,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(command: &str, results: Vec<T>, errors: Vec<ErrorEntry>) -> Self {
+        Envelope {
+            schema_version: SCHEMA_VERSION,
+            command: command.to_string(),
+            results,
+            errors,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ErrorEntry {
+    /// Service, template, source or file the error is about.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// One of `usage`, `not_found`, `invalid`, `service` and `io`.
+    pub kind: String,
+    /// Exit code of the process for this kind of error.
+    pub code: i32,
+    pub message: String,
+}
+
+impl ErrorEntry {
+    pub fn new(target: Option<&str>, error: &Error) -> Self {
+        ErrorEntry {
+            target: target.map(str::to_string),
+            kind: error.kind().to_string(),
+            code: error.exit_code(),
+            message: error.message().to_string(),
+        }
+    }
+}
+
+/// Result of `list` and `status`.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ServiceEntry {
+    pub service: String,
+    pub path: PathBuf,
+    pub status: ServiceStatus,
+    /// Why the manifest cannot be loaded, for `Invalid` services, or why the health check cannot
+    /// tell the status, for `Unknow` services.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Template the service was created from, with `list --verbose`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<OriginEntry>,
+    /// Files changed since the service was created, with `list --verbose`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified: Option<Vec<String>>,
+}
+
+impl From<ServiceInformation> for ServiceEntry {
+    fn from(service: ServiceInformation) -> Self {
+        ServiceEntry {
+            service: service.name,
+            path: service.path,
+            status: service.status,
+            error: service.error,
+            origin: None,
+            modified: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct OriginEntry {
+    pub template: String,
+    pub version: Option<String>,
+    pub source: Option<String>,
+    pub commit: Option<String>,
+    pub created: DateTime<Local>,
+    pub vars: BTreeMap<String, String>,
+    /// Number of files copied from the template.
+    pub files: usize,
+}
+
+impl From<ServiceOrigin> for OriginEntry {
+    fn from(origin: ServiceOrigin) -> Self {
+        OriginEntry {
+            template: origin.template,
+            version: origin.version,
+            source: origin.source,
+            commit: origin.commit,
+            created: origin.created,
+            vars: origin.vars,
+            files: origin.files.len(),
+        }
+    }
+}
+
+/// Result of `status --watch`, printed on its own line whenever the status of a service changes.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct StatusChange {
+    pub time: DateTime<Local>,
+    pub service: String,
+    pub previous: Option<ServiceStatus>,
+    pub status: ServiceStatus,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct StartEntry {
+    pub service: String,
+    pub result: StartResult,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct StopEntry {
+    pub service: String,
+    pub result: StopResult,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct RestartEntry {
+    pub service: String,
+    /// Batch of the service, with `restart --rolling`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batch: Option<usize>,
+    pub result: RestartResult,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ReloadEntry {
+    pub service: String,
+    pub result: ReloadResult,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct TemplateEntry {
+    pub template: String,
+    pub version: Option<String>,
+    pub source: Option<String>,
+    pub extends: Option<String>,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct TemplateCreated {
+    pub path: PathBuf,
+    /// Number of files in the new template.
+    pub files: usize,
+    /// Values replaced with placeholders, with `--from-service`.
+    pub placeholders: Vec<Replacement>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct TemplateDeleted {
+    pub template: String,
+}
+
+/// Result of `template update` and `init`.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct SourceUpdated {
+    pub source: String,
+    pub commit: Option<String>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct SourceEntry {
+    pub source: String,
+    pub location: String,
+    pub reference: Option<String>,
+    pub commit: Option<String>,
+    /// `None` when the source was never fetched.
+    pub updated: Option<DateTime<Local>>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ServiceCreated {
+    pub service: String,
+    pub template: String,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ServiceDeleted {
+    pub service: String,
+    /// The service was running and has been stopped.
+    pub stopped: bool,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct UpgradeEntry {
+    pub service: String,
+    pub from: String,
+    pub to: String,
+    pub files: Vec<FileOutcome>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct FileOutcome {
+    pub file: String,
+    pub outcome: MergeOutcome,
+}
+
+/// Result of `props get`.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PropertyEntry {
+    pub service: String,
+    pub key: String,
+    pub value: String,
+    /// Set by `[properties]` in the manifest.
+    pub managed: bool,
+}
+
+/// Result of `props set`.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PropertyChange {
+    pub service: String,
+    pub key: String,
+    pub previous: Option<String>,
+    pub value: String,
+}
+
+/// Result of `props diff`.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PropertyDiff {
+    pub key: String,
+    /// Value of the property by service or template, `None` when it is not set.
+    pub values: BTreeMap<String, Option<String>>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct JobEntry {
+    pub service: String,
+    pub cron: String,
+    pub action: String,
+    /// `None` when the job never runs again or its cron expression is invalid.
+    pub next_run: Option<DateTime<Local>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct BackupCreated {
+    pub service: String,
+    pub snapshot: String,
+    pub files: usize,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct SnapshotEntry {
+    pub service: String,
+    pub snapshot: String,
+    pub created: DateTime<Local>,
+    pub incremental: bool,
+    pub label: Option<String>,
+    pub files: usize,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct RestoreEntry {
+    pub service: String,
+    #[serde(flatten)]
+    pub report: RestoreReport,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct VerifyEntry {
+    pub service: String,
+    pub snapshot: String,
+    pub ok: bool,
+    pub problems: Vec<String>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PruneEntry {
+    pub service: String,
+    /// Snapshots that were removed.
+    pub pruned: Vec<String>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct JavaCommandEntry {
+    pub service: String,
+    pub command: String,
+}
+
+/// Schema of the output of every command, keyed by command.
+#[cfg(test)]
+fn schemas() -> Vec<(&'static str, schemars::Schema)> {
+    use schemars::schema_for;
+
+    use crate::{
+        doctor::Finding, java::JavaInstallation, lint::LintIssue, plugin::PluginEntry,
+        schedule::ScheduleRecord,
+    };
+
+    vec![
+        ("list", schema_for!(Envelope<ServiceEntry>)),
+        ("start", schema_for!(Envelope<StartEntry>)),
+        ("stop", schema_for!(Envelope<StopEntry>)),
+        ("restart", schema_for!(Envelope<RestartEntry>)),
+        ("reload", schema_for!(Envelope<ReloadEntry>)),
+        ("status", schema_for!(Envelope<ServiceEntry>)),
+        ("status --watch", schema_for!(Envelope<StatusChange>)),
+        ("template create", schema_for!(Envelope<TemplateCreated>)),
+        ("template list", schema_for!(Envelope<TemplateEntry>)),
+        ("template delete", schema_for!(Envelope<TemplateDeleted>)),
+        ("template update", schema_for!(Envelope<SourceUpdated>)),
+        ("template sources", schema_for!(Envelope<SourceEntry>)),
+        ("template lint", schema_for!(Envelope<LintIssue>)),
+        ("service lint", schema_for!(Envelope<LintIssue>)),
+        ("create", schema_for!(Envelope<ServiceCreated>)),
+        ("delete", schema_for!(Envelope<ServiceDeleted>)),
+        ("upgrade", schema_for!(Envelope<UpgradeEntry>)),
+        ("plugin add", schema_for!(Envelope<PluginEntry>)),
+        ("plugin remove", schema_for!(Envelope<PluginEntry>)),
+        ("plugin list", schema_for!(Envelope<PluginEntry>)),
+        ("props get", schema_for!(Envelope<PropertyEntry>)),
+        ("props set", schema_for!(Envelope<PropertyChange>)),
+        ("props diff", schema_for!(Envelope<PropertyDiff>)),
+        ("schedule list", schema_for!(Envelope<JobEntry>)),
+        ("schedule history", schema_for!(Envelope<ScheduleRecord>)),
+        ("backup create", schema_for!(Envelope<BackupCreated>)),
+        ("backup list", schema_for!(Envelope<SnapshotEntry>)),
+        ("backup restore", schema_for!(Envelope<RestoreEntry>)),
+        ("backup verify", schema_for!(Envelope<VerifyEntry>)),
+        ("backup prune", schema_for!(Envelope<PruneEntry>)),
+        ("java list", schema_for!(Envelope<JavaInstallation>)),
+        ("java command", schema_for!(Envelope<JavaCommandEntry>)),
+        ("doctor", schema_for!(Envelope<Finding>)),
+        ("daemon", schema_for!(Envelope<ScheduleRecord>)),
+        ("init", schema_for!(Envelope<SourceUpdated>)),
+    ]
+}
+
+/// The schema files must match the Rust types. Run with `UPDATE_SCHEMAS=1` to write them.
+#[cfg(test)]
+#[test]
+fn response_test() {
+    let directory = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(SCHEMA_DIRECTORY);
+    let update = std::env::var_os("UPDATE_SCHEMAS").is_some();
+
+    for (command, schema) in schemas() {
+        let path = directory.join(format!(
+            "{}.json",
+            command
+                .split_whitespace()
+                .map(|ele| ele.trim_start_matches('-'))
+                .collect::<Vec<_>>()
+                .join("-")
+        ));
+        let schema = serde_json::to_string_pretty(&schema).expect("Cannot serialized into json");
+
+        if update {
+            std::fs::create_dir_all(&directory).expect("Cannot create schema directory");
+            std::fs::write(&path, format!("{}\n", schema)).expect("Cannot write schema");
+            continue;
+        }
+
+        let written = std::fs::read_to_string(&path)
+            .unwrap_or_else(|_| panic!("Missing {}, run with UPDATE_SCHEMAS=1", path.display()));
+        assert_eq!(
+            written.trim_end(),
+            schema,
+            "{} is outdated, run with UPDATE_SCHEMAS=1",
+            path.display()
+        );
+    }
+
+    let error = Error::NotFound("No such service found: lobby".to_string());
+    let envelope =
+        Envelope::<StopEntry>::new("stop", vec![], vec![ErrorEntry::new(Some("lobby"), &error)]);
+    assert_eq!(
+        serde_json::to_value(envelope).unwrap(),
+        serde_json::json!({
+            "schema_version": SCHEMA_VERSION,
+            "command": "stop",
+            "results": [],
+            "errors": [{
+                "target": "lobby",
+                "kind": "not_found",
+                "code": 3,
+                "message": "No such service found: lobby"
+            }]
+        })
+    );
+}
@@ -0,0 +1,275 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use schemars::JsonSchema;
+use serde::Serialize;
+use toml::Value;
+use walkdir::WalkDir;
+
+use crate::{
+    manifest::{ManifestContentVariable, VariableType},
+    service::ServiceInformation,
+    template::MANIFEST_FILE,
+};
+
+/// Left out when turning a service into a template, in gitignore syntax. Directories holding a
+/// `level.dat` are worlds and are always left out as well.
+pub const DEFAULT_IGNORE: [&str; 13] = [
+    ".smc/",
+    "world/",
+    "world_nether/",
+    "world_the_end/",
+    "logs/",
+    "cache/",
+    "crash-reports/",
+    "libraries/",
+    "versions/",
+    "*.log",
+    "*.log.gz",
+    "usercache.json",
+    "session.lock",
+];
+
+/// A value of the service that can become a `{VAR}` placeholder.
+#[derive(Debug, Serialize, JsonSchema, Clone)]
+pub struct Replacement {
+    pub var: String,
+    pub value: String,
+    /// Occurrences in the copied files.
+    pub occurrences: usize,
+}
+
+/// Build the ignore rules from the defaults and `extra` patterns.
+pub fn ignore_rules(root: &Path, extra: &[String]) -> anyhow::Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in DEFAULT_IGNORE
+        .iter()
+        .copied()
+        .chain(extra.iter().map(String::as_str))
+    {
+        builder.add_line(None, pattern)?;
+    }
+    Ok(builder.build()?)
+}
+
+/// Copy the service directory into `target`, skipping what `ignore` matches and worlds, and
+/// return the copied files relative to `target`.
+pub fn copy_service(
+    service: &ServiceInformation,
+    target: &Path,
+    ignore: &Gitignore,
+) -> anyhow::Result<Vec<String>> {
+    let walk = WalkDir::new(&service.path)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|ele| {
+            let relative = ele
+                .path()
+                .strip_prefix(&service.path)
+                .expect("Service file is under the service directory");
+            let is_dir = ele.file_type().is_dir();
+
+            let is_world = is_dir && ele.path().join("level.dat").exists();
+
+            !(is_world || ignore.matched(relative, is_dir).is_ignore())
+        });
+
+    let mut files = vec![];
+    for ele in walk {
+        let ele = ele?;
+        let relative = ele
+            .path()
+            .strip_prefix(&service.path)
+            .expect("Service file is under the service directory");
+        let target_path = target.join(relative);
+
+        if ele.file_type().is_dir() {
+            fs::create_dir_all(target_path)?;
+        } else if ele.file_type().is_file() {
+            fs::copy(ele.path(), target_path)?;
+            files.push(relative.to_string_lossy().to_string());
+        }
+    }
+
+    Ok(files)
+}
+
+/// Values of the service worth turning into placeholders, with how often they appear in the
+/// copied `files` of `target`.
+pub fn candidates(
+    service: &ServiceInformation,
+    target: &Path,
+    files: &[String],
+) -> Vec<Replacement> {
+    let properties = fs::read_to_string(service.path.join("server.properties"))
+        .map(|ele| read_properties(&ele))
+        .unwrap_or_default();
+
+    let mut values = vec![("SERVICE_NAME", Some(service.name.clone()))];
+    values.push(("PORT", properties.get("server-port").cloned()));
+    values.push(("QUERY_PORT", properties.get("query.port").cloned()));
+    values.push((
+        "RCON_PORT",
+        properties.get("rcon.port").cloned().or_else(|| {
+            let rcon = service.manifest.rcon.as_ref()?;
+            Some(rcon.port.to_string())
+        }),
+    ));
+    values.push((
+        "RCON_PASSWORD",
+        properties.get("rcon.password").cloned().or_else(|| {
+            let rcon = service.manifest.rcon.as_ref()?;
+            Some(rcon.password.clone())
+        }),
+    ));
+
+    let texts = files
+        .iter()
+        .filter_map(|ele| fs::read_to_string(target.join(ele)).ok())
+        .collect::<Vec<_>>();
+
+    let mut candidates: Vec<Replacement> = vec![];
+    for (var, value) in values {
+        let Some(value) = value.filter(|ele| !ele.is_empty()) else {
+            continue;
+        };
+        // The query port is usually the server port, one placeholder is enough.
+        if candidates.iter().any(|ele| ele.value == value) {
+            continue;
+        }
+
+        let occurrences = texts
+            .iter()
+            .map(|text| replace_value(text, &value, "").1)
+            .sum();
+        if occurrences > 0 {
+            candidates.push(Replacement {
+                var: var.to_string(),
+                value,
+                occurrences,
+            });
+        }
+    }
+
+    candidates
+}
+
+/// Replace the values of `replacements` with their placeholders in the text files of
+/// `target`, and declare the variables in its manifest.
+///
+/// Only string values of the manifest are touched, so that it stays valid TOML before
+/// rendering.
+pub fn apply(
+    target: &Path,
+    files: &[String],
+    replacements: &[Replacement],
+    service_name: &str,
+) -> anyhow::Result<()> {
+    // Longer values first, so that a value containing another is replaced whole.
+    let mut replacements = replacements.to_vec();
+    replacements.sort_by_key(|ele| std::cmp::Reverse(ele.value.len()));
+
+    let replace = |text: &str| {
+        replacements.iter().fold(text.to_string(), |text, ele| {
+            replace_value(&text, &ele.value, &format!("{{{}}}", ele.var)).0
+        })
+    };
+
+    for file in files.iter().filter(|ele| *ele != MANIFEST_FILE) {
+        let path = target.join(file);
+        if let Ok(text) = fs::read_to_string(&path) {
+            fs::write(&path, replace(&text))?;
+        }
+    }
+
+    let manifest_path = target.join(MANIFEST_FILE);
+    let mut manifest = toml::from_str::<Value>(&fs::read_to_string(&manifest_path)?)?;
+    replace_strings(&mut manifest, &replace);
+
+    let table = manifest
+        .as_table_mut()
+        .expect("Manifest is a table")
+        .entry("variables")
+        .or_insert_with(|| Value::Table(Default::default()));
+    if let Value::Table(variables) = table {
+        for ele in replacements.iter().filter(|ele| ele.var != "SERVICE_NAME") {
+            let variable = ManifestContentVariable {
+                default: None,
+                description: Some(format!("Was {} in service {}", ele.value, service_name)),
+                kind: if ele.var.ends_with("PORT") {
+                    VariableType::Port
+                } else {
+                    VariableType::String
+                },
+            };
+            variables.insert(ele.var.clone(), Value::try_from(variable)?);
+        }
+    }
+
+    fs::write(manifest_path, toml::to_string_pretty(&manifest)?)?;
+    Ok(())
+}
+
+fn replace_strings(value: &mut Value, replace: &impl Fn(&str) -> String) {
+    match value {
+        Value::String(text) => *text = replace(text),
+        Value::Array(array) => array
+            .iter_mut()
+            .for_each(|ele| replace_strings(ele, replace)),
+        Value::Table(table) => table
+            .iter_mut()
+            .for_each(|(_, ele)| replace_strings(ele, replace)),
+        _ => {}
+    }
+}
+
+/// Replace `value` where it is not part of a longer word or number, returning the result and
+/// the number of replacements.
+fn replace_value(text: &str, value: &str, replacement: &str) -> (String, usize) {
+    let is_word = |ele: Option<char>| ele.is_some_and(|ele| ele.is_alphanumeric() || ele == '_');
+
+    let mut result = String::with_capacity(text.len());
+    let mut count = 0;
+    let mut rest = text;
+    while let Some(index) = rest.find(value) {
+        let before = rest[..index]
+            .chars()
+            .next_back()
+            .or(result.chars().next_back());
+        let after = rest[index + value.len()..].chars().next();
+
+        result.push_str(&rest[..index]);
+        if is_word(before) || is_word(after) {
+            result.push_str(value);
+        } else {
+            result.push_str(replacement);
+            count += 1;
+        }
+        rest = &rest[index + value.len()..];
+    }
+    result.push_str(rest);
+
+    (result, count)
+}
+
+fn read_properties(content: &str) -> BTreeMap<String, String> {
+    content
+        .lines()
+        .filter(|ele| !ele.trim_start().starts_with('#'))
+        .filter_map(|ele| ele.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+#[test]
+fn replace_value_test() {
+    assert_eq!(
+        replace_value("server-port=25565\nmax=125565\n", "25565", "{PORT}"),
+        ("server-port={PORT}\nmax=125565\n".to_string(), 1)
+    );
+    assert_eq!(
+        replace_value("lobby lobby_old lobby-2", "lobby", "{SERVICE_NAME}"),
+        ("{SERVICE_NAME} lobby_old {SERVICE_NAME}-2".to_string(), 2)
+    );
+}
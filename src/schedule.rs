@@ -0,0 +1,262 @@
+use std::{
+    collections::HashSet,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use chrono::{DateTime, Local};
+use cron::Schedule;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    artifact::ArtifactCache,
+    backup::{self, BackupStore},
+    executor::Termination,
+    manifest::{ManifestContentSchedule, ScheduleAction},
+    response::Envelope,
+    service::{RestartResult, ServiceInformation},
+    warning,
+    work::WorkDirectory,
+};
+
+const TICK: Duration = Duration::from_secs(1);
+const RESTART_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Serialize, JsonSchema, Deserialize, Clone)]
+pub struct ScheduleRecord {
+    pub time: DateTime<Local>,
+    pub service: String,
+    pub cron: String,
+    pub action: String,
+    pub success: bool,
+    pub result: String,
+}
+
+impl ScheduleRecord {
+    pub fn append<T>(&self, path: T) -> anyhow::Result<()>
+    where
+        T: Into<PathBuf>,
+    {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.into())?;
+
+        writeln!(file, "{}", serde_json::to_string(self)?)?;
+
+        Ok(())
+    }
+
+    pub fn read_all<T>(path: T) -> anyhow::Result<Vec<ScheduleRecord>>
+    where
+        T: Into<PathBuf>,
+    {
+        let path: PathBuf = path.into();
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+
+        fs::read_to_string(path)?
+            .lines()
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    }
+}
+
+/// Parse a cron expression, accepting the classic five field form without seconds.
+pub fn parse_cron(expression: &str) -> anyhow::Result<Schedule> {
+    let expression = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression.to_string()
+    };
+
+    Ok(Schedule::from_str(&expression)?)
+}
+
+pub fn next_run(expression: &str) -> anyhow::Result<Option<DateTime<Local>>> {
+    Ok(parse_cron(expression)?.upcoming(Local).next())
+}
+
+pub fn jobs(
+    services: Vec<ServiceInformation>,
+) -> Vec<(ServiceInformation, ManifestContentSchedule)> {
+    services
+        .into_iter()
+        .flat_map(|service| {
+            service
+                .manifest
+                .schedule
+                .clone()
+                .into_iter()
+                .map(move |schedule| (service.clone(), schedule))
+        })
+        .collect()
+}
+
+pub async fn run_action(
+    service: ServiceInformation,
+    action: ScheduleAction,
+    workdir: WorkDirectory,
+) -> anyhow::Result<String> {
+    match action {
+        ScheduleAction::Restart { warn, timeout } => {
+            let mut data_dir = workdir.clone().data_directory();
+            data_dir.push(&service.name);
+
+            let warn = warn
+                .map(|ele| humantime::parse_duration(&ele))
+                .transpose()?;
+            let timeout = timeout
+                .map(|ele| humantime::parse_duration(&ele))
+                .transpose()?
+                .unwrap_or(RESTART_TIMEOUT);
+            warning::countdown_all(std::slice::from_ref(&service), warn, false, "restart").await;
+
+            let cache = ArtifactCache::new(workdir.artifact_directory());
+            match service.restart(data_dir, &cache, timeout).await? {
+                RestartResult::Success => Ok(RestartResult::Success.to_string()),
+                result => Err(anyhow::anyhow!("{}", result)),
+            }
+        }
+        ScheduleAction::Script { script } => {
+            let mut executor = service.executor(&script);
+            executor.current_dir(&service.path);
+
+            let ecode = executor.exec().await?.wait()?;
+            if ecode.success() {
+                Ok("Success".to_string())
+            } else {
+                Err(anyhow::anyhow!(
+                    "Script exited with {}",
+                    Termination::from(ecode)
+                ))
+            }
+        }
+        ScheduleAction::Command { command } => service.send_command(&command).await,
+        ScheduleAction::Backup => {
+            let backup = service.manifest.backup.clone().unwrap_or_default();
+
+            let snapshot = backup::backup_service(
+                &service,
+                workdir.clone().backup_directory(),
+                workdir.clone().data_directory(),
+                backup.pause_saves,
+                backup.incremental,
+            )
+            .await?;
+
+            let removed = BackupStore::new(workdir.backup_directory(), &service.name)
+                .prune(&backup)?
+                .len();
+
+            Ok(format!(
+                "Created snapshot {}, pruned {} snapshot(s)",
+                snapshot.id, removed
+            ))
+        }
+    }
+}
+
+/// Run due scheduled actions of every service until the process is killed. Each due action runs
+/// in its own task, so a long backup does not delay the others.
+///
+/// The working directory is reloaded on every tick so manifest changes apply without a restart.
+/// A cron expression that cannot be parsed is recorded as a failed run once.
+pub async fn daemon<T>(workingdir: T, json: bool)
+where
+    T: Into<PathBuf> + Clone,
+{
+    let mut last_check = Local::now();
+    let mut invalid = HashSet::new();
+
+    loop {
+        tokio::time::sleep(TICK).await;
+
+        let now = Local::now();
+        let workdir = WorkDirectory::new(workingdir.clone());
+
+        for (service, schedule) in jobs(workdir.clone().services()) {
+            let cron = match parse_cron(&schedule.cron) {
+                Ok(cron) => cron,
+                Err(err) => {
+                    if invalid.insert((service.name.clone(), schedule.cron.clone())) {
+                        record(&service.name, &schedule, Err(err), &workdir, json);
+                    }
+                    continue;
+                }
+            };
+
+            if cron.after(&last_check).next().is_none_or(|next| next > now) {
+                continue;
+            }
+
+            let workdir = workdir.clone();
+            tokio::spawn(async move {
+                let name = service.name.clone();
+                let result = run_action(service, schedule.action.clone(), workdir.clone()).await;
+                record(&name, &schedule, result, &workdir, json);
+            });
+        }
+
+        last_check = now;
+    }
+}
+
+/// Append the outcome of a scheduled action to the history and print it.
+fn record(
+    service: &str,
+    schedule: &ManifestContentSchedule,
+    result: anyhow::Result<String>,
+    workdir: &WorkDirectory,
+    json: bool,
+) {
+    let record = ScheduleRecord {
+        time: Local::now(),
+        service: service.to_string(),
+        cron: schedule.cron.clone(),
+        action: schedule.action.to_string(),
+        success: result.is_ok(),
+        result: match result {
+            Ok(result) => result,
+            Err(err) => err.to_string(),
+        },
+    };
+
+    if let Err(err) = record.append(workdir.clone().schedule_history()) {
+        eprintln!("Cannot write schedule history: {}", err);
+    }
+
+    if json {
+        let envelope = Envelope::new("daemon", vec![record], vec![]);
+        println!(
+            "{}",
+            serde_json::to_string(&envelope).expect("Cannot serialized into json")
+        );
+    } else {
+        println!(
+            "[{}] {} {} ({}): {}",
+            record.time.format("%Y-%m-%d %H:%M:%S"),
+            record.service,
+            record.action,
+            record.cron,
+            record.result
+        );
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn parse_cron_test() {
+    use chrono::Timelike;
+
+    let next = next_run("30 4 * * *").unwrap().unwrap();
+    assert_eq!((next.hour(), next.minute(), next.second()), (4, 30, 0));
+
+    assert!(parse_cron("0 0 4 * * *").is_ok());
+    assert!(parse_cron("not a cron").is_err());
+}
@@ -2,27 +2,51 @@ use core::fmt;
 use std::{
     fmt::{Display, Formatter},
     fs::{self},
+    io::Read,
     path::PathBuf,
-    process::ExitCode,
+    time::{Duration, Instant},
 };
 
-use crate::{executor::Executor, manifest::ManifestContent};
-use anyhow::Ok;
+use crate::{
+    artifact::ArtifactCache,
+    executor::{Executor, Termination},
+    java,
+    manifest::ManifestContent,
+    plugin, properties,
+    rcon::Rcon,
+};
+use anyhow::anyhow;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ServiceInformation {
     pub name: String,
+    pub path: PathBuf,
     pub status: ServiceStatus,
     pub manifest: ManifestContent,
+    /// JVM command line built from `[java]` by `WorkDirectory`, or why it could not be built.
+    #[serde(skip)]
+    pub java_command: Option<Result<String, String>>,
+    /// Why the manifest could not be loaded, for services with the `Invalid` status, or why the
+    /// health check could not tell the status, for `Unknow`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(
+    Debug, Serialize, JsonSchema, Deserialize, Clone, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
 pub enum ServiceStatus {
+    #[default]
     Unknow,
     Start,
     Stop,
     Disable,
+    /// The manifest cannot be loaded.
+    Invalid,
 }
 
 impl Display for ServiceStatus {
@@ -31,12 +55,6 @@ impl Display for ServiceStatus {
     }
 }
 
-impl Default for ServiceStatus {
-    fn default() -> Self {
-        ServiceStatus::Unknow
-    }
-}
-
 impl ServiceInformation {
     pub fn new<T>(path: T) -> anyhow::Result<Self>
     where
@@ -49,140 +67,433 @@ impl ServiceInformation {
 
         path.pop();
 
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("{} is not in a service directory", path.display()))?
+            .to_string_lossy()
+            .to_string();
+
         Ok(ServiceInformation {
-            name: path
-                .into_iter()
-                .last()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string(),
+            name,
+            path,
             manifest,
             ..Default::default()
         })
     }
 
     pub async fn update_status(self) -> Self {
+        if self.status == ServiceStatus::Invalid {
+            return self;
+        }
+
         let mut status = ServiceStatus::Disable;
+        let mut error = None;
 
-        if let Some(enable) = self.manifest.enable {
-            if enable {
-                let child = Executor::from(&self.manifest.scripts.health_check)
-                    .exec()
-                    .await
-                    .expect(&format!(
-                        "Cannot execute script {}",
-                        &self.manifest.scripts.health_check
-                    ));
-
-                let (_, mut child) = Executor::output_reader(child).await;
-
-                status = if child
-                    .wait()
-                    .expect("Cannot get exit status.")
-                    .code()
-                    .expect("Cannot get exit code.")
-                    == 0
-                {
-                    ServiceStatus::Start
-                } else {
-                    ServiceStatus::Stop
+        if let Some(true) = self.manifest.enable {
+            status = match self.clone().health_check().await {
+                Ok(HealthCheckResult::Success(true)) => ServiceStatus::Start,
+                Ok(HealthCheckResult::Success(false)) => ServiceStatus::Stop,
+                Ok(HealthCheckResult::RunFailed(termination)) => {
+                    error = Some(format!("Health check was killed by {}", termination));
+                    ServiceStatus::Unknow
+                }
+                Err(err) => {
+                    error = Some(format!("Cannot run health check: {}", err));
+                    ServiceStatus::Unknow
                 }
             }
         }
-        return Self { status, ..self };
+        Self {
+            status,
+            error,
+            ..self
+        }
     }
 
     pub async fn start<T>(self, current_dir: T) -> anyhow::Result<StartResult>
     where
         T: Into<PathBuf> + Clone,
     {
-        if let Some(script) = self.manifest.scripts.pre_start {
-            let mut child = Executor::from(script);
+        if let Some(script) = &self.manifest.scripts.pre_start {
+            let mut child = self.executor(script);
             child.current_dir(current_dir);
 
             let mut child = child.exec().await?;
 
             let ecode = child.wait()?;
-            if !&child.wait()?.success() {
-                return Ok(StartResult::PreStartFailed(
-                    ecode.code().expect("Canno get exit code"),
-                ));
+            if !ecode.success() {
+                return Ok(StartResult::PreStartFailed(ecode.into()));
             };
         }
 
-        let start = Executor::from(self.manifest.scripts.start);
+        let start = if self.manifest.scripts.start.trim() == java::BUILTIN_START {
+            let command = match &self.java_command {
+                Some(Ok(command)) => command,
+                Some(Err(err)) => return Err(anyhow!("{}", err)),
+                None => {
+                    return Err(anyhow!(
+                        "Service {} starts with {} but has no [java] section",
+                        self.name,
+                        java::BUILTIN_START
+                    ))
+                }
+            };
+            let mut start = self.executor(&java::start_script(
+                command,
+                self.manifest.pid_file.as_deref(),
+            ));
+            start.current_dir(&self.path);
+            start
+        } else {
+            self.executor(&self.manifest.scripts.start)
+        };
 
         let ecode = start.exec().await?.wait()?;
 
         if !ecode.success() {
-            Ok(StartResult::StartFailed(
-                ecode.code().expect("Cannot get exit code"),
-            ))
+            Ok(StartResult::StartFailed(ecode.into()))
         } else {
             Ok(StartResult::Success)
         }
     }
 
     pub async fn stop(self) -> anyhow::Result<StopResult> {
-        if let Some(stop) = self.manifest.scripts.stop {
-            let stop = Executor::from(stop).exec().await?.wait()?;
+        if let Some(stop) = &self.manifest.scripts.stop {
+            let stop = self.executor(stop).exec().await?.wait()?;
             if !stop.success() {
-                return Ok(StopResult::StopFailed(
-                    stop.code().expect("Cannot get exit code"),
-                ));
+                return Ok(StopResult::StopFailed(stop.into()));
             }
         }
 
-        if let Some(post_stop) = self.manifest.scripts.post_stop {
-            let post_stop = Executor::from(post_stop).exec().await?.wait()?;
+        if let Some(post_stop) = &self.manifest.scripts.post_stop {
+            let post_stop = self.executor(post_stop).exec().await?.wait()?;
             if !post_stop.success() {
-                return Ok(StopResult::PostStopFailed(
-                    post_stop.code().expect("Cannot get exit code"),
-                ));
+                return Ok(StopResult::PostStopFailed(post_stop.into()));
             }
         }
 
         Ok(StopResult::Success)
     }
 
-    pub async fn health_check(self) -> anyhow::Result<ExitCode> {
-        todo!()
+    pub async fn reload(self) -> anyhow::Result<ReloadResult> {
+        let Some(reload) = &self.manifest.scripts.reload else {
+            return Ok(ReloadResult::NoScript);
+        };
+
+        let reload = self.executor(reload).exec().await?.wait()?;
+        if !reload.success() {
+            return Ok(ReloadResult::ReloadFailed(reload.into()));
+        }
+
+        Ok(ReloadResult::Success)
+    }
+
+    /// Send a command to the server console, through RCON when it is configured and through the
+    /// `console` script otherwise. The command is passed to the script as `SMC_COMMAND`.
+    pub async fn send_command(self, command: &str) -> anyhow::Result<String> {
+        if let Some(rcon) = &self.manifest.rcon {
+            let mut rcon = Rcon::connect((rcon.host.as_str(), rcon.port), &rcon.password).await?;
+            return rcon.command(command).await;
+        }
+
+        let Some(console) = &self.manifest.scripts.console else {
+            return Err(anyhow!(
+                "Service {} has neither rcon nor console script",
+                self.name
+            ));
+        };
+
+        let mut executor = self.executor(console);
+        executor.current_dir(&self.path);
+        executor.env("SMC_COMMAND", command);
+
+        let (mut reader, mut child) = Executor::output_reader(executor.exec().await?).await;
+
+        let mut output = String::new();
+        reader.read_to_string(&mut output)?;
+
+        let ecode = child.wait()?;
+        if !ecode.success() {
+            return Err(anyhow!(
+                "Console script exited with {}",
+                Termination::from(ecode)
+            ));
+        }
+
+        Ok(output.trim_end().to_string())
+    }
+
+    /// Bring what the manifest manages up to date: the artifacts, the plugins and
+    /// `server.properties`.
+    pub fn prepare(&self, cache: &ArtifactCache) -> anyhow::Result<()> {
+        cache.install(self)?;
+        plugin::install(self, cache)?;
+        properties::apply(&self.manifest, &self.path)?;
+        Ok(())
+    }
+
+    /// Stop the service, wait until the health check confirms it is down, then prepare and start
+    /// it again.
+    pub async fn restart<T>(
+        self,
+        current_dir: T,
+        cache: &ArtifactCache,
+        timeout: Duration,
+    ) -> anyhow::Result<RestartResult>
+    where
+        T: Into<PathBuf> + Clone,
+    {
+        let stopped = self.clone().stop().await?;
+        if stopped != StopResult::Success {
+            return Ok(RestartResult::StopFailed(stopped));
+        }
+
+        if !self
+            .clone()
+            .wait_until(|status| *status != ServiceStatus::Start, timeout)
+            .await
+        {
+            return Ok(RestartResult::StopTimeout);
+        }
+
+        self.prepare(cache)?;
+        match self.start(current_dir).await? {
+            StartResult::Success => Ok(RestartResult::Success),
+            started => Ok(RestartResult::StartFailed(started)),
+        }
+    }
+
+    /// Poll the health check until `predicate` holds or `timeout` elapses.
+    pub async fn wait_until<F>(self, predicate: F, timeout: Duration) -> bool
+    where
+        F: Fn(&ServiceStatus) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if predicate(&self.clone().update_status().await.status) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Executor of a script of the service, with `SMC_JAVA` set when the service has `[java]`.
+    pub fn executor(&self, script: &str) -> Executor {
+        let mut executor = Executor::from(script);
+        if let Some(Ok(java)) = &self.java_command {
+            executor.env("SMC_JAVA", java);
+        }
+        executor
+    }
+
+    pub async fn health_check(self) -> anyhow::Result<HealthCheckResult> {
+        let child = self
+            .executor(&self.manifest.scripts.health_check)
+            .exec()
+            .await?;
+
+        let (_, mut child) = Executor::output_reader(child).await;
+
+        let status = child.wait()?;
+        Ok(match status.code() {
+            Some(code) => HealthCheckResult::Success(code == 0),
+            None => HealthCheckResult::RunFailed(status.into()),
+        })
     }
 }
 
-#[derive(Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Serialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord)]
 pub enum StopResult {
     Success,
-    StopFailed(i32),
-    PostStopFailed(i32),
+    StopFailed(Termination),
+    PostStopFailed(Termination),
 }
 impl Display for StopResult {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "{}", self.clone())
+        match self {
+            StopResult::Success => write!(f, "Success"),
+            StopResult::StopFailed(termination) => write!(f, "StopFailed({})", termination),
+            StopResult::PostStopFailed(termination) => {
+                write!(f, "PostStopFailed({})", termination)
+            }
+        }
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, JsonSchema)]
 pub enum StartResult {
     Success,
-    PreStartFailed(i32),
-    StartFailed(i32),
+    PreStartFailed(Termination),
+    StartFailed(Termination),
 }
 
 impl Display for StartResult {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "{}", self.clone())
+        match self {
+            StartResult::Success => write!(f, "Success"),
+            StartResult::PreStartFailed(termination) => {
+                write!(f, "PreStartFailed({})", termination)
+            }
+            StartResult::StartFailed(termination) => write!(f, "StartFailed({})", termination),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub enum RestartResult {
+    Success,
+    StopFailed(StopResult),
+    StopTimeout,
+    StartFailed(StartResult),
+    Unhealthy,
+    Aborted,
+    NotRunning,
+}
+
+impl Display for RestartResult {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            RestartResult::StopFailed(result) => write!(f, "StopFailed({})", result),
+            RestartResult::StartFailed(result) => write!(f, "StartFailed({})", result),
+            result => write!(f, "{:?}", result),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub enum ReloadResult {
+    Success,
+    NoScript,
+    ReloadFailed(Termination),
+}
+
+impl Display for ReloadResult {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ReloadResult::ReloadFailed(termination) => write!(f, "ReloadFailed({})", termination),
+            result => write!(f, "{:?}", result),
+        }
     }
 }
 
 #[derive(Debug, Serialize)]
 pub enum HealthCheckResult {
     Success(bool),
-    RunFailed(i32),
+    /// The health check was killed by a signal.
+    RunFailed(Termination),
 }
 impl Display for HealthCheckResult {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "{}", self.clone())
+        match self {
+            HealthCheckResult::Success(healthy) => write!(f, "Success({})", healthy),
+            HealthCheckResult::RunFailed(termination) => write!(f, "RunFailed({})", termination),
+        }
+    }
+}
+
+/// Sort services so that every service comes after the services it depends on.
+///
+/// Dependencies outside of `services` are ignored.
+pub fn dependency_order(
+    services: Vec<ServiceInformation>,
+) -> anyhow::Result<Vec<ServiceInformation>> {
+    let mut ordered = vec![];
+    let mut remaining = services;
+
+    while !remaining.is_empty() {
+        let names = remaining
+            .iter()
+            .map(|ele| ele.name.clone())
+            .collect::<Vec<_>>();
+
+        let (ready, blocked): (Vec<_>, Vec<_>) = remaining
+            .into_iter()
+            .partition(|ele| ele.manifest.depends.iter().all(|dep| !names.contains(dep)));
+
+        if ready.is_empty() {
+            return Err(anyhow!(
+                "Dependency cycle between services: {}",
+                names.join(", ")
+            ));
+        }
+
+        ordered.extend(ready);
+        remaining = blocked;
     }
+
+    Ok(ordered)
+}
+
+/// Split services in dependency order into batches of at most `size`, starting a new batch
+/// before a service that depends on one of the current batch.
+pub fn dependency_batches(
+    services: Vec<ServiceInformation>,
+    size: usize,
+) -> Vec<Vec<ServiceInformation>> {
+    let mut batches: Vec<Vec<ServiceInformation>> = vec![];
+    for service in services {
+        match batches.last_mut() {
+            Some(batch)
+                if batch.len() < size
+                    && !batch
+                        .iter()
+                        .any(|ele| service.manifest.depends.contains(&ele.name)) =>
+            {
+                batch.push(service)
+            }
+            _ => batches.push(vec![service]),
+        }
+    }
+    batches
+}
+
+#[cfg(test)]
+#[test]
+fn dependency_order_test() {
+    let service = |name: &str, depends: Vec<&str>| ServiceInformation {
+        name: name.to_string(),
+        manifest: ManifestContent {
+            depends: depends.into_iter().map(str::to_string).collect(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let ordered = dependency_order(vec![
+        service("lobby", vec!["proxy"]),
+        service("proxy", vec!["database"]),
+        service("survival", vec!["proxy", "auth"]),
+    ])
+    .unwrap()
+    .into_iter()
+    .map(|ele| ele.name)
+    .collect::<Vec<_>>();
+    assert_eq!(ordered, vec!["proxy", "lobby", "survival"]);
+
+    let batches = dependency_batches(
+        vec![
+            service("proxy", vec![]),
+            service("auth", vec![]),
+            service("lobby", vec!["proxy"]),
+            service("survival", vec![]),
+        ],
+        3,
+    )
+    .into_iter()
+    .map(|ele| ele.into_iter().map(|ele| ele.name).collect::<Vec<_>>())
+    .collect::<Vec<_>>();
+    assert_eq!(
+        batches,
+        vec![vec!["proxy", "auth"], vec!["lobby", "survival"]]
+    );
+
+    assert!(dependency_order(vec![
+        service("lobby", vec!["proxy"]),
+        service("proxy", vec!["lobby"]),
+    ])
+    .is_err());
 }
@@ -1,16 +1,53 @@
 use serde::Serialize;
 use std::{
+    collections::BTreeMap,
     fs::{self},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
-use crate::manifest::ManifestContent;
+use anyhow::anyhow;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use sha2::{Digest, Sha256};
+use toml::Value;
+
+use crate::{
+    manifest::{ManifestContent, ManifestContentFiles},
+    registry::{self, SOURCE_STATE_FILE},
+};
+
+pub const MANIFEST_FILE: &str = "manifest.toml";
+
+/// Gitignore file listing what a template directory holds but `create` leaves out.
+pub const IGNORE_FILE: &str = ".smcignore";
+
+/// Never part of a template.
+const ALWAYS_IGNORED: [&str; 3] = [".git/", IGNORE_FILE, SOURCE_STATE_FILE];
+
+/// Files that are never rendered, whatever the manifest says.
+const VERBATIM_EXTENSIONS: [&str; 12] = [
+    "jar", "zip", "gz", "tgz", "png", "jpg", "dat", "mca", "mcr", "class", "so", "dll",
+];
+
+/// Variables every template can use without declaring them.
+pub const BUILTIN_VARS: [&str; 1] = ["SERVICE_NAME"];
 
 #[derive(Debug, Default, Serialize, Clone)]
 pub struct TemplateInfomation {
     pub name: String,
+    /// Taken from the directory name, `paper@1.20` is version `1.20` of `paper`.
+    pub version: Option<String>,
+    /// Name of the source the template was fetched from, if any.
+    pub source: Option<String>,
     pub path: PathBuf,
     pub template: ManifestContent,
+    /// Directories overlaid to build the template, from the root ancestor to the template
+    /// itself. Empty until the template is resolved.
+    #[serde(skip)]
+    pub layers: Vec<PathBuf>,
+    /// Manifest merged from every layer when the template extends another, written in place of
+    /// the template's own.
+    #[serde(skip)]
+    pub merged_manifest: Option<String>,
 }
 
 impl TemplateInfomation {
@@ -21,22 +58,511 @@ impl TemplateInfomation {
         let mut path = path.into();
         let file_content = fs::read_to_string(&path)?;
 
-        let template = toml::from_str::<ManifestContent>(&file_content)
-            .expect("Cannot deserialized into object");
+        let value = toml::from_str::<Value>(&file_content)?;
+
+        // A template extending another only has to declare what it changes, it becomes a
+        // complete manifest once resolved.
+        let template = match value.get("extends").and_then(Value::as_str) {
+            Some(extends) => ManifestContent {
+                extends: Some(extends.to_string()),
+                ..Default::default()
+            },
+            None => value.try_into::<ManifestContent>()?,
+        };
 
         path.pop();
 
+        let directory = path
+            .iter()
+            .next_back()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let (name, version) = registry::split_version(&directory);
+
         Ok(TemplateInfomation {
-            name: path
-                .into_iter()
-                .last()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string(),
+            name: name.to_string(),
+            version: version.map(str::to_string),
+            source: None,
             path,
             template,
             ..Default::default()
         })
     }
+
+    /// Follow `extends` up to the root template, merging the manifests (the child wins) and
+    /// recording the directories to overlay.
+    pub fn resolve(&self, templates: &[TemplateInfomation]) -> anyhow::Result<TemplateInfomation> {
+        let mut chain = vec![self.clone()];
+        while let Some(parent) = chain.last().and_then(|ele| ele.template.extends.clone()) {
+            let child = chain.last().expect("Chain is never empty").full_name();
+            let parent = find(templates.iter().cloned(), &parent)?.ok_or_else(|| {
+                anyhow!(
+                    "Template {} extends {}, which does not exist",
+                    child,
+                    parent
+                )
+            })?;
+
+            if chain.iter().any(|ele| ele.path == parent.path) {
+                let cycle = chain
+                    .iter()
+                    .chain([&parent])
+                    .map(TemplateInfomation::full_name)
+                    .collect::<Vec<_>>();
+                return Err(anyhow!(
+                    "Templates extend each other: {}",
+                    cycle.join(" -> ")
+                ));
+            }
+
+            chain.push(parent);
+        }
+        chain.reverse();
+
+        let mut merged = Value::Table(Default::default());
+        for ele in &chain {
+            let content = fs::read_to_string(ele.path.join(MANIFEST_FILE))?;
+            merge_values(&mut merged, toml::from_str(&content)?);
+        }
+        if let Value::Table(table) = &mut merged {
+            table.remove("extends");
+        }
+
+        let mut resolved = self.clone();
+        resolved.template = merged.clone().try_into().map_err(|err| {
+            anyhow!(
+                "Merged manifest of {} is invalid: {}",
+                self.full_name(),
+                err
+            )
+        })?;
+        resolved.layers = chain.into_iter().map(|ele| ele.path).collect();
+        if resolved.layers.len() > 1 {
+            resolved.merged_manifest = Some(toml::to_string_pretty(&merged)?);
+        }
+
+        Ok(resolved)
+    }
+
+    /// `name@version`, or only the name for unversioned templates.
+    pub fn full_name(&self) -> String {
+        match &self.version {
+            Some(version) => format!("{}@{}", self.name, version),
+            None => self.name.clone(),
+        }
+    }
+}
+
+impl TemplateInfomation {
+    /// Content of every file of the template with its placeholders replaced, keyed by path
+    /// relative to the template directory.
+    pub fn render_files(
+        &self,
+        vars: &BTreeMap<String, String>,
+    ) -> anyhow::Result<BTreeMap<String, Vec<u8>>> {
+        let mut files = BTreeMap::new();
+
+        for layer in self.layers() {
+            let rules = FileRules::new(
+                layer,
+                &fs::read_to_string(layer.join(IGNORE_FILE)).unwrap_or_default(),
+                self.template.files.as_ref(),
+            )?;
+
+            for (relative, path) in rules.files(layer) {
+                files.insert(
+                    relative.clone(),
+                    rules.render(&relative, fs::read(path)?, vars),
+                );
+            }
+        }
+
+        // Values are substituted into the parsed manifest, so that they cannot break its syntax.
+        if let Some(manifest) = &self.merged_manifest {
+            let mut manifest = toml::from_str::<Value>(manifest)?;
+            render_value(&mut manifest, vars);
+            files.insert(
+                MANIFEST_FILE.to_string(),
+                toml::to_string_pretty(&manifest)?.into_bytes(),
+            );
+        }
+
+        Ok(files)
+    }
+
+    /// Copy the template into `target`, replacing `{VAR}` placeholders in text files, and
+    /// return the sha256 of every file written keyed by its path relative to `target`.
+    pub fn materialize(
+        &self,
+        target: &Path,
+        vars: &BTreeMap<String, String>,
+    ) -> anyhow::Result<BTreeMap<String, String>> {
+        let mut files = BTreeMap::new();
+
+        for (relative, content) in self.render_files(vars)? {
+            let target_path = target.join(&relative);
+            fs::create_dir_all(target_path.parent().expect("File path has a parent"))?;
+            fs::write(&target_path, &content)?;
+            self.copy_permissions(&relative, &target_path)?;
+
+            files.insert(relative, hex::encode(Sha256::digest(&content)));
+        }
+
+        Ok(files)
+    }
+
+    /// Give `target` the permissions of the template file at `relative`, so that scripts stay
+    /// executable.
+    pub fn copy_permissions(&self, relative: &str, target: &Path) -> anyhow::Result<()> {
+        let source = self
+            .layers()
+            .iter()
+            .rev()
+            .map(|ele| ele.join(relative))
+            .find(|ele| ele.exists());
+        if let Some(source) = source {
+            fs::set_permissions(target, fs::metadata(source)?.permissions())?;
+        }
+        Ok(())
+    }
+
+    fn layers(&self) -> &[PathBuf] {
+        if self.layers.is_empty() {
+            std::slice::from_ref(&self.path)
+        } else {
+            &self.layers
+        }
+    }
+}
+
+/// Which files of a template directory are copied, and which of those are rendered.
+pub struct FileRules {
+    ignore: Gitignore,
+    render: Option<Gitignore>,
+    verbatim: Gitignore,
+}
+
+impl FileRules {
+    /// `ignore` is the content of the `.smcignore` of the directory at `root`.
+    pub fn new(
+        root: &Path,
+        ignore: &str,
+        files: Option<&ManifestContentFiles>,
+    ) -> anyhow::Result<FileRules> {
+        let build = |patterns: &mut dyn Iterator<Item = &str>| {
+            let mut builder = GitignoreBuilder::new(root);
+            for pattern in patterns {
+                builder.add_line(None, pattern)?;
+            }
+            anyhow::Ok(builder.build()?)
+        };
+        let files = files.cloned().unwrap_or_default();
+
+        Ok(FileRules {
+            ignore: build(&mut ALWAYS_IGNORED.into_iter().chain(ignore.lines()))?,
+            render: if files.render.is_empty() {
+                None
+            } else {
+                Some(build(&mut files.render.iter().map(String::as_str))?)
+            },
+            verbatim: build(&mut files.verbatim.iter().map(String::as_str))?,
+        })
+    }
+
+    /// Whether placeholders are replaced in the file at `relative`.
+    pub fn is_rendered(&self, relative: &str) -> bool {
+        let binary = Path::new(relative)
+            .extension()
+            .is_some_and(|ele| VERBATIM_EXTENSIONS.contains(&ele.to_string_lossy().as_ref()));
+        let matched = |rules: &Gitignore| {
+            rules
+                .matched_path_or_any_parents(relative, false)
+                .is_ignore()
+        };
+
+        !binary && self.render.as_ref().is_none_or(matched) && !matched(&self.verbatim)
+    }
+
+    pub fn render(
+        &self,
+        relative: &str,
+        content: Vec<u8>,
+        vars: &BTreeMap<String, String>,
+    ) -> Vec<u8> {
+        if self.is_rendered(relative) {
+            render_bytes(content, vars)
+        } else {
+            content
+        }
+    }
+
+    /// Files under `root` that are not ignored, relative to it and as full paths.
+    pub fn files(&self, root: &Path) -> Vec<(String, PathBuf)> {
+        walkdir::WalkDir::new(root)
+            .min_depth(1)
+            .into_iter()
+            .filter_entry(|ele| {
+                let relative = ele.path().strip_prefix(root).unwrap_or(ele.path());
+                !self
+                    .ignore
+                    .matched(relative, ele.file_type().is_dir())
+                    .is_ignore()
+            })
+            .flatten()
+            .filter(|ele| ele.file_type().is_file())
+            .map(|ele| {
+                let relative = ele.path().strip_prefix(root).unwrap_or(ele.path());
+                (relative.to_string_lossy().to_string(), ele.into_path())
+            })
+            .collect()
+    }
+}
+
+/// Find a template by `name` or `name@version`, optionally prefixed with `<source>/`. Without a
+/// version an unversioned template wins, then the highest version. A match in several sources is
+/// an error.
+pub fn find<T>(templates: T, spec: &str) -> anyhow::Result<Option<TemplateInfomation>>
+where
+    T: IntoIterator<Item = TemplateInfomation>,
+{
+    let (source, spec) = match spec.split_once('/') {
+        Some((source, spec)) => (Some(source), spec),
+        None => (None, spec),
+    };
+    let (name, version) = registry::split_version(spec);
+
+    let candidates = templates
+        .into_iter()
+        .filter(|ele| ele.name == name)
+        .filter(|ele| source.is_none() || ele.source.as_deref() == source)
+        .filter(|ele| version.is_none() || ele.version.as_deref() == version)
+        .collect::<Vec<_>>();
+
+    let best = candidates
+        .iter()
+        .map(|ele| &ele.version)
+        .max_by(|a, b| match (a, b) {
+            (Some(a), Some(b)) => registry::compare_versions(a, b),
+            (a, b) => a.is_none().cmp(&b.is_none()),
+        })
+        .cloned();
+    let mut found = candidates
+        .into_iter()
+        .filter(|ele| Some(&ele.version) == best.as_ref())
+        .collect::<Vec<_>>();
+
+    if found.len() > 1 {
+        let sources = found
+            .iter()
+            .map(|ele| ele.source.as_deref().unwrap_or("local"))
+            .collect::<Vec<_>>();
+        return Err(anyhow!(
+            "Template {} is found in several sources ({}), prefix it with one like {}/{}",
+            spec,
+            sources.join(", "),
+            sources[0],
+            spec
+        ));
+    }
+    Ok(found.pop())
+}
+
+/// Merge `overlay` into `base`, tables key by key and everything else replaced.
+fn merge_values(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Table(base), Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Render `content` if it is text, binary files are returned untouched.
+pub fn render_bytes(content: Vec<u8>, vars: &BTreeMap<String, String>) -> Vec<u8> {
+    match String::from_utf8(content) {
+        Ok(text) => render(&text, vars).into_bytes(),
+        Err(err) => err.into_bytes(),
+    }
+}
+
+/// Replace every `{KEY}` of `vars` in `content`. Unknown placeholders are left untouched.
+pub fn render(content: &str, vars: &BTreeMap<String, String>) -> String {
+    vars.iter()
+        .fold(content.to_string(), |content, (key, value)| {
+            content.replace(&format!("{{{}}}", key), value)
+        })
+}
+
+fn render_value(value: &mut Value, vars: &BTreeMap<String, String>) {
+    match value {
+        Value::String(text) => *text = render(text, vars),
+        Value::Array(values) => values.iter_mut().for_each(|ele| render_value(ele, vars)),
+        Value::Table(table) => table
+            .iter_mut()
+            .for_each(|(_, ele)| render_value(ele, vars)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn resolve_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let write = |template: &str, file: &str, content: &str| {
+        fs::create_dir_all(dir.path().join(template)).unwrap();
+        fs::write(dir.path().join(template).join(file), content).unwrap();
+    };
+
+    write(
+        "paper-base",
+        MANIFEST_FILE,
+        "[scripts]\nhealth_check = \"true\"\nstart = \"./start.sh\"\n",
+    );
+    write("paper-base", "server.properties", "motd=base\n");
+    write("paper-base", "eula.txt", "eula=true\n");
+    write(
+        "lobby",
+        MANIFEST_FILE,
+        "extends = \"paper-base\"\n[scripts]\nstart = \"./lobby.sh\"\n",
+    );
+    write("lobby", "server.properties", "motd=lobby\n");
+    write("loop-a", MANIFEST_FILE, "extends = \"loop-b\"\n");
+    write("loop-b", MANIFEST_FILE, "extends = \"loop-a\"\n");
+    write("orphan", MANIFEST_FILE, "extends = \"missing\"\n");
+
+    let templates = ["paper-base", "lobby", "loop-a", "loop-b", "orphan"]
+        .map(|ele| TemplateInfomation::new(dir.path().join(ele).join(MANIFEST_FILE)).unwrap());
+
+    let lobby = templates[1].resolve(&templates).unwrap();
+    assert_eq!(lobby.template.scripts.start, "./lobby.sh");
+    assert_eq!(lobby.template.scripts.health_check, "true");
+
+    let files = lobby.render_files(&BTreeMap::new()).unwrap();
+    assert_eq!(files["server.properties"], b"motd=lobby\n");
+    assert_eq!(files["eula.txt"], b"eula=true\n");
+    assert!(!String::from_utf8_lossy(&files[MANIFEST_FILE]).contains("extends"));
+
+    let base = templates[0].resolve(&templates).unwrap();
+    assert!(base.merged_manifest.is_none());
+    let files = base.render_files(&BTreeMap::new()).unwrap();
+    assert_eq!(
+        files[MANIFEST_FILE],
+        b"[scripts]\nhealth_check = \"true\"\nstart = \"./start.sh\"\n"
+    );
+
+    write(
+        "quoted",
+        MANIFEST_FILE,
+        "extends = \"paper-base\"\n[properties]\nmotd = \"{MOTD}\"\n",
+    );
+    let quoted = TemplateInfomation::new(dir.path().join("quoted").join(MANIFEST_FILE))
+        .unwrap()
+        .resolve(&templates)
+        .unwrap();
+    let vars = BTreeMap::from([("MOTD".to_string(), "A \"quoted\"\nmotd".to_string())]);
+    let files = quoted.render_files(&vars).unwrap();
+    let manifest = toml::from_str::<Value>(&String::from_utf8_lossy(&files[MANIFEST_FILE]));
+    assert_eq!(
+        manifest.unwrap()["properties"]["motd"].as_str(),
+        Some("A \"quoted\"\nmotd")
+    );
+
+    assert!(templates[2].resolve(&templates).is_err());
+    assert!(templates[4].resolve(&templates).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn find_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let template = |name: &str, source: Option<&str>| {
+        let path = dir.path().join(source.unwrap_or("")).join(name);
+        fs::create_dir_all(&path).unwrap();
+        fs::write(
+            path.join(MANIFEST_FILE),
+            "[scripts]\nhealth_check = \"true\"\nstart = \"true\"\n",
+        )
+        .unwrap();
+        let mut template = TemplateInfomation::new(path.join(MANIFEST_FILE)).unwrap();
+        template.source = source.map(str::to_string);
+        template
+    };
+
+    let templates = vec![
+        template("paper", None),
+        template("paper@1.20", None),
+        template("paper@1.9", None),
+        template("velocity@3.2", Some("a")),
+        template("velocity@3.2", Some("b")),
+    ];
+    let find = |spec| find(templates.clone(), spec);
+
+    assert_eq!(find("paper").unwrap().unwrap().version, None);
+    assert_eq!(
+        find("paper@1.20").unwrap().unwrap().version.as_deref(),
+        Some("1.20")
+    );
+    assert!(find("velocity").is_err());
+    assert_eq!(
+        find("b/velocity").unwrap().unwrap().source.as_deref(),
+        Some("b")
+    );
+    assert!(find("missing").unwrap().is_none());
+}
+
+#[cfg(test)]
+#[test]
+fn file_rules_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let template = dir.path();
+    fs::create_dir_all(template.join(".git")).unwrap();
+    fs::create_dir_all(template.join("plugins/x")).unwrap();
+    fs::create_dir_all(template.join("cache")).unwrap();
+    fs::write(template.join(".git/HEAD"), "{PORT}").unwrap();
+    fs::write(template.join("cache/a"), "{PORT}").unwrap();
+    fs::write(template.join("server.properties"), "{PORT}").unwrap();
+    fs::write(template.join("server.jar"), "{PORT}").unwrap();
+    fs::write(template.join("plugins/x/config.yml"), "{PORT}").unwrap();
+    fs::write(template.join(IGNORE_FILE), "cache/\n").unwrap();
+    fs::write(
+        template.join(MANIFEST_FILE),
+        "[scripts]\nhealth_check = \"true\"\nstart = \"true\"\n[files]\nverbatim = [\"plugins/\"]\n",
+    )
+    .unwrap();
+
+    let template = TemplateInfomation::new(template.join(MANIFEST_FILE)).unwrap();
+    let vars = BTreeMap::from([("PORT".to_string(), "25565".to_string())]);
+    let files = template.render_files(&vars).unwrap();
+
+    assert_eq!(
+        files.keys().collect::<Vec<_>>(),
+        vec![
+            MANIFEST_FILE,
+            "plugins/x/config.yml",
+            "server.jar",
+            "server.properties"
+        ]
+    );
+    assert_eq!(files["server.properties"], b"25565");
+    assert_eq!(files["server.jar"], b"{PORT}");
+    assert_eq!(files["plugins/x/config.yml"], b"{PORT}");
+}
+
+#[cfg(test)]
+#[test]
+fn render_test() {
+    let vars = BTreeMap::from([("PORT".to_string(), "25565".to_string())]);
+    assert_eq!(
+        render("server-port={PORT}\nmotd={MOTD}", &vars),
+        "server-port=25565\nmotd={MOTD}"
+    );
 }
@@ -0,0 +1,433 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::anyhow;
+use schemars::JsonSchema;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    origin::ServiceOrigin,
+    registry::{self, SourceState},
+    service::ServiceInformation,
+    template::TemplateInfomation,
+    work,
+};
+
+/// What happened to a template file during an upgrade.
+#[derive(Debug, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+pub enum MergeOutcome {
+    /// The service already has the content of the new template.
+    Unchanged,
+    Added,
+    Updated,
+    Removed,
+    /// Only the service changed the file, the local version is kept.
+    Kept,
+    /// Both changed the file and the changes were merged cleanly.
+    Merged,
+    /// Both changed the file. Either conflict markers were written or the local version was
+    /// saved next to it as `.orig`.
+    Conflict,
+}
+
+impl Display for MergeOutcome {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpgradeReport {
+    pub service: String,
+    pub from: String,
+    pub to: String,
+    pub files: Vec<(String, MergeOutcome)>,
+}
+
+/// Bring a service to `target`, merging the changes between the template it was created from
+/// and `target` with the local modifications of the service.
+pub fn upgrade(
+    service: &ServiceInformation,
+    origin: &ServiceOrigin,
+    target: &TemplateInfomation,
+    templates_dir: &Path,
+    templates: &[TemplateInfomation],
+) -> anyhow::Result<UpgradeReport> {
+    let new = target.render_files(&origin.vars)?;
+
+    let staging = service.path.join(".smc").join("merge");
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+    let base = base_files(origin, templates_dir, templates, &staging);
+
+    let paths = base
+        .keys()
+        .chain(new.keys())
+        .chain(origin.files.keys())
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    let mut files = vec![];
+    for path in paths {
+        let local_path = service.path.join(&path);
+        let local = fs::read(&local_path).ok();
+        let new = new.get(&path);
+
+        let outcome = match decide(
+            origin.files.get(&path).map(String::as_str),
+            local.as_deref(),
+            new.map(Vec::as_slice),
+        ) {
+            Decision::Unchanged => MergeOutcome::Unchanged,
+            Decision::Keep => MergeOutcome::Kept,
+            Decision::Apply => match new {
+                Some(new) => {
+                    fs::create_dir_all(local_path.parent().expect("File path has a parent"))?;
+                    fs::write(&local_path, new)?;
+                    target.copy_permissions(&path, &local_path)?;
+                    if local.is_some() {
+                        MergeOutcome::Updated
+                    } else {
+                        MergeOutcome::Added
+                    }
+                }
+                None => {
+                    fs::remove_file(&local_path)?;
+                    MergeOutcome::Removed
+                }
+            },
+            Decision::Merge => {
+                let new = new.expect("Merged files exist in the new template");
+                let local = local.expect("Merged files exist in the service");
+                let merged = match base.get(&path) {
+                    Some(base) => merge_text(&staging, base, &local, new, target)?,
+                    None => None,
+                };
+
+                match merged {
+                    Some((content, clean)) => {
+                        fs::write(&local_path, content)?;
+                        if clean {
+                            MergeOutcome::Merged
+                        } else {
+                            MergeOutcome::Conflict
+                        }
+                    }
+                    None => {
+                        fs::rename(&local_path, free_orig_path(&local_path))?;
+                        fs::write(&local_path, new)?;
+                        target.copy_permissions(&path, &local_path)?;
+                        MergeOutcome::Conflict
+                    }
+                }
+            }
+        };
+
+        files.push((path, outcome));
+    }
+
+    fs::remove_dir_all(&staging)?;
+
+    let state = target
+        .source
+        .as_ref()
+        .and_then(|source| SourceState::load(templates_dir.join(source)));
+    let hashes = new
+        .iter()
+        .map(|(path, content)| (path.clone(), hex::encode(Sha256::digest(content))))
+        .collect();
+    let mut upgraded =
+        ServiceOrigin::new(target, templates_dir, state, origin.vars.clone(), hashes);
+    upgraded.created = origin.created;
+    upgraded.ports = origin.ports.clone();
+    upgraded.save(&service.path)?;
+
+    Ok(UpgradeReport {
+        service: service.name.clone(),
+        from: origin.to_string(),
+        to: upgraded.to_string(),
+        files,
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Decision {
+    Unchanged,
+    Apply,
+    Keep,
+    Merge,
+}
+
+/// Decide what to do with a file from the hash recorded when the service was created, its
+/// content in the service and its content in the new template.
+fn decide(recorded: Option<&str>, local: Option<&[u8]>, new: Option<&[u8]>) -> Decision {
+    let hash = |content: &[u8]| hex::encode(Sha256::digest(content));
+
+    if local == new {
+        return Decision::Unchanged;
+    }
+
+    let pristine = match (local, recorded) {
+        (Some(local), Some(recorded)) => hash(local) == recorded,
+        (None, None) => true,
+        _ => false,
+    };
+    if pristine {
+        return Decision::Apply;
+    }
+
+    // Files the service deleted stay deleted.
+    if new.map(hash).as_deref() == recorded || new.is_none() || local.is_none() {
+        return Decision::Keep;
+    }
+
+    Decision::Merge
+}
+
+/// Merge text files with `git merge-file`, returning the result and whether it is free of
+/// conflicts. Binary files give `None`.
+fn merge_text(
+    staging: &Path,
+    base: &[u8],
+    local: &[u8],
+    new: &[u8],
+    target: &TemplateInfomation,
+) -> anyhow::Result<Option<(Vec<u8>, bool)>> {
+    if [base, local, new]
+        .iter()
+        .any(|ele| std::str::from_utf8(ele).is_err())
+    {
+        return Ok(None);
+    }
+
+    let (base_path, local_path, new_path) = (
+        staging.join("base"),
+        staging.join("local"),
+        staging.join("new"),
+    );
+    fs::write(&base_path, base)?;
+    fs::write(&local_path, local)?;
+    fs::write(&new_path, new)?;
+
+    let output = Command::new("git")
+        .args(["merge-file", "-p", "-L", "local", "-L", "base", "-L"])
+        .arg(target.full_name())
+        .args([&local_path, &base_path, &new_path])
+        .output()?;
+
+    match output.status.code() {
+        Some(0) => Ok(Some((output.stdout, true))),
+        Some(1..=127) => Ok(Some((output.stdout, false))),
+        _ => Err(anyhow!(
+            "git merge-file failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+/// `path` with `.orig` appended, or `.orig.1`, `.orig.2`... when that name is taken.
+fn free_orig_path(path: &Path) -> PathBuf {
+    let mut orig = path.as_os_str().to_owned();
+    orig.push(".orig");
+    let orig = PathBuf::from(orig);
+
+    let mut candidate = orig.clone();
+    for suffix in 1.. {
+        if !candidate.exists() {
+            break;
+        }
+        let mut next = orig.clone().into_os_string();
+        next.push(format!(".{}", suffix));
+        candidate = PathBuf::from(next);
+    }
+    candidate
+}
+
+/// Files of the template the service was created from, rendered with the variables recorded at
+/// creation. Files whose original content cannot be recovered are left out.
+fn base_files(
+    origin: &ServiceOrigin,
+    templates_dir: &Path,
+    templates: &[TemplateInfomation],
+    staging: &Path,
+) -> BTreeMap<String, Vec<u8>> {
+    let render = |templates: &[TemplateInfomation], path: &Path| {
+        templates
+            .iter()
+            .find(|ele| ele.path == path)?
+            .resolve(templates)
+            .ok()?
+            .render_files(&origin.vars)
+            .ok()
+    };
+
+    let files = git_base_templates(origin, templates_dir, templates, staging)
+        .and_then(|(templates, path)| render(&templates, &path))
+        .or_else(|| render(templates, &templates_dir.join(origin.path.as_ref()?)))
+        .unwrap_or_default();
+
+    files
+        .into_iter()
+        .filter(|(path, content)| {
+            origin.files.get(path) == Some(&hex::encode(Sha256::digest(content)))
+        })
+        .collect()
+}
+
+/// The templates as they were at the commit recorded when the service was created, unpacked
+/// under `staging`, with the path of the template of the service. Templates of other sources
+/// are taken as they are now.
+fn git_base_templates(
+    origin: &ServiceOrigin,
+    templates_dir: &Path,
+    templates: &[TemplateInfomation],
+    staging: &Path,
+) -> Option<(Vec<TemplateInfomation>, PathBuf)> {
+    let source = origin.source.as_ref()?;
+    let commit = origin.commit.as_ref()?;
+    let relative = Path::new(origin.path.as_ref()?).strip_prefix(source).ok()?;
+
+    let archive = registry::git_output(
+        &templates_dir.join(source),
+        &["archive", "--format=tar", commit],
+    )
+    .ok()?;
+    let snapshot = staging.join("template");
+    tar::Archive::new(archive.as_slice())
+        .unpack(&snapshot)
+        .ok()?;
+
+    let mut snapshot_templates = work::manifest_paths(&snapshot)
+        .into_iter()
+        .flat_map(TemplateInfomation::new)
+        .map(|mut ele| {
+            ele.source = Some(source.clone());
+            ele
+        })
+        .collect::<Vec<_>>();
+    snapshot_templates.extend(
+        templates
+            .iter()
+            .filter(|ele| ele.source.as_ref() != Some(source))
+            .cloned(),
+    );
+
+    Some((snapshot_templates, snapshot.join(relative)))
+}
+
+#[cfg(test)]
+#[test]
+fn decide_test() {
+    let hash = |content: &[u8]| hex::encode(Sha256::digest(content));
+    let base = hash(b"a");
+
+    assert_eq!(
+        decide(Some(&base), Some(b"b"), Some(b"b")),
+        Decision::Unchanged
+    );
+    assert_eq!(decide(Some(&base), Some(b"a"), Some(b"b")), Decision::Apply);
+    assert_eq!(decide(Some(&base), Some(b"a"), None), Decision::Apply);
+    assert_eq!(decide(None, None, Some(b"b")), Decision::Apply);
+    assert_eq!(decide(Some(&base), Some(b"c"), Some(b"a")), Decision::Keep);
+    assert_eq!(decide(Some(&base), Some(b"c"), None), Decision::Keep);
+    assert_eq!(decide(Some(&base), Some(b"c"), Some(b"b")), Decision::Merge);
+    assert_eq!(decide(Some(&base), None, Some(b"b")), Decision::Keep);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("server.properties");
+    assert_eq!(
+        free_orig_path(&path),
+        dir.path().join("server.properties.orig")
+    );
+    fs::write(dir.path().join("server.properties.orig"), "").unwrap();
+    assert_eq!(
+        free_orig_path(&path),
+        dir.path().join("server.properties.orig.1")
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn upgrade_test() {
+    use chrono::Local;
+
+    let dir = tempfile::tempdir().unwrap();
+    let templates_dir = dir.path().join("templates");
+    let source_dir = templates_dir.join("official");
+    let manifest = source_dir.join("paper").join("manifest.toml");
+    fs::create_dir_all(manifest.parent().unwrap()).unwrap();
+
+    let git = |args: &[&str]| {
+        let args = [
+            &["-c", "user.name=smc", "-c", "user.email=smc@localhost"],
+            args,
+        ]
+        .concat();
+        registry::run_git(&source_dir, &args).unwrap()
+    };
+    let template = || {
+        let mut template = TemplateInfomation::new(&manifest).unwrap();
+        template.source = Some("official".to_string());
+        template.resolve(&[template.clone()]).unwrap()
+    };
+
+    git(&["init", "-q"]);
+    fs::write(
+        &manifest,
+        "# Paper\n[scripts]\nhealth_check = \"true\"\nstart = \"./start.sh\"\nstop = \"true\"\n",
+    )
+    .unwrap();
+    git(&["add", "-A"]);
+    git(&["commit", "-qm", "paper"]);
+
+    let service_dir = dir.path().join("services").join("lobby");
+    let vars = BTreeMap::new();
+    let files = template().materialize(&service_dir, &vars).unwrap();
+    let state = SourceState {
+        name: "official".to_string(),
+        location: String::new(),
+        reference: None,
+        commit: Some(git(&["rev-parse", "HEAD"])),
+        updated: Local::now(),
+    };
+    let origin = ServiceOrigin::new(&template(), &templates_dir, Some(state), vars, files);
+
+    let local = fs::read_to_string(service_dir.join("manifest.toml")).unwrap();
+    fs::write(
+        service_dir.join("manifest.toml"),
+        local + "\n[properties]\nmotd = \"Lobby\"\n",
+    )
+    .unwrap();
+    let new = fs::read_to_string(&manifest).unwrap();
+    fs::write(&manifest, new.replace("./start.sh", "./run.sh")).unwrap();
+    git(&["commit", "-qam", "run.sh"]);
+
+    let service = ServiceInformation {
+        name: "lobby".to_string(),
+        path: service_dir.clone(),
+        ..Default::default()
+    };
+    let target = template();
+    let report = upgrade(
+        &service,
+        &origin,
+        &target,
+        &templates_dir,
+        std::slice::from_ref(&target),
+    )
+    .unwrap();
+    assert_eq!(
+        report.files,
+        vec![("manifest.toml".to_string(), MergeOutcome::Merged)]
+    );
+    let merged = fs::read_to_string(service_dir.join("manifest.toml")).unwrap();
+    assert!(merged.contains("./run.sh") && merged.contains("motd = \"Lobby\""));
+}
@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use crate::service::{ServiceInformation, ServiceStatus};
+
+/// Announce an upcoming stop or restart to the players of every running service, then wait
+/// until the countdown is over.
+///
+/// `warn` overrides the `warning.default` of each manifest, `now` skips the countdown entirely.
+pub async fn countdown_all(
+    services: &[ServiceInformation],
+    warn: Option<Duration>,
+    now: bool,
+    action: &str,
+) {
+    if now {
+        return;
+    }
+
+    let handles = services
+        .iter()
+        .filter_map(|ele| {
+            let total = warn.or_else(|| default_warning(ele))?;
+            let (service, action) = (ele.clone(), action.to_string());
+            Some(tokio::spawn(async move {
+                let service = service.update_status().await;
+                if service.status == ServiceStatus::Start {
+                    countdown(service, total, action).await;
+                }
+            }))
+        })
+        .collect::<Vec<_>>();
+
+    for handle in handles {
+        handle.await.expect("Cannot join countdown task");
+    }
+}
+
+pub async fn countdown(service: ServiceInformation, total: Duration, action: String) {
+    let warning = service.manifest.warning.clone().unwrap_or_default();
+
+    let mut steps = vec![];
+    for ele in &warning.countdown {
+        match humantime::parse_duration(ele) {
+            Ok(step) => steps.push(step),
+            Err(err) => eprintln!(
+                "Ignoring countdown step {} of {}: {}",
+                ele, service.name, err
+            ),
+        }
+    }
+
+    for (wait, remaining) in countdown_steps(total, steps) {
+        tokio::time::sleep(wait).await;
+
+        if remaining.is_zero() {
+            break;
+        }
+
+        let message = render_message(&warning.message, remaining, &action);
+        if let Err(err) = service.clone().send_command(&message).await {
+            eprintln!("Cannot warn players of {}: {}", service.name, err);
+        }
+    }
+}
+
+fn default_warning(service: &ServiceInformation) -> Option<Duration> {
+    let default = service.manifest.warning.as_ref()?.default.as_ref()?;
+    humantime::parse_duration(default)
+        .map_err(|err| {
+            eprintln!(
+                "Ignoring warning.default {} of {}: {}",
+                default, service.name, err
+            )
+        })
+        .ok()
+}
+
+/// Returns how long to wait before each announcement and the time remaining when it is made,
+/// finishing with the wait until the countdown is over.
+fn countdown_steps(total: Duration, mut steps: Vec<Duration>) -> Vec<(Duration, Duration)> {
+    steps.retain(|ele| *ele < total && !ele.is_zero());
+    steps.sort_by(|a, b| b.cmp(a));
+    steps.dedup();
+
+    let mut result = vec![(Duration::ZERO, total)];
+    let mut remaining = total;
+    for step in steps.into_iter().chain([Duration::ZERO]) {
+        result.push((remaining - step, step));
+        remaining = step;
+    }
+
+    result
+}
+
+fn render_message(template: &str, remaining: Duration, action: &str) -> String {
+    template
+        .replace("$TIME", &humantime::format_duration(remaining).to_string())
+        .replace("$ACTION", action)
+}
+
+#[cfg(test)]
+#[test]
+fn countdown_steps_test() {
+    let secs = Duration::from_secs;
+
+    assert_eq!(
+        countdown_steps(secs(120), vec![secs(300), secs(60), secs(10), secs(30)]),
+        vec![
+            (secs(0), secs(120)),
+            (secs(60), secs(60)),
+            (secs(30), secs(30)),
+            (secs(20), secs(10)),
+            (secs(10), secs(0)),
+        ]
+    );
+
+    assert_eq!(
+        render_message("say Server will $ACTION in $TIME", secs(300), "restart"),
+        "say Server will restart in 5m"
+    );
+}
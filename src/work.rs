@@ -1,14 +1,32 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
 
 use walkdir::WalkDir;
 
-use crate::{service::ServiceInformation, template::TemplateInfomation};
+use crate::{
+    config::Config,
+    error::{self, Classify, Error},
+    java,
+    registry::SOURCE_STATE_FILE,
+    service::{ServiceInformation, ServiceStatus},
+    template::{self, TemplateInfomation, MANIFEST_FILE},
+};
 
 #[derive(Debug, Default, Clone)]
 pub struct WorkDirectory {
     pub path: PathBuf,
     templates: Vec<TemplateInfomation>,
     services: Vec<ServiceInformation>,
+    errors: Vec<LoadError>,
+}
+
+/// A service manifest, or the configuration, that could not be loaded.
+#[derive(Debug, Serialize, Clone)]
+pub struct LoadError {
+    /// Path of the manifest or of `config.toml`.
+    pub path: PathBuf,
+    pub message: String,
 }
 
 #[allow(unused)]
@@ -18,45 +36,71 @@ impl WorkDirectory {
         T: Into<PathBuf> + Clone,
     {
         let walk = |s: String| {
-            {
-                WalkDir::new({
-                    let mut x = Into::<PathBuf>::into(path.clone());
-                    x.push(s);
-                    x
-                })
-                .into_iter()
-                .map(|element| {
-                    if let Ok(dir) = element {
-                        if (dir.file_name().to_str().unwrap() != "manifest.toml") {
-                            return None;
-                        }
-                        return Some(dir.into_path());
-                    } else {
-                        return None;
-                    }
-                })
-            }
-            .filter(Option::is_some)
-            .map(Option::unwrap)
+            manifest_paths({
+                let mut x = Into::<PathBuf>::into(path.clone());
+                x.push(s);
+                x
+            })
         };
 
+        let template_root = {
+            let mut x = Into::<PathBuf>::into(path.clone());
+            x.push("templates");
+            x
+        };
         let templates = walk("templates".to_string())
-            .map(TemplateInfomation::new)
-            .filter(Result::is_ok)
-            .map(Result::unwrap)
+            .into_iter()
+            .flat_map(TemplateInfomation::new)
+            .map(|mut ele| {
+                // Sources are fetched into `templates/<source>/`, which holds their state file.
+                ele.source = ele
+                    .path
+                    .strip_prefix(&template_root)
+                    .ok()
+                    .and_then(|ele| ele.iter().next())
+                    .filter(|source| template_root.join(source).join(SOURCE_STATE_FILE).exists())
+                    .map(|source| source.to_string_lossy().to_string());
+                ele
+            })
             .collect();
 
-        let services = walk("services".to_string())
-            .map(ServiceInformation::new)
-            .filter(Result::is_ok)
-            .map(Result::unwrap)
-            .collect();
+        let mut services = vec![];
+        let mut errors = vec![];
+        for manifest in walk("services".to_string()) {
+            match ServiceInformation::new(&manifest) {
+                Ok(service) => services.push(service),
+                Err(err) => errors.push(LoadError {
+                    path: manifest,
+                    message: err.to_string().trim_end().to_string(),
+                }),
+            }
+        }
+
+        let path: PathBuf = path.into();
+        if services.iter().any(|ele| ele.manifest.java.is_some()) {
+            let config_file = path.join("config.toml");
+            let config = Config::load(&config_file).unwrap_or_else(|err| {
+                errors.push(LoadError {
+                    path: config_file,
+                    message: err.to_string().trim_end().to_string(),
+                });
+                Config::default()
+            });
+            let installations = java::installations(&config, Path::new(java::JVM_DIRECTORY));
+            for ele in services.iter_mut() {
+                if let Some(java) = &ele.manifest.java {
+                    ele.java_command = Some(
+                        java::command_line(java, &installations).map_err(|err| err.to_string()),
+                    );
+                }
+            }
+        }
 
         WorkDirectory {
-            path: path.into(),
+            path,
             templates,
             services,
-            ..Default::default()
+            errors,
         }
     }
 
@@ -64,10 +108,46 @@ impl WorkDirectory {
         self.templates
     }
 
+    /// Find a template and resolve what it `extends`.
+    pub fn resolve_template(self, spec: &str) -> error::Result<TemplateInfomation> {
+        template::find(self.templates.clone(), spec)
+            .classify(Error::Usage)?
+            .ok_or_else(|| Error::NotFound(format!("There is no template with the name {}", spec)))?
+            .resolve(&self.templates)
+            .classify(Error::Invalid)
+    }
+
     pub fn services(self) -> Vec<ServiceInformation> {
         self.services
     }
 
+    /// Services whose manifest could not be loaded, and the configuration if it could not be.
+    pub fn errors(self) -> Vec<LoadError> {
+        self.errors
+    }
+
+    /// The services of `errors`, with the `Invalid` status and the error.
+    pub fn invalid_services(self) -> Vec<ServiceInformation> {
+        self.errors
+            .into_iter()
+            .filter(|ele| ele.path.ends_with(MANIFEST_FILE))
+            .map(|ele| {
+                let mut path = ele.path;
+                path.pop();
+                ServiceInformation {
+                    name: path
+                        .file_name()
+                        .map(|ele| ele.to_string_lossy().to_string())
+                        .unwrap_or_default(),
+                    path,
+                    status: ServiceStatus::Invalid,
+                    error: Some(ele.message),
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+
     pub fn data_directory(self) -> PathBuf {
         let mut path = self.path.clone();
         path.push("data");
@@ -85,4 +165,50 @@ impl WorkDirectory {
         path.push("services");
         path
     }
+
+    pub fn backup_directory(self) -> PathBuf {
+        let mut path = self.path.clone();
+        path.push("backups");
+        path
+    }
+
+    pub fn artifact_directory(self) -> PathBuf {
+        let mut path = self.path.clone();
+        path.push("cache");
+        path.push("artifacts");
+        path
+    }
+
+    pub fn config_file(self) -> PathBuf {
+        let mut path = self.path.clone();
+        path.push("config.toml");
+        path
+    }
+
+    pub fn sources_file(self) -> PathBuf {
+        let mut path = self.path.clone();
+        path.push("sources.toml");
+        path
+    }
+
+    pub fn schedule_history(self) -> PathBuf {
+        let mut path = self.path.clone();
+        path.push("schedule-history.jsonl");
+        path
+    }
+}
+
+/// Every `manifest.toml` under `root`. Hidden directories hold git metadata and sources being
+/// fetched, so they are skipped.
+pub fn manifest_paths<T>(root: T) -> Vec<PathBuf>
+where
+    T: Into<PathBuf>,
+{
+    WalkDir::new(root.into())
+        .into_iter()
+        .filter_entry(|ele| ele.depth() == 0 || !ele.file_name().to_string_lossy().starts_with('.'))
+        .flatten()
+        .filter(|ele| ele.file_name() == MANIFEST_FILE)
+        .map(|ele| ele.into_path())
+        .collect()
 }